@@ -1,9 +1,18 @@
+#[cfg(feature = "native")]
 extern crate cc;
 
+#[cfg(feature = "native")]
 use std::env;
+#[cfg(feature = "native")]
 use std::path::PathBuf;
 
 fn main() {
+    #[cfg(feature = "native")]
+    build_native();
+}
+
+#[cfg(feature = "native")]
+fn build_native() {
     cc::Build::new()
         .cpp(true)
         .include("../build/include")
@@ -2,14 +2,10 @@ use super::native;
 use super::vector;
 use err;
 use std::ffi::CString;
+use std::io;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, PartialEq)]
-pub enum Distance {
-    Angular,
-    Euclidean,
-    Manhattan,
-}
+pub use distance::Distance;
 
 pub struct AnnoyIndexRaw(native::rust_annoy_index_t);
 
@@ -19,12 +15,18 @@ impl AnnoyIndexRaw {
             Distance::Angular => unsafe { native::rust_annoy_index_angular_init(dimension) },
             Distance::Euclidean => unsafe { native::rust_annoy_index_euclidian_init(dimension) },
             Distance::Manhattan => unsafe { native::rust_annoy_index_manhattan_init(dimension) },
+            Distance::DotProduct => unsafe { native::rust_annoy_index_dot_product_init(dimension) },
+            Distance::Hamming => unsafe { native::rust_annoy_index_hamming_init(dimension) },
         };
 
         AnnoyIndexRaw(raw)
     }
 }
 
+// annoylib indexes are only read once built or loaded, from any number of threads
+unsafe impl Send for AnnoyIndexRaw {}
+unsafe impl Sync for AnnoyIndexRaw {}
+
 impl Drop for AnnoyIndexRaw {
     fn drop(&mut self) {
         unsafe { native::rust_annoy_index_destroy(self.0) };
@@ -36,12 +38,14 @@ pub struct AnnoyIndexBuilder {
     distance: Distance,
     raw: AnnoyIndexRaw,
     item_count: i32,
+    seed: Option<u64>,
 }
 
 pub struct AnnoyIndex {
     dimension: i32,
     distance: Distance,
     tree_count: Option<i32>,
+    seed: Option<u64>,
     raw: AnnoyIndexRaw,
 }
 
@@ -59,6 +63,49 @@ impl AnnoyIndexBuilder {
             distance,
             raw,
             item_count: -1,
+            seed: None,
+        }
+    }
+
+    /// Seed the random generator used to build the trees,
+    /// so that identical items give identical indexes.
+    /// annoylib only keeps the lower 32 bits of the seed.
+    /// ```
+    /// use annoy_rs::annoy::*;
+    /// let mut builder = AnnoyIndexBuilder::new(2, Distance::Angular).with_seed(42);
+    /// builder.add_item(&[0.0, 1.0]);
+    /// let index = builder.build(None);
+    /// assert_eq!(index.seed(), Some(42));
+    /// ```
+    pub fn with_seed(mut self, seed: u64) -> AnnoyIndexBuilder {
+        unsafe { native::rust_annoy_index_set_seed(self.raw.0, seed) };
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Create a builder for index with vector of dimension [dimension],
+    /// storing items and trees in the file [path] instead of memory.
+    /// Once built, the index is usable directly and the file does not need to be saved.
+    /// ```
+    /// use annoy_rs::annoy::*;
+    /// let path = std::env::temp_dir().join("on_disk_doc.tree");
+    /// let mut builder = AnnoyIndexBuilder::on_disk(2, Distance::Angular, &path).unwrap();
+    /// builder.add_item(&[0.0, 1.0]);
+    /// builder.add_item(&[1.0, 0.0]);
+    /// let index = builder.build(Some(2));
+    /// assert_eq!(index.len(), 2);
+    /// ```
+    pub fn on_disk<P: AsRef<Path>>(
+        dimension: i32,
+        distance: Distance,
+        path: P,
+    ) -> Result<AnnoyIndexBuilder, err::Error> {
+        let builder = AnnoyIndexBuilder::new(dimension, distance);
+        let cs = c_path(path)?;
+        if unsafe { native::rust_annoy_index_on_disk_build(builder.raw.0, cs.as_ptr()) } {
+            Ok(builder)
+        } else {
+            Err(err::Error::from(io::Error::last_os_error()))
         }
     }
 
@@ -94,6 +141,7 @@ impl AnnoyIndexBuilder {
             dimension: self.dimension,
             raw: self.raw,
             tree_count: n_tree,
+            seed: self.seed,
         }
     }
 
@@ -110,9 +158,16 @@ impl AnnoyIndexBuilder {
     pub fn distance(&self) -> &Distance {
         &self.distance
     }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
 }
 
 impl AnnoyIndex {
+    /// Number of candidates fetched by the first batch of a radius search
+    const RADIUS_FIRST_BATCH: i32 = 64;
+
     /// Return the dimension used to build the index
     /// ```
     /// use annoy_rs::annoy::*;
@@ -146,6 +201,11 @@ impl AnnoyIndex {
         self.tree_count
     }
 
+    /// Return the seed given to the builder, None for loaded indexes
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
     /// Return the [n] closer item to item index [item] searching [search_k] tree
     /// When using None for search_k it uses [n_trees * n]
     /// It return 2 array containing results and distance to the item.
@@ -225,14 +285,57 @@ impl AnnoyIndex {
         (result_vec.data(), distances_vec.data())
     }
 
-    pub fn save2<P: AsRef<Path>>(&self, path: P, load_into_ram: bool) -> Result<(), err::Error> {
-        let path_str = path
-            .as_ref()
-            .as_os_str()
-            .to_str()
-            .ok_or(err::Error::InvalidPath)?;
+    /// Return the items within [max_distance] of vector [w], closest first, searching
+    /// [search_k] nodes for each batch of candidates. At most [max_results] items are
+    /// returned, the last element tells if more items were within the distance.
+    /// For dot product indexes, [max_distance] is the minimum inner product.
+    ///
+    /// ```
+    /// use annoy_rs::annoy::*;
+    /// let mut builder = AnnoyIndexBuilder::new(2, Distance::Euclidean);
+    /// builder.add_item(&[0.0, 0.0]);
+    /// builder.add_item(&[1.0, 0.0]);
+    /// builder.add_item(&[5.0, 0.0]);
+    /// let index = builder.build(Some(2));
+    /// let (results, distances, truncated) = index.get_nns_within(&[0.0, 0.0], 2.0, 10, None);
+    /// assert_eq!(results, vec![0, 1]);
+    /// assert!(!truncated);
+    /// ```
+    pub fn get_nns_within(
+        &self,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>, bool) {
+        let within = |d: &f32| match self.distance {
+            Distance::DotProduct => *d >= max_distance,
+            _ => *d <= max_distance,
+        };
+        let limit = max_results.max(0).saturating_add(1);
+        let mut n = limit.min(Self::RADIUS_FIRST_BATCH);
+        let mut search_k = search_k;
+        loop {
+            let (mut results, mut distances) = self.get_nns_by_vector(w, n, search_k);
+            let count = distances.iter().take_while(|d| within(d)).count();
+            // a candidate beyond the distance or fewer candidates than asked: all were found
+            if count < results.len() || (results.len() as i32) < n {
+                results.truncate(count);
+                distances.truncate(count);
+                return (results, distances, false);
+            }
+            if n >= limit {
+                results.truncate(max_results.max(0) as usize);
+                distances.truncate(max_results.max(0) as usize);
+                return (results, distances, true);
+            }
+            n = n.saturating_mul(2).min(limit);
+            search_k = search_k.map(|k| k.saturating_mul(2));
+        }
+    }
 
-        let cs = CString::new(path_str).unwrap();
+    pub fn save2<P: AsRef<Path>>(&self, path: P, load_into_ram: bool) -> Result<(), err::Error> {
+        let cs = c_path(path)?;
         unsafe { native::rust_annoy_index_save(self.raw.0, cs.as_ptr(), load_into_ram) };
         Ok(())
     }
@@ -242,13 +345,7 @@ impl AnnoyIndex {
     }
 
     pub fn load2<P: AsRef<Path>>(&self, path: P, load_into_ram: bool) -> Result<(), err::Error> {
-        let path_str = path
-            .as_ref()
-            .as_os_str()
-            .to_str()
-            .ok_or(err::Error::InvalidPath)?;
-
-        let cs = CString::new(path_str).unwrap();
+        let cs = c_path(path)?;
         unsafe { native::rust_annoy_index_load(self.raw.0, cs.as_ptr(), load_into_ram) };
         Ok(())
     }
@@ -262,7 +359,7 @@ impl AnnoyIndex {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len() != 0
+        self.len() == 0
     }
 
     pub fn get_item(&self, item: i32) -> Option<Vec<f32>> {
@@ -284,6 +381,16 @@ impl AnnoyIndex {
     }
 }
 
+fn c_path<P: AsRef<Path>>(path: P) -> Result<CString, err::Error> {
+    let path_str = path
+        .as_ref()
+        .as_os_str()
+        .to_str()
+        .ok_or(err::Error::InvalidPath)?;
+
+    CString::new(path_str).map_err(|_| err::Error::InvalidPath)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -325,6 +432,102 @@ mod tests {
         println!("{:?}", index2.get_nns_by_vector(&[1.0, 0.5, 0.5], 2, None));
     }
 
+    #[test]
+    fn on_disk_build_test() {
+        let path = std::env::temp_dir().join("on_disk_build_test.tree");
+        let mut a = AnnoyIndexBuilder::on_disk(3, Distance::Euclidean, &path).unwrap();
+        let mut rng = thread_rng();
+        for _i in 0..1000 {
+            let v: Vec<f32> = rng.sample_iter(&Standard).take(3).collect();
+            a.add_item(v.as_slice());
+        }
+        let index = a.build(Some(10));
+
+        let index2 = AnnoyIndexBuilder::new(3, Distance::Euclidean).build(None);
+        index2.load(path.clone()).unwrap();
+
+        assert_eq!(index2.len(), 1000);
+        assert_eq!(index2.get_item(12), index.get_item(12));
+        assert_eq!(
+            index2.get_nns_by_item(12, 10, None),
+            index.get_nns_by_item(12, 10, None)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn seed_test() {
+        let mut rng = thread_rng();
+        let items: Vec<Vec<f32>> = (0..1000)
+            .map(|_| rng.sample_iter(&Standard).take(10).collect())
+            .collect();
+        let build = |seed: u64, path: &PathBuf| {
+            let mut a = AnnoyIndexBuilder::new(10, Distance::Angular).with_seed(seed);
+            for item in &items {
+                a.add_item(item.as_slice());
+            }
+            a.build(Some(10)).save(path.clone()).unwrap();
+            std::fs::read(path).unwrap()
+        };
+
+        let path1 = std::env::temp_dir().join("seed_test1.tree");
+        let path2 = std::env::temp_dir().join("seed_test2.tree");
+        assert_eq!(build(1234, &path1), build(1234, &path2));
+        // annoylib seeds its generator with a constant otherwise
+        assert_ne!(build(1234, &path1), build(4321, &path2));
+        std::fs::remove_file(path1).unwrap();
+        std::fs::remove_file(path2).unwrap();
+    }
+
+    #[test]
+    fn radius_test() {
+        let mut a = AnnoyIndexBuilder::new(1, Distance::Euclidean);
+        for i in 0..1000 {
+            a.add_item(&[i as f32]);
+        }
+        let index = a.build(Some(10));
+
+        let (results, distances, truncated) = index.get_nns_within(&[500.0], 100.0, 1000, None);
+        assert_eq!(results.len(), 201);
+        assert!(distances.iter().all(|d| *d <= 100.0));
+        assert!(!truncated);
+
+        let (results, _, truncated) = index.get_nns_within(&[500.0], 100.0, 150, None);
+        assert_eq!(results.len(), 150);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn dot_product_test() {
+        let mut a = AnnoyIndexBuilder::new(2, Distance::DotProduct);
+        a.add_item(&[1.0, 0.0]);
+        a.add_item(&[0.0, 2.0]);
+        a.add_item(&[3.0, 0.0]);
+        let index = a.build(Some(10));
+
+        let (results, distances) = index.get_nns_by_vector(&[1.0, 0.0], 3, None);
+        assert_eq!(results, vec![2, 0, 1]);
+        assert_eq!(distances, vec![3.0, 1.0, 0.0]);
+        assert_eq!(index.get_item(1), Some(vec![0.0, 2.0]));
+    }
+
+    #[test]
+    fn hamming_test() {
+        let mut a = AnnoyIndexBuilder::new(70, Distance::Hamming);
+        let mut v = vec![0.0; 70];
+        a.add_item(&v);
+        v[3] = 1.0;
+        v[68] = 1.0;
+        a.add_item(&v);
+        let index = a.build(Some(10));
+
+        assert_eq!(index.get_item(1), Some(v.clone()));
+        v[68] = 0.0;
+        let (results, distances) = index.get_nns_by_vector(&v, 2, None);
+        assert_eq!(results, vec![0, 1]);
+        assert_eq!(distances, vec![1.0, 1.0]);
+    }
+
     #[test]
     fn get_n_item_test() {
         let mut a = AnnoyIndexBuilder::new(3, Distance::Angular);
@@ -0,0 +1,719 @@
+use distance::{self, Distance};
+use err::Error;
+use num_cpus;
+use random::Kiss64Random;
+use reader::AnnoyReader;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use storage;
+
+/// Progress of a build, reported each time a tree is finished
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildProgress {
+    pub trees_built: usize,
+    /// None when the number of trees depends on the number of nodes
+    pub tree_count: Option<usize>,
+    pub node_count: usize,
+}
+
+pub trait ProgressCallback = FnMut(BuildProgress) + Send;
+
+/// Pure-Rust annoy index builder. Trees are built in parallel and written in the annoy
+/// 1.15.2 format, so the file can be loaded by annoylib as well as by `reader::AnnoyReader`.
+pub struct AnnoyBuilder {
+    items: Items,
+    seed: u64,
+    threads: usize,
+    progress: Option<Box<dyn ProgressCallback>>,
+}
+
+/// Item vectors shared by the tree building threads
+struct Items {
+    dimension: usize,
+    distance: Distance,
+    vectors: Vec<f32>,
+    norms: Vec<f32>,
+}
+
+enum TreeNode {
+    /// Up to K item indexes stored in place of the vector
+    Bucket {
+        n_descendants: i32,
+        children: Vec<i32>,
+    },
+    Split {
+        n_descendants: i32,
+        children: [i32; 2],
+        v: Vec<f32>,
+        a: f32,
+    },
+}
+
+/// Nodes of a single tree, with the root last. Node references above the item count
+/// are relative to the start of the tree until it is placed in the file.
+struct Tree {
+    nodes: Vec<TreeNode>,
+}
+
+impl Items {
+    fn len(&self) -> i32 {
+        self.norms.len() as i32
+    }
+
+    fn vector(&self, item: i32) -> &[f32] {
+        let start = item as usize * self.dimension;
+        &self.vectors[start..start + self.dimension]
+    }
+
+    fn max_descendants(&self) -> usize {
+        (self.distance.node_size(self.dimension) - self.distance.children_offset()) / 4
+    }
+
+    fn build_tree(&self, seed: u64) -> Tree {
+        let mut tree = Tree { nodes: Vec::new() };
+        let mut random = Kiss64Random::new(seed);
+        let indices: Vec<i32> = (0..self.len()).collect();
+        self.make_tree(&mut tree, &mut random, indices, true);
+        tree
+    }
+
+    /// Port of annoylib `_make_tree`. Root nodes are identified by having [n_items] descendants.
+    fn make_tree(
+        &self,
+        tree: &mut Tree,
+        random: &mut Kiss64Random,
+        indices: Vec<i32>,
+        is_root: bool,
+    ) -> i32 {
+        let n_items = self.len();
+        if indices.len() == 1 && !is_root {
+            return indices[0];
+        }
+
+        let k = self.max_descendants();
+        if indices.len() <= k && (!is_root || n_items as usize <= k || indices.len() == 1) {
+            tree.nodes.push(TreeNode::Bucket {
+                n_descendants: if is_root {
+                    n_items
+                } else {
+                    indices.len() as i32
+                },
+                children: indices,
+            });
+            return n_items + tree.nodes.len() as i32 - 1;
+        }
+
+        let (mut v, a) = self.create_split(&indices, random);
+        let mut children_indices: [Vec<i32>; 2] = [Vec::new(), Vec::new()];
+        for j in &indices {
+            let margin = self.distance.margin(&v, a, self.vector(*j));
+            let side = if margin != 0.0 {
+                margin > 0.0
+            } else {
+                random.flip()
+            };
+            children_indices[side as usize].push(*j);
+        }
+
+        // If we didn't find a hyperplane, just randomize sides as a last option
+        while children_indices[0].is_empty() || children_indices[1].is_empty() {
+            children_indices[0].clear();
+            children_indices[1].clear();
+            for z in v.iter_mut() {
+                *z = 0.0;
+            }
+            for j in &indices {
+                children_indices[random.flip() as usize].push(*j);
+            }
+        }
+
+        // run make_tree for the smallest child first
+        let flip = (children_indices[0].len() > children_indices[1].len()) as usize;
+        let mut children = [0; 2];
+        for side in 0..2 {
+            let child_indices = std::mem::take(&mut children_indices[side ^ flip]);
+            children[side ^ flip] = self.make_tree(tree, random, child_indices, false);
+        }
+
+        tree.nodes.push(TreeNode::Split {
+            n_descendants: if is_root {
+                n_items
+            } else {
+                indices.len() as i32
+            },
+            children,
+            v,
+            a,
+        });
+        n_items + tree.nodes.len() as i32 - 1
+    }
+
+    /// Port of annoylib `create_split`, returning the plane normal and offset
+    fn create_split(&self, indices: &[i32], random: &mut Kiss64Random) -> (Vec<f32>, f32) {
+        let cosine = self.distance == Distance::Angular;
+        let (p, q) = self.two_means(indices, random, cosine);
+        let mut v: Vec<f32> = p.iter().zip(&q).map(|(p, q)| p - q).collect();
+        normalize(&mut v);
+
+        let mut a = 0.0f32;
+        if !cosine {
+            for z in 0..v.len() {
+                a += -v[z] * (p[z] + q[z]) / 2.0;
+            }
+        }
+        (v, a)
+    }
+
+    /// Port of annoylib `two_means`
+    fn two_means(
+        &self,
+        indices: &[i32],
+        random: &mut Kiss64Random,
+        cosine: bool,
+    ) -> (Vec<f32>, Vec<f32>) {
+        const ITERATION_STEPS: usize = 200;
+        let count = indices.len();
+
+        let i = random.index(count);
+        let mut j = random.index(count - 1);
+        if j >= i {
+            j += 1;
+        }
+
+        let mut p = self.vector(indices[i]).to_vec();
+        let mut q = self.vector(indices[j]).to_vec();
+        if cosine {
+            normalize(&mut p);
+            normalize(&mut q);
+        }
+        let mut p_norm = self.init_norm(&p);
+        let mut q_norm = self.init_norm(&q);
+
+        let mut ic = 1;
+        let mut jc = 1;
+        for _l in 0..ITERATION_STEPS {
+            let k = indices[random.index(count)];
+            let x = self.vector(k);
+            let x_norm = self.norms[k as usize];
+            let di = ic as f32 * self.distance.distance(&p, p_norm, x, x_norm);
+            let dj = jc as f32 * self.distance.distance(&q, q_norm, x, x_norm);
+            let norm = if cosine {
+                distance::dot(x, x).sqrt()
+            } else {
+                1.0
+            };
+            if norm.is_nan() || norm <= 0.0 {
+                continue;
+            }
+            if di < dj {
+                for z in 0..p.len() {
+                    p[z] = (p[z] * ic as f32 + x[z] / norm) / (ic + 1) as f32;
+                }
+                p_norm = self.init_norm(&p);
+                ic += 1;
+            } else if dj < di {
+                for z in 0..q.len() {
+                    q[z] = (q[z] * jc as f32 + x[z] / norm) / (jc + 1) as f32;
+                }
+                q_norm = self.init_norm(&q);
+                jc += 1;
+            }
+        }
+        (p, q)
+    }
+
+    /// Norm cached in the node by annoylib `init_node`, only used by the angular distance
+    fn init_norm(&self, v: &[f32]) -> f32 {
+        match self.distance {
+            Distance::Angular => distance::dot(v, v),
+            _ => 0.0,
+        }
+    }
+
+    fn write_item<W: Write>(&self, w: &mut W, item: i32) -> Result<(), Error> {
+        write_i32(w, 1)?;
+        match self.distance {
+            Distance::Angular => {
+                write_f32(w, self.norms[item as usize])?;
+                write_i32(w, 0)?;
+            }
+            _ => {
+                write_f32(w, 0.0)?;
+                write_i32(w, 0)?;
+                write_i32(w, 0)?;
+            }
+        }
+        for x in self.vector(item) {
+            write_f32(w, *x)?;
+        }
+        Ok(())
+    }
+
+    fn write_node<W: Write>(&self, w: &mut W, node: &TreeNode, offset: i32) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(self.distance.node_size(self.dimension));
+        match node {
+            TreeNode::Bucket {
+                n_descendants,
+                children,
+            } => {
+                write_i32(&mut buf, *n_descendants)?;
+                if self.distance != Distance::Angular {
+                    write_f32(&mut buf, 0.0)?;
+                }
+                for child in children {
+                    write_i32(&mut buf, *child)?;
+                }
+            }
+            TreeNode::Split {
+                n_descendants,
+                children,
+                v,
+                a,
+            } => {
+                write_i32(&mut buf, *n_descendants)?;
+                if self.distance != Distance::Angular {
+                    write_f32(&mut buf, *a)?;
+                }
+                for child in children {
+                    let child = if *child >= self.len() {
+                        child + offset
+                    } else {
+                        *child
+                    };
+                    write_i32(&mut buf, child)?;
+                }
+                for x in v {
+                    write_f32(&mut buf, *x)?;
+                }
+            }
+        }
+        buf.resize(self.distance.node_size(self.dimension), 0);
+        w.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+impl AnnoyBuilder {
+    /// Create a builder for index with vector of dimension [dimension]
+    /// ```
+    /// use annoy_rs::builder::AnnoyBuilder;
+    /// use annoy_rs::distance::Distance;
+    /// let builder = AnnoyBuilder::new(10, Distance::Angular).with_seed(42);
+    /// ```
+    pub fn new(dimension: i32, distance: Distance) -> AnnoyBuilder {
+        AnnoyBuilder {
+            items: Items {
+                dimension: dimension as usize,
+                distance,
+                vectors: Vec::new(),
+                norms: Vec::new(),
+            },
+            seed: Kiss64Random::DEFAULT_SEED,
+            threads: num_cpus::get(),
+            progress: None,
+        }
+    }
+
+    /// Seed of the random generator. Two builds of the same items with the same seed
+    /// produce identical files, whatever the number of threads.
+    pub fn with_seed(mut self, seed: u64) -> AnnoyBuilder {
+        self.seed = seed;
+        self
+    }
+
+    /// Number of threads building trees, defaults to the number of cpus
+    pub fn with_threads(mut self, threads: usize) -> AnnoyBuilder {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Callback called from the building thread each time a tree is finished
+    pub fn with_progress<F: ProgressCallback + 'static>(mut self, progress: F) -> AnnoyBuilder {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Add a vector to the index
+    /// returns item index, or `Error::DimensionError` when [v] is not of the index dimension
+    /// ```
+    /// use annoy_rs::builder::AnnoyBuilder;
+    /// use annoy_rs::distance::Distance;
+    /// let mut builder = AnnoyBuilder::new(2, Distance::Angular);
+    /// builder.add_item(&[0.0, 1.0]).unwrap();
+    /// let item_index = builder.add_item(&[0.0, 1.0]).unwrap();
+    /// assert_eq!(item_index, 1);
+    /// assert!(builder.add_item(&[0.0]).is_err());
+    /// ```
+    pub fn add_item(&mut self, v: &[f32]) -> Result<i32, Error> {
+        if v.len() != self.items.dimension {
+            return Err(Error::DimensionError(v.len(), self.items.dimension));
+        }
+        self.items.vectors.extend_from_slice(v);
+        let norm = self.items.init_norm(v);
+        self.items.norms.push(norm);
+        Ok(self.items.len() - 1)
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.items.dimension as i32
+    }
+
+    pub fn distance(&self) -> &Distance {
+        &self.items.distance
+    }
+
+    pub fn len(&self) -> i32 {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.len() == 0
+    }
+
+    /// Build [n_tree] trees, write the index to [path] and map it.
+    /// If None or a non-positive count is specified, trees are added until there are 2 * [item_count] nodes,
+    /// by batches of one tree per thread, the trees of the last batch past that count
+    /// being dropped so that the file does not depend on the number of threads.
+    /// Dot product and hamming distances are not supported, nor builds without items.
+    /// ```
+    /// use annoy_rs::builder::AnnoyBuilder;
+    /// use annoy_rs::distance::Distance;
+    /// let mut builder = AnnoyBuilder::new(2, Distance::Angular);
+    /// builder.add_item(&[0.0, 1.0]).unwrap();
+    /// builder.add_item(&[1.0, 0.0]).unwrap();
+    /// let path = std::env::temp_dir().join("builder_doc.tree");
+    /// let index = builder.build(Some(2), &path).unwrap();
+    /// assert_eq!(index.len(), 2);
+    /// ```
+    pub fn build<P: AsRef<Path>>(self, n_tree: Option<i32>, path: P) -> Result<AnnoyReader, Error> {
+        let AnnoyBuilder {
+            items,
+            seed,
+            threads,
+            mut progress,
+        } = self;
+        items.distance.check_pure_support()?;
+        if items.len() == 0 {
+            return Err(Error::EmptyIndex);
+        }
+        let items = Arc::new(items);
+        let mut random = Kiss64Random::new(seed);
+        let mut trees: Vec<Tree> = Vec::new();
+        let mut node_count = items.len() as usize;
+        let tree_count = n_tree.filter(|n| *n > 0).map(|n| n as usize);
+
+        loop {
+            let batch = match tree_count {
+                Some(count) => count - trees.len(),
+                None if node_count >= 2 * items.len() as usize => 0,
+                None => threads,
+            };
+            if batch == 0 {
+                break;
+            }
+
+            // seeds are drawn in tree order so that they do not depend on the batches
+            let seeds: Vec<u64> = (0..batch).map(|_| random.kiss()).collect();
+            let batch_trees = build_trees(&items, seeds, threads, |tree| {
+                if tree_count.is_none() && node_count >= 2 * items.len() as usize {
+                    return;
+                }
+                node_count += tree.nodes.len();
+                if let Some(ref mut progress) = progress {
+                    progress(BuildProgress {
+                        trees_built: trees.len() + 1,
+                        tree_count,
+                        node_count,
+                    });
+                }
+                trees.push(tree);
+            });
+            batch_trees?;
+        }
+
+        // the previous file may be mapped by a reader
+        storage::write_replacing(path.as_ref(), |w| {
+            for item in 0..items.len() {
+                items.write_item(w, item)?;
+            }
+            let mut offset = 0;
+            let mut roots = Vec::with_capacity(trees.len());
+            for tree in &trees {
+                for node in &tree.nodes {
+                    items.write_node(w, node, offset)?;
+                }
+                offset += tree.nodes.len() as i32;
+                roots.push(tree.nodes.last());
+            }
+
+            // Also, copy the roots into the last segment of the file
+            // This way they can be found without reading the whole file
+            let mut offset = 0;
+            for (tree, root) in trees.iter().zip(roots) {
+                if let Some(root) = root {
+                    items.write_node(w, root, offset)?;
+                }
+                offset += tree.nodes.len() as i32;
+            }
+            Ok(())
+        })?;
+
+        AnnoyReader::load(path, items.dimension as i32, items.distance)
+    }
+}
+
+/// Build one tree per seed on [threads] threads, handing them back in seed order
+fn build_trees<F: FnMut(Tree)>(
+    items: &Arc<Items>,
+    seeds: Vec<u64>,
+    threads: usize,
+    mut on_tree: F,
+) -> Result<(), Error> {
+    let seeds = Arc::new(seeds);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    let handles: Vec<_> = (0..threads.min(seeds.len()))
+        .map(|_| {
+            let items = items.clone();
+            let seeds = seeds.clone();
+            let next = next.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= seeds.len() {
+                    break;
+                }
+                let tree = items.build_tree(seeds[i]);
+                if sender.send((i, tree)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut pending: Vec<Option<Tree>> = (0..seeds.len()).map(|_| None).collect();
+    let mut delivered = 0;
+    for (i, tree) in receiver {
+        pending[i] = Some(tree);
+        while delivered < pending.len() {
+            match pending[delivered].take() {
+                Some(tree) => on_tree(tree),
+                None => break,
+            }
+            delivered += 1;
+        }
+    }
+
+    for handle in handles {
+        handle.join().map_err(|_| Error::BuildError)?;
+    }
+    if delivered < seeds.len() {
+        return Err(Error::BuildError);
+    }
+    Ok(())
+}
+
+fn normalize(v: &mut [f32]) {
+    let norm = distance::dot(v, v).sqrt();
+    if norm > 0.0 {
+        for z in v.iter_mut() {
+            *z /= norm;
+        }
+    }
+}
+
+fn write_i32<W: Write>(w: &mut W, value: i32) -> Result<(), Error> {
+    w.write_all(&value.to_ne_bytes())?;
+    Ok(())
+}
+
+fn write_f32<W: Write>(w: &mut W, value: f32) -> Result<(), Error> {
+    write_i32(w, value.to_bits() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Standard;
+    use rand::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    fn random_builder(distance: Distance, n: usize, f: usize) -> AnnoyBuilder {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut builder = AnnoyBuilder::new(f as i32, distance);
+        for _i in 0..n {
+            let v: Vec<f32> = rng.sample_iter(&Standard).take(f).collect();
+            builder.add_item(v.as_slice()).unwrap();
+        }
+        builder
+    }
+
+    #[test]
+    fn same_seed_same_file() {
+        let path1 = std::env::temp_dir().join("annoy_builder_seed1.tree");
+        let path2 = std::env::temp_dir().join("annoy_builder_seed2.tree");
+        for n_tree in &[Some(8), None] {
+            let index1 = random_builder(Distance::Euclidean, 500, 10)
+                .with_seed(42)
+                .with_threads(1)
+                .build(*n_tree, &path1)
+                .unwrap();
+            let index2 = random_builder(Distance::Euclidean, 500, 10)
+                .with_seed(42)
+                .with_threads(4)
+                .build(*n_tree, &path2)
+                .unwrap();
+
+            assert_eq!(index1.tree_count(), index2.tree_count());
+            assert_eq!(
+                std::fs::read(&path1).unwrap(),
+                std::fs::read(&path2).unwrap()
+            );
+        }
+        std::fs::remove_file(path1).unwrap();
+        std::fs::remove_file(path2).unwrap();
+    }
+
+    #[test]
+    fn build_and_search() {
+        for distance in &[Distance::Angular, Distance::Euclidean, Distance::Manhattan] {
+            let path = std::env::temp_dir().join(format!("annoy_builder_{:?}.tree", distance));
+            let builder = random_builder(*distance, 1000, 20);
+            let items: Vec<Vec<f32>> = (0..10).map(|i| builder.items.vector(i).to_vec()).collect();
+            let index = builder.build(Some(10), &path).unwrap();
+
+            assert_eq!(index.len(), 1000);
+            assert_eq!(index.tree_count(), 10);
+            for (i, v) in items.iter().enumerate() {
+                assert_eq!(index.get_item(i as i32).as_ref(), Some(v));
+                let (results, distances) = index.get_nns_by_item(i as i32, 10, None);
+                assert_eq!(results[0], i as i32);
+                assert_eq!(distances[0], 0.0);
+                let unique: HashSet<_> = results.iter().collect();
+                assert_eq!(unique.len(), 10);
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn progress_is_reported() {
+        let path = std::env::temp_dir().join("annoy_builder_progress.tree");
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_copy = reports.clone();
+        random_builder(Distance::Angular, 200, 5)
+            .with_threads(3)
+            .with_progress(move |p| reports_copy.lock().unwrap().push(p))
+            .build(Some(7), &path)
+            .unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 7);
+        for (i, p) in reports.iter().enumerate() {
+            assert_eq!(p.trees_built, i + 1);
+            assert_eq!(p.tree_count, Some(7));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rebuild_keeps_mapped_file() {
+        let path = std::env::temp_dir().join("annoy_builder_rebuild.tree");
+        let builder = random_builder(Distance::Euclidean, 200, 5);
+        let item = builder.items.vector(7).to_vec();
+        let index = builder.build(Some(4), &path).unwrap();
+        random_builder(Distance::Euclidean, 10, 5)
+            .build(Some(1), &path)
+            .unwrap();
+        assert_eq!(index.get_item(7), Some(item));
+        assert_eq!(index.get_nns_by_item(7, 1, None).0, vec![7]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_tree_count() {
+        let path = std::env::temp_dir().join("annoy_builder_default.tree");
+        let index = random_builder(Distance::Angular, 300, 5)
+            .build(None, &path)
+            .unwrap();
+        assert!(index.tree_count() > 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_builds() {
+        let path = std::env::temp_dir().join("annoy_builder_invalid.tree");
+        let mut builder = AnnoyBuilder::new(3, Distance::Euclidean);
+        assert!(builder.add_item(&[1.0, 2.0]).is_err());
+        assert!(builder.add_item(&[1.0, 2.0, 3.0, 4.0]).is_err());
+        assert!(builder.is_empty());
+        match builder.build(Some(2), &path) {
+            Err(Error::EmptyIndex) => {}
+            _ => panic!("an empty build must fail"),
+        }
+        assert!(!path.exists());
+
+        for n_tree in &[Some(0), Some(-1)] {
+            let index = random_builder(Distance::Euclidean, 300, 5)
+                .build(*n_tree, &path)
+                .unwrap();
+            assert!(index.tree_count() > 0);
+            assert_eq!(index.get_nns_by_item(3, 1, None).0, vec![3]);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn quantized_items() {
+        use quantization::{Quantization, QuantizedVectors};
+
+        let path = std::env::temp_dir().join("annoy_builder_quantized.tree");
+        let builder = random_builder(Distance::Euclidean, 1000, 20);
+        let items = QuantizedVectors::new(&builder.items.vectors, 20, Quantization::Int8);
+        let index = builder.build(Some(10), &path).unwrap();
+        let quantized = AnnoyReader::load(&path, 20, Distance::Euclidean)
+            .unwrap()
+            .with_items(items)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut found = 0;
+        for i in 0..100 {
+            let (expected, _) = index.get_nns_by_item(i, 10, None);
+            let (ids, _) = quantized.get_nns_by_item(i, 10, None);
+            assert_eq!(ids[0], i);
+            found += ids.iter().filter(|j| expected.contains(j)).count();
+        }
+        assert!(found > 900, "{}", found);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn readable_by_annoylib() {
+        use annoy::AnnoyIndexBuilder;
+
+        let path = std::env::temp_dir().join("annoy_builder_native.tree");
+        let reader = random_builder(Distance::Angular, 1000, 20)
+            .build(Some(10), &path)
+            .unwrap();
+        let native = AnnoyIndexBuilder::new(20, Distance::Angular).build(None);
+        native.load(path.clone()).unwrap();
+
+        assert_eq!(native.len(), reader.len());
+        for i in 0..100 {
+            assert_eq!(native.get_item(i), reader.get_item(i));
+            assert_eq!(
+                native.get_nns_by_item(i, 10, None),
+                reader.get_nns_by_item(i, 10, None)
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use err::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Distance {
+    Angular,
+    Euclidean,
+    Manhattan,
+    /// Maximum inner product search. Annoy returns the inner product, higher is closer.
+    DotProduct,
+    /// Components are bits (set when greater than 0.5), packed in u64 words by annoylib.
+    /// The dimension is the number of bits.
+    Hamming,
+}
+
+impl FromStr for Distance {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Distance, Error> {
+        match s {
+            "angular" => Ok(Distance::Angular),
+            "euclidean" => Ok(Distance::Euclidean),
+            "manhattan" => Ok(Distance::Manhattan),
+            "dot" => Ok(Distance::DotProduct),
+            "hamming" => Ok(Distance::Hamming),
+            _ => Err(Error::ParsingError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Distance::Angular => "angular",
+            Distance::Euclidean => "euclidean",
+            Distance::Manhattan => "manhattan",
+            Distance::DotProduct => "dot",
+            Distance::Hamming => "hamming",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Distance {
+    /// Exact distance between two vectors, as returned by annoy searches
+    /// ```
+    /// use annoy_rs::distance::Distance;
+    /// assert_eq!(Distance::Euclidean.between(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+    /// assert_eq!(Distance::DotProduct.between(&[1.0, 2.0], &[3.0, 4.0]), 11.0);
+    /// assert_eq!(Distance::Hamming.between(&[1.0, 0.0], &[1.0, 1.0]), 1.0);
+    /// ```
+    pub fn between(self, x: &[f32], y: &[f32]) -> f32 {
+        self.between_iter(x, y.iter().cloned())
+    }
+
+    /// Fail when [v] has a component that is not finite, or when it has a zero norm
+    /// for the angular distance, which has no direction to compare
+    /// ```
+    /// use annoy_rs::distance::Distance;
+    /// assert!(Distance::Euclidean.check_vector(&[0.0, 0.0]).is_ok());
+    /// assert!(Distance::Angular.check_vector(&[0.0, 0.0]).is_err());
+    /// assert!(Distance::Euclidean.check_vector(&[1.0, f32::NAN]).is_err());
+    /// ```
+    pub fn check_vector(self, v: &[f32]) -> Result<(), Error> {
+        if let Some(i) = v.iter().position(|x| !x.is_finite()) {
+            return Err(Error::NonFiniteComponent(i));
+        }
+        if self == Distance::Angular && v.iter().all(|x| *x == 0.0) {
+            return Err(Error::ZeroVector);
+        }
+        Ok(())
+    }
+
+    /// [between] with the components of [y] decoded on the fly, e.g. from quantized vectors
+    pub(crate) fn between_iter<I: Iterator<Item = f32>>(self, x: &[f32], y: I) -> f32 {
+        let pairs = x.iter().cloned().zip(y);
+        match self {
+            Distance::Angular => {
+                let (mut pp, mut qq, mut pq) = (0.0f32, 0.0f32, 0.0f32);
+                for (a, b) in pairs {
+                    pp += a * a;
+                    qq += b * b;
+                    pq += a * b;
+                }
+                let ppqq = pp * qq;
+                let d = if ppqq > 0.0 {
+                    2.0 - 2.0 * pq / ppqq.sqrt()
+                } else {
+                    2.0
+                };
+                d.max(0.0).sqrt()
+            }
+            Distance::Euclidean => pairs.map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt(),
+            Distance::Manhattan => pairs.map(|(a, b)| (a - b).abs()).sum(),
+            Distance::DotProduct => pairs.map(|(a, b)| a * b).sum(),
+            Distance::Hamming => pairs.filter(|(a, b)| (*a > 0.5) != (*b > 0.5)).count() as f32,
+        }
+    }
+}
+
+/// Node layout and distance functions of annoylib.h (1.15.2), used by the pure-Rust implementation.
+///
+/// Angular nodes are `{ n_descendants, children[2] | norm, v[f] }`,
+/// Euclidean and Manhattan nodes are `{ n_descendants, a, children[2], v[f] }`.
+impl Distance {
+    /// Dot product and hamming nodes have their own layout and are only supported by annoylib
+    pub(crate) fn check_pure_support(self) -> Result<(), Error> {
+        match self {
+            Distance::Angular | Distance::Euclidean | Distance::Manhattan => Ok(()),
+            Distance::DotProduct | Distance::Hamming => Err(Error::UnsupportedDistance(self)),
+        }
+    }
+
+    pub(crate) fn children_offset(self) -> usize {
+        match self {
+            Distance::Angular => 4,
+            Distance::Euclidean | Distance::Manhattan => 8,
+            Distance::DotProduct | Distance::Hamming => unreachable!(),
+        }
+    }
+
+    pub(crate) fn vector_offset(self) -> usize {
+        self.children_offset() + 8
+    }
+
+    pub(crate) fn node_size(self, dimension: usize) -> usize {
+        self.vector_offset() + dimension * 4
+    }
+
+    /// Distance between an item node [x] and a query node [y], before normalization.
+    /// [x_norm] and [y_norm] are the squared norms stored in angular nodes (0 if unknown).
+    pub(crate) fn distance(self, x: &[f32], x_norm: f32, y: &[f32], y_norm: f32) -> f32 {
+        match self {
+            Distance::Angular => {
+                let pp = if x_norm != 0.0 { x_norm } else { dot(x, x) };
+                let qq = if y_norm != 0.0 { y_norm } else { dot(y, y) };
+                let pq = dot(x, y);
+                let ppqq = pp * qq;
+                if ppqq > 0.0 {
+                    // annoylib computes this expression in double precision
+                    (2.0 - 2.0 * f64::from(pq) / f64::from(ppqq.sqrt())) as f32
+                } else {
+                    2.0
+                }
+            }
+            Distance::Euclidean => {
+                let mut d = 0.0f32;
+                for (a, b) in x.iter().zip(y) {
+                    d += (a - b) * (a - b);
+                }
+                d
+            }
+            Distance::Manhattan => {
+                let mut d = 0.0f32;
+                for (a, b) in x.iter().zip(y) {
+                    d += (a - b).abs();
+                }
+                d
+            }
+            Distance::DotProduct | Distance::Hamming => unreachable!(),
+        }
+    }
+
+    /// Signed distance of [y] to the split plane of a node with normal [v] and offset [a]
+    pub(crate) fn margin(self, v: &[f32], a: f32, y: &[f32]) -> f32 {
+        match self {
+            Distance::Angular => dot(v, y),
+            Distance::Euclidean | Distance::Manhattan => a + dot(v, y),
+            Distance::DotProduct | Distance::Hamming => unreachable!(),
+        }
+    }
+
+    pub(crate) fn normalized_distance(self, distance: f32) -> f32 {
+        match self {
+            Distance::Angular | Distance::Euclidean => distance.max(0.0).sqrt(),
+            Distance::Manhattan => distance.max(0.0),
+            Distance::DotProduct | Distance::Hamming => unreachable!(),
+        }
+    }
+}
+
+pub(crate) fn dot(x: &[f32], y: &[f32]) -> f32 {
+    let mut d = 0.0f32;
+    for (a, b) in x.iter().zip(y) {
+        d += a * b;
+    }
+    d
+}
@@ -1,3 +1,4 @@
+use distance::Distance;
 use std::fmt;
 use std::io;
 
@@ -6,6 +7,20 @@ pub enum Error {
     InvalidPath,
     KeyAlreadyPresent,
     ParsingError(String),
+    InvalidIndex(String),
+    BuildError,
+    /// Build of an index without items
+    EmptyIndex,
+    UnsupportedDistance(Distance),
+    UnsupportedAlgorithm(String),
+    /// Dimension of a vector, then of the index
+    DimensionError(usize, usize),
+    /// Position of the first component of a vector that is NaN or infinite
+    NonFiniteComponent(usize),
+    /// Vector of zero norm for the angular distance
+    ZeroVector,
+    /// Number of neighbours asked for a neighbour table, which must be positive
+    InvalidNeighbourCount(i32),
     IoError(io::Error),
 }
 impl From<io::Error> for Error {
@@ -20,6 +35,25 @@ impl fmt::Display for Error {
             Error::InvalidPath => write!(f, "Path is Invalid"),
             Error::KeyAlreadyPresent => write!(f, "Key is already present in the index"),
             Error::ParsingError(s) => write!(f, "Unable to parse {}", s),
+            Error::InvalidIndex(s) => write!(f, "Invalid index file: {}", s),
+            Error::BuildError => write!(f, "Index build failed"),
+            Error::EmptyIndex => write!(f, "Index has no items"),
+            Error::UnsupportedDistance(d) => write!(f, "Distance {:?} is not supported", d),
+            Error::UnsupportedAlgorithm(a) => {
+                write!(f, "Algorithm {} is not available for this index", a)
+            }
+            Error::DimensionError(actual, expected) => write!(
+                f,
+                "Vector of dimension {} for an index of dimension {}",
+                actual, expected
+            ),
+            Error::NonFiniteComponent(i) => {
+                write!(f, "Component {} of the vector is not finite", i)
+            }
+            Error::ZeroVector => write!(f, "Vector of zero norm for the angular distance"),
+            Error::InvalidNeighbourCount(k) => {
+                write!(f, "Invalid neighbour count {}, it must be positive", k)
+            }
             Error::IoError(e) => e.fmt(f),
         }
     }
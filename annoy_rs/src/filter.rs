@@ -0,0 +1,161 @@
+use err::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+/// Attributes of a single item
+pub type Attributes = HashMap<String, AttributeValue>;
+
+/// Filter expression on item attributes.
+/// Items without the attribute used by a condition never match it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Equals(String, AttributeValue),
+    OneOf(String, Vec<AttributeValue>),
+    /// Inclusive bounds on a numeric attribute
+    Range {
+        key: String,
+        min: f64,
+        max: f64,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl AttributeValue {
+    /// Parse a value of the attributes file: a boolean, a number or else a text
+    /// ```
+    /// use annoy_rs::filter::AttributeValue;
+    /// assert_eq!(AttributeValue::parse("true"), AttributeValue::Boolean(true));
+    /// assert_eq!(AttributeValue::parse("1.5"), AttributeValue::Number(1.5));
+    /// assert_eq!(AttributeValue::parse("FR"), AttributeValue::Text("FR".to_owned()));
+    /// ```
+    pub fn parse(value: &str) -> AttributeValue {
+        match value {
+            "true" => AttributeValue::Boolean(true),
+            "false" => AttributeValue::Boolean(false),
+            _ => value
+                .parse::<f64>()
+                .map(AttributeValue::Number)
+                .unwrap_or_else(|_| AttributeValue::Text(value.to_owned())),
+        }
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, attributes: &Attributes) -> bool {
+        match self {
+            Filter::Equals(key, value) => attributes.get(key) == Some(value),
+            Filter::OneOf(key, values) => match attributes.get(key) {
+                Some(value) => values.contains(value),
+                None => false,
+            },
+            Filter::Range { key, min, max } => match attributes.get(key) {
+                Some(AttributeValue::Number(value)) => min <= value && value <= max,
+                _ => false,
+            },
+            Filter::And(filters) => filters.iter().all(|f| f.matches(attributes)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(attributes)),
+            Filter::Not(filter) => !filter.matches(attributes),
+        }
+    }
+}
+
+/// Parse one line of the attributes file: tab separated `key=value` pairs
+pub fn parse_attributes(line: &str) -> Result<Attributes, Error> {
+    let mut attributes = Attributes::new();
+    for pair in line.split('\t').filter(|pair| !pair.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => {
+                attributes.insert(key.to_owned(), AttributeValue::parse(value));
+            }
+            _ => return Err(Error::ParsingError(line.to_owned())),
+        }
+    }
+    Ok(attributes)
+}
+
+/// Format attributes as a line of the attributes file
+pub fn format_attributes(attributes: &Attributes) -> String {
+    let mut pairs: Vec<String> = attributes
+        .iter()
+        .map(|(key, value)| match value {
+            AttributeValue::Text(v) => format!("{}={}", key, v),
+            AttributeValue::Number(v) => format!("{}={}", key, v),
+            AttributeValue::Boolean(v) => format!("{}={}", key, v),
+        })
+        .collect();
+    pairs.sort();
+    pairs.join("\t")
+}
+
+/// Read an attributes file, with one line per item in the same order as the mapping file
+pub fn read_attributes<P: AsRef<Path>>(path: P) -> Result<Vec<Attributes>, Error> {
+    let buf = BufReader::new(File::open(path)?);
+    let mut attributes = Vec::new();
+    for line in buf.lines() {
+        attributes.push(parse_attributes(&line?)?);
+    }
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Attributes {
+        parse_attributes("country=FR\tprice=12.5\tin_stock=true").unwrap()
+    }
+
+    #[test]
+    fn parse_test() {
+        let attributes = item();
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(attributes["country"], AttributeValue::Text("FR".to_owned()));
+        assert_eq!(attributes["price"], AttributeValue::Number(12.5));
+        assert_eq!(
+            parse_attributes(&format_attributes(&attributes)).unwrap(),
+            attributes
+        );
+        assert!(parse_attributes("").unwrap().is_empty());
+        assert!(parse_attributes("country").is_err());
+    }
+
+    #[test]
+    fn matches_test() {
+        let attributes = item();
+        let in_stock = Filter::Equals("in_stock".to_owned(), AttributeValue::Boolean(true));
+        let country = Filter::OneOf(
+            "country".to_owned(),
+            vec![
+                AttributeValue::Text("DE".to_owned()),
+                AttributeValue::Text("FR".to_owned()),
+            ],
+        );
+        let cheap = Filter::Range {
+            key: "price".to_owned(),
+            min: 0.0,
+            max: 10.0,
+        };
+        let missing = Filter::Equals("category".to_owned(), AttributeValue::Number(1.0));
+
+        assert!(in_stock.matches(&attributes));
+        assert!(country.matches(&attributes));
+        assert!(!cheap.matches(&attributes));
+        assert!(!missing.matches(&attributes));
+        assert!(Filter::And(vec![in_stock.clone(), country.clone()]).matches(&attributes));
+        assert!(!Filter::And(vec![in_stock.clone(), cheap.clone()]).matches(&attributes));
+        assert!(Filter::Or(vec![cheap.clone(), country]).matches(&attributes));
+        assert!(Filter::Not(Box::new(cheap)).matches(&attributes));
+    }
+}
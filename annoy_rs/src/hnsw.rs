@@ -0,0 +1,743 @@
+use distance::Distance;
+use err::Error;
+use quantization::{Quantization, Vectors};
+use random::Kiss64Random;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::path::Path;
+use storage::Storage;
+
+/// "HNSW" in ascii
+const MAGIC: u32 = 0x5753_4e48;
+const VERSION: u32 = 1;
+/// Header words: magic, version, dimension, item count, m, max level, entry point, quantization
+const HEADER_WORDS: usize = 8;
+const DEFAULT_EF: usize = 64;
+
+/// Search queue entry, ordered by key then item
+#[derive(PartialEq, Clone, Copy)]
+struct Candidate(f32, u32);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.0
+            .partial_cmp(&other.0)
+            .unwrap_or(Ordering::Equal)
+            .then(self.1.cmp(&other.1))
+    }
+}
+
+/// Distance used to order items, lower is closer
+fn key(distance: Distance, x: &[f32], y: &[f32]) -> f32 {
+    match distance {
+        Distance::DotProduct => -distance.between(x, y),
+        _ => distance.between(x, y),
+    }
+}
+
+/// Layers of a graph, either being built or loaded
+trait Layers {
+    fn key(&self, q: &[f32], i: u32) -> f32;
+    fn neighbours(&self, i: u32, layer: usize) -> &[u32];
+}
+
+/// Closest items to [q] on [layer] starting from [entries], at most [ef] of them by increasing key
+fn search_layer<L: Layers>(
+    graph: &L,
+    q: &[f32],
+    entries: &[Candidate],
+    ef: usize,
+    layer: usize,
+) -> Vec<Candidate> {
+    let mut visited: HashSet<u32> = entries.iter().map(|c| c.1).collect();
+    let mut candidates: BinaryHeap<Reverse<Candidate>> =
+        entries.iter().map(|c| Reverse(*c)).collect();
+    let mut results: BinaryHeap<Candidate> = entries.iter().cloned().collect();
+    while results.len() > ef {
+        results.pop();
+    }
+    while let Some(Reverse(c)) = candidates.pop() {
+        match results.peek() {
+            Some(furthest) if results.len() >= ef && c.0 > furthest.0 => break,
+            _ => {}
+        }
+        for &j in graph.neighbours(c.1, layer) {
+            if !visited.insert(j) {
+                continue;
+            }
+            let d = graph.key(q, j);
+            let closer = match results.peek() {
+                Some(furthest) => results.len() < ef || d < furthest.0,
+                None => true,
+            };
+            if closer {
+                candidates.push(Reverse(Candidate(d, j)));
+                results.push(Candidate(d, j));
+                if results.len() > ef {
+                    results.pop();
+                }
+            }
+        }
+    }
+    results.into_sorted_vec()
+}
+
+/// Append the count of [links] then [links] padded to [max_links] words
+fn push_links(words: &mut Vec<u32>, links: &[u32], max_links: usize) {
+    words.push(links.len() as u32);
+    words.extend_from_slice(links);
+    words.extend((links.len()..max_links).map(|_| 0));
+}
+
+/// Pure-Rust hierarchical navigable small world graph builder.
+/// Items are inserted in order, so the graph only depends on the items and the seed.
+pub struct HnswBuilder {
+    dimension: usize,
+    distance: Distance,
+    m: usize,
+    ef_construction: usize,
+    seed: u64,
+    quantization: Quantization,
+    vectors: Vec<f32>,
+}
+
+/// Graph being built
+struct Graph<'a> {
+    dimension: usize,
+    distance: Distance,
+    vectors: &'a [f32],
+    /// Neighbours of each item on each of its layers
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    max_level: usize,
+}
+
+impl<'a> Graph<'a> {
+    fn vector(&self, i: u32) -> &'a [f32] {
+        let start = i as usize * self.dimension;
+        &self.vectors[start..start + self.dimension]
+    }
+
+    fn insert(&mut self, i: u32, level: usize, m: usize, ef_construction: usize) {
+        self.links.push(vec![Vec::new(); level + 1]);
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(i);
+                self.max_level = level;
+                return;
+            }
+        };
+        let q = self.vector(i);
+        let mut entries = vec![Candidate(self.key(q, entry), entry)];
+        for layer in (level + 1..=self.max_level).rev() {
+            entries = search_layer(self, q, &entries, 1, layer);
+        }
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = search_layer(self, q, &entries, ef_construction, layer);
+            let max_links = if layer == 0 { 2 * m } else { m };
+            let selected: Vec<u32> = found.iter().take(m).map(|c| c.1).collect();
+            for &j in &selected {
+                let mut links = std::mem::take(&mut self.links[j as usize][layer]);
+                links.push(i);
+                if links.len() > max_links {
+                    let v = self.vector(j);
+                    let mut scored: Vec<Candidate> = links
+                        .iter()
+                        .map(|&l| Candidate(self.key(v, l), l))
+                        .collect();
+                    scored.sort();
+                    links = scored.iter().take(max_links).map(|c| c.1).collect();
+                }
+                self.links[j as usize][layer] = links;
+            }
+            self.links[i as usize][layer] = selected;
+            entries = found;
+        }
+        if level > self.max_level {
+            self.entry = Some(i);
+            self.max_level = level;
+        }
+    }
+}
+
+impl<'a> Layers for Graph<'a> {
+    fn key(&self, q: &[f32], i: u32) -> f32 {
+        key(self.distance, q, self.vector(i))
+    }
+
+    fn neighbours(&self, i: u32, layer: usize) -> &[u32] {
+        &self.links[i as usize][layer]
+    }
+}
+
+impl HnswBuilder {
+    pub const DEFAULT_M: usize = 16;
+    pub const DEFAULT_EF_CONSTRUCTION: usize = 200;
+
+    pub fn new(dimension: i32, distance: Distance) -> HnswBuilder {
+        HnswBuilder {
+            dimension: dimension as usize,
+            distance,
+            m: HnswBuilder::DEFAULT_M,
+            ef_construction: HnswBuilder::DEFAULT_EF_CONSTRUCTION,
+            seed: Kiss64Random::DEFAULT_SEED,
+            quantization: Quantization::F32,
+            vectors: Vec::new(),
+        }
+    }
+
+    /// Number of neighbours of each item on the upper layers, twice as many on the first one
+    pub fn with_m(mut self, m: usize) -> HnswBuilder {
+        self.m = m.max(2);
+        self
+    }
+
+    /// Number of candidates considered when inserting an item
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> HnswBuilder {
+        self.ef_construction = ef_construction.max(1);
+        self
+    }
+
+    /// Seed of the random levels of the items
+    pub fn with_seed(mut self, seed: u64) -> HnswBuilder {
+        self.seed = seed;
+        self
+    }
+
+    /// Store the item vectors as [quantization], the graph is still built on the f32 vectors
+    pub fn with_quantization(mut self, quantization: Quantization) -> HnswBuilder {
+        self.quantization = quantization;
+        self
+    }
+
+    pub fn add_item(&mut self, v: &[f32]) -> i32 {
+        let id = self.len();
+        self.vectors.extend_from_slice(&v[..self.dimension]);
+        id
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.dimension as i32
+    }
+
+    pub fn distance(&self) -> &Distance {
+        &self.distance
+    }
+
+    pub fn len(&self) -> i32 {
+        (self.vectors.len() / self.dimension.max(1)) as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /// Build the graph in memory
+    /// ```
+    /// use annoy_rs::distance::Distance;
+    /// use annoy_rs::hnsw::HnswBuilder;
+    /// let mut builder = HnswBuilder::new(2, Distance::Euclidean);
+    /// for i in 0..100 {
+    ///     builder.add_item(&[i as f32, 0.0]);
+    /// }
+    /// let index = builder.build();
+    /// let (results, _) = index.get_nns_by_vector(&[10.2, 0.0], 2, None);
+    /// assert_eq!(results, vec![10, 11]);
+    /// ```
+    pub fn build(self) -> Hnsw {
+        let count = self.len() as usize;
+        let mut rng = Kiss64Random::new(self.seed);
+        let level_factor = 1.0 / (self.m as f64).ln();
+        let levels: Vec<usize> = (0..count)
+            .map(|_| {
+                let u = (rng.kiss() >> 11) as f64 / (1u64 << 53) as f64;
+                (-(1.0 - u).ln() * level_factor) as usize
+            })
+            .collect();
+
+        let mut graph = Graph {
+            dimension: self.dimension,
+            distance: self.distance,
+            vectors: &self.vectors,
+            links: Vec::with_capacity(count),
+            entry: None,
+            max_level: 0,
+        };
+        for (i, level) in levels.iter().enumerate() {
+            graph.insert(i as u32, *level, self.m, self.ef_construction);
+        }
+
+        let m = self.m;
+        let mut words = vec![
+            MAGIC,
+            VERSION,
+            self.dimension as u32,
+            count as u32,
+            m as u32,
+            graph.max_level as u32,
+            graph.entry.unwrap_or(0),
+            self.quantization.to_word(),
+        ];
+        words.extend(self.quantization.encode(&self.vectors, self.dimension));
+        words.extend(levels.iter().map(|l| *l as u32));
+        for links in &graph.links {
+            push_links(&mut words, &links[0], 2 * m);
+        }
+        for links in &graph.links {
+            for layer in &links[1..] {
+                push_links(&mut words, layer, m);
+            }
+        }
+        Hnsw::from_storage(Storage::Owned(words), self.dimension, self.distance)
+            .expect("invalid graph")
+    }
+}
+
+/// Hierarchical navigable small world graph, built by `HnswBuilder` or memory mapped from a file.
+///
+/// The file holds native endian words: a header, the item vectors encoded as set by
+/// `HnswBuilder::with_quantization`, the level of each item,
+/// the neighbours of each item on the first layer (a count followed by 2m slots),
+/// then the neighbours of each item on each of its upper layers (a count followed by m slots).
+pub struct Hnsw {
+    distance: Distance,
+    storage: Storage,
+    dimension: usize,
+    quantization: Quantization,
+    count: usize,
+    m: usize,
+    max_level: usize,
+    entry: u32,
+    levels_offset: usize,
+    links_offset: usize,
+    /// Offset of the upper layers of each item, 0 for items only on the first layer
+    upper_offsets: Vec<usize>,
+}
+
+impl Layers for Hnsw {
+    fn key(&self, q: &[f32], i: u32) -> f32 {
+        let d = self.vectors().between(self.distance, q, i as usize);
+        match self.distance {
+            Distance::DotProduct => -d,
+            _ => d,
+        }
+    }
+
+    fn neighbours(&self, i: u32, layer: usize) -> &[u32] {
+        let start = self.links_start(i, layer);
+        let words = self.storage.words();
+        let count = words[start] as usize;
+        &words[start + 1..start + 1 + count]
+    }
+}
+
+impl Hnsw {
+    fn from_storage(storage: Storage, dimension: usize, distance: Distance) -> Result<Hnsw, Error> {
+        let (count, m, max_level, entry, quantization) = {
+            let words = storage.words();
+            if words.len() < HEADER_WORDS || words[0] != MAGIC || words[1] != VERSION {
+                return Err(Error::InvalidIndex("not a hnsw file".to_owned()));
+            }
+            if words[2] as usize != dimension {
+                return Err(Error::InvalidIndex(format!(
+                    "hnsw dimension {}, expected {}",
+                    words[2], dimension
+                )));
+            }
+            (
+                words[3] as usize,
+                words[4] as usize,
+                words[5] as usize,
+                words[6],
+                Quantization::from_word(words[7])?,
+            )
+        };
+        // every item has at least a level and a link count, which bounds the sizes below
+        let length = storage.words().len();
+        if count > length || m > length {
+            return Err(Error::InvalidIndex("truncated hnsw file".to_owned()));
+        }
+        let levels_offset = HEADER_WORDS + quantization.words(count, dimension);
+        let links_offset = levels_offset + count;
+        let mut upper_offsets = vec![0; count];
+        let mut offset = links_offset + count * (1 + 2 * m);
+        {
+            let words = storage.words();
+            if words.len() < offset {
+                return Err(Error::InvalidIndex("truncated hnsw file".to_owned()));
+            }
+            for (i, level) in words[levels_offset..links_offset].iter().enumerate() {
+                if *level as usize > max_level {
+                    return Err(Error::InvalidIndex(format!(
+                        "hnsw level {} of item {} above the maximum {}",
+                        level, i, max_level
+                    )));
+                }
+                if *level > 0 {
+                    upper_offsets[i] = offset;
+                    offset += *level as usize * (1 + m);
+                }
+            }
+            if words.len() != offset {
+                return Err(Error::InvalidIndex(format!(
+                    "hnsw file of {} words, expected {}",
+                    words.len(),
+                    offset
+                )));
+            }
+        }
+        let hnsw = Hnsw {
+            distance,
+            storage,
+            dimension,
+            quantization,
+            count,
+            m,
+            max_level,
+            entry,
+            levels_offset,
+            links_offset,
+            upper_offsets,
+        };
+        hnsw.check_links()?;
+        Ok(hnsw)
+    }
+
+    /// Check that searches stay within the graph: the entry point is an item of the top
+    /// layer and the links of each item and layer are items, no more than their slots
+    fn check_links(&self) -> Result<(), Error> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        if self.entry as usize >= self.count || self.level(self.entry as i32) != self.max_level {
+            return Err(Error::InvalidIndex(format!(
+                "hnsw entry point {} is not on layer {}",
+                self.entry, self.max_level
+            )));
+        }
+        let words = self.storage.words();
+        for i in 0..self.count as u32 {
+            for layer in 0..=self.level(i as i32) {
+                let start = self.links_start(i, layer);
+                let slots = if layer == 0 { 2 * self.m } else { self.m };
+                let count = words[start] as usize;
+                if count > slots {
+                    return Err(Error::InvalidIndex(format!(
+                        "{} hnsw links of item {} on layer {}, at most {}",
+                        count, i, layer, slots
+                    )));
+                }
+                let links = &words[start + 1..start + 1 + count];
+                if let Some(link) = links.iter().find(|j| **j as usize >= self.count) {
+                    return Err(Error::InvalidIndex(format!(
+                        "hnsw link of item {} to unknown item {}",
+                        i, link
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Offset of the link count of item [i] on [layer], followed by its links
+    fn links_start(&self, i: u32, layer: usize) -> usize {
+        if layer == 0 {
+            self.links_offset + i as usize * (1 + 2 * self.m)
+        } else {
+            self.upper_offsets[i as usize] + (layer - 1) * (1 + self.m)
+        }
+    }
+
+    /// Map the graph file at [path] built with vectors of dimension [dimension]
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        dimension: i32,
+        distance: Distance,
+    ) -> Result<Hnsw, Error> {
+        Hnsw::from_storage(Storage::map(path)?, dimension as usize, distance)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.storage.save(path)
+    }
+
+    fn vectors(&self) -> Vectors<'_> {
+        Vectors {
+            quantization: self.quantization,
+            dimension: self.dimension,
+            words: &self.storage.words()[HEADER_WORDS..self.levels_offset],
+        }
+    }
+
+    pub fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.dimension as i32
+    }
+
+    pub fn distance(&self) -> &Distance {
+        &self.distance
+    }
+
+    pub fn len(&self) -> i32 {
+        self.count as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Return the highest layer of [item]
+    pub fn level(&self, item: i32) -> usize {
+        self.storage.words()[self.levels_offset + item as usize] as usize
+    }
+
+    /// Return the vector of [item], dequantized
+    pub fn get_item(&self, item: i32) -> Option<Vec<f32>> {
+        if item < 0 || item >= self.len() {
+            return None;
+        }
+        Some(self.vectors().get(item as usize))
+    }
+
+    /// Return the [n] closer item to item index [item], see [get_nns_by_vector]
+    pub fn get_nns_by_item(&self, item: i32, n: i32, ef: Option<i32>) -> (Vec<i32>, Vec<f32>) {
+        if item < 0 || item >= self.len() {
+            return (Vec::new(), Vec::new());
+        }
+        self.get_nns_by_vector(&self.vectors().get(item as usize), n, ef)
+    }
+
+    /// Return the [n] closer item to vector [w], keeping [ef] candidates on the first layer.
+    /// When using None for ef it uses the maximum of [n] and 64.
+    /// Distances are the ones of annoy, so the inner product for dot product graphs.
+    pub fn get_nns_by_vector(&self, w: &[f32], n: i32, ef: Option<i32>) -> (Vec<i32>, Vec<f32>) {
+        if self.count == 0 {
+            return (Vec::new(), Vec::new());
+        }
+        let q = &w[..self.dimension];
+        let n = if n < 0 { self.count } else { n as usize };
+        let ef = match ef {
+            Some(ef) if ef > 0 => (ef as usize).max(n),
+            _ => n.max(DEFAULT_EF),
+        };
+        let mut entries = vec![Candidate(self.key(q, self.entry), self.entry)];
+        for layer in (1..=self.max_level).rev() {
+            entries = search_layer(self, q, &entries, 1, layer);
+        }
+        let mut found = search_layer(self, q, &entries, ef, 0);
+        found.truncate(n);
+        found
+            .into_iter()
+            .map(|Candidate(d, i)| {
+                let d = match self.distance {
+                    Distance::DotProduct => -d,
+                    _ => d,
+                };
+                (i as i32, d)
+            })
+            .unzip()
+    }
+
+    /// Return items within [max_distance] of [w], closest first, up to [max_results] of them.
+    /// The last value is true when more items might be within the distance.
+    /// For dot product graphs, items with an inner product of at least [max_distance] are returned.
+    pub fn get_nns_within(
+        &self,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        ef: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>, bool) {
+        let within = |d: &f32| match self.distance {
+            Distance::DotProduct => *d >= max_distance,
+            _ => *d <= max_distance,
+        };
+        let mut n = DEFAULT_EF as i32;
+        loop {
+            let fetched = n.min(max_results.saturating_add(1));
+            let (ids, distances) = self.get_nns_by_vector(w, fetched, ef);
+            let count = distances.iter().take_while(|d| within(d)).count();
+            let exhausted = (ids.len() as i32) < fetched;
+            if count < ids.len() || exhausted || fetched > max_results {
+                let truncated = count > max_results as usize;
+                let count = count.min(max_results.max(0) as usize);
+                return (
+                    ids[..count].to_vec(),
+                    distances[..count].to_vec(),
+                    truncated,
+                );
+            }
+            n = n.saturating_mul(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Standard;
+    use rand::prelude::*;
+
+    fn random_vectors(n: usize, f: usize) -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..n)
+            .map(|_| rng.sample_iter(&Standard).take(f).collect())
+            .collect()
+    }
+
+    fn exact_nns(vectors: &[Vec<f32>], distance: Distance, q: &[f32], n: usize) -> Vec<i32> {
+        let mut scored: Vec<Candidate> = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| Candidate(key(distance, q, v), i as u32))
+            .collect();
+        scored.sort();
+        scored.iter().take(n).map(|c| c.1 as i32).collect()
+    }
+
+    fn recall(distance: Distance, quantization: Quantization) -> f64 {
+        let vectors = random_vectors(2000, 16);
+        let mut builder = HnswBuilder::new(16, distance)
+            .with_m(8)
+            .with_quantization(quantization);
+        for v in &vectors {
+            builder.add_item(v);
+        }
+        let index = builder.build();
+        let queries = random_vectors(50, 16);
+        let mut found = 0;
+        for q in &queries {
+            let expected = exact_nns(&vectors, distance, q, 10);
+            let (ids, _) = index.get_nns_by_vector(q, 10, Some(100));
+            found += ids.iter().filter(|id| expected.contains(id)).count();
+        }
+        found as f64 / (queries.len() * 10) as f64
+    }
+
+    #[test]
+    fn recall_test() {
+        assert!(recall(Distance::Euclidean, Quantization::F32) > 0.9);
+        assert!(recall(Distance::Angular, Quantization::F32) > 0.9);
+        assert!(recall(Distance::DotProduct, Quantization::F32) > 0.9);
+    }
+
+    #[test]
+    fn corrupt_test() {
+        let mut builder = HnswBuilder::new(8, Distance::Euclidean).with_m(4);
+        for v in &random_vectors(100, 8) {
+            builder.add_item(v);
+        }
+        let path = std::env::temp_dir().join("annoy_hnsw_corrupt");
+        builder.build().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let word = |i: usize| {
+            u32::from_ne_bytes([
+                bytes[4 * i],
+                bytes[4 * i + 1],
+                bytes[4 * i + 2],
+                bytes[4 * i + 3],
+            ])
+        };
+        let load = |i: usize, value: u32| {
+            let mut corrupt = bytes.clone();
+            corrupt[4 * i..4 * i + 4].copy_from_slice(&value.to_ne_bytes());
+            std::fs::write(&path, &corrupt).unwrap();
+            Hnsw::load(&path, 8, Distance::Euclidean)
+        };
+        let links_offset = HEADER_WORDS + 100 * 8 + 100;
+        assert!(load(6, word(6)).is_ok());
+        // entry point, max level, link count and link of the first item
+        assert!(load(6, 100).is_err());
+        assert!(load(5, word(5) + 1).is_err());
+        assert!(load(links_offset, 9).is_err());
+        assert!(word(links_offset) > 0);
+        assert!(load(links_offset + 1, 100).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quantized_test() {
+        assert!(recall(Distance::Euclidean, Quantization::F16) > 0.9);
+        assert!(recall(Distance::Euclidean, Quantization::Int8) > 0.8);
+
+        let vectors = random_vectors(100, 32);
+        let size = |quantization| {
+            let mut builder =
+                HnswBuilder::new(32, Distance::Angular).with_quantization(quantization);
+            for v in &vectors {
+                builder.add_item(v);
+            }
+            let index = builder.build();
+            let decoded = index.get_item(3).unwrap();
+            assert!(Distance::Angular.between(&vectors[3], &decoded) < 0.01);
+            let path = std::env::temp_dir().join(format!("annoy_hnsw_{}", quantization));
+            index.save(&path).unwrap();
+            let loaded = Hnsw::load(&path, 32, Distance::Angular).unwrap();
+            assert_eq!(loaded.quantization(), quantization);
+            assert_eq!(loaded.get_item(3), Some(decoded));
+            let size = std::fs::metadata(&path).unwrap().len();
+            std::fs::remove_file(&path).unwrap();
+            size
+        };
+        let (f32_size, f16_size, int8_size) = (
+            size(Quantization::F32),
+            size(Quantization::F16),
+            size(Quantization::Int8),
+        );
+        // vectors are 100 * 32 * 4 bytes out of the f32 file
+        assert_eq!(f32_size - f16_size, 100 * 32 * 2);
+        assert_eq!(f32_size - int8_size, 100 * 32 * 3 - 32 * 4);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let vectors = random_vectors(500, 8);
+        let mut builder = HnswBuilder::new(8, Distance::Manhattan).with_seed(7);
+        for v in &vectors {
+            builder.add_item(v);
+        }
+        let index = builder.build();
+        let path = std::env::temp_dir().join("annoy_hnsw_test");
+        index.save(&path).unwrap();
+        let loaded = Hnsw::load(&path, 8, Distance::Manhattan).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 500);
+        assert_eq!(loaded.get_item(3), Some(vectors[3].clone()));
+        for i in 0..50 {
+            assert_eq!(
+                loaded.get_nns_by_item(i, 10, None),
+                index.get_nns_by_item(i, 10, None)
+            );
+        }
+        assert!(Hnsw::load("test.tree", 8, Distance::Manhattan).is_err());
+    }
+
+    #[test]
+    fn within_test() {
+        let mut builder = HnswBuilder::new(1, Distance::Euclidean);
+        for i in 0..1000 {
+            builder.add_item(&[i as f32]);
+        }
+        let index = builder.build();
+        let (ids, distances, truncated) = index.get_nns_within(&[500.0], 100.5, 1000, None);
+        assert_eq!(ids.len(), 201);
+        assert!(distances.iter().all(|d| *d <= 100.5));
+        assert!(!truncated);
+        let (ids, _, truncated) = index.get_nns_within(&[500.0], 100.5, 10, None);
+        assert_eq!(ids.len(), 10);
+        assert!(truncated);
+    }
+}
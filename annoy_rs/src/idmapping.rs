@@ -1,10 +1,134 @@
 use annoy::{AnnoyIndex, AnnoyIndexBuilder, Distance};
 use err::Error;
+use filter::{self, Attributes, Filter};
+use hnsw::{Hnsw, HnswBuilder};
+use ivfpq::{IvfPq, IvfPqBuilder};
+use memmap::Mmap;
+use preprocessing::Pipeline;
+use quantization::{Quantization, QuantizedVectors};
+use random::Kiss64Random;
+use reader::AnnoyReader;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+
+/// Structure searched by a mapping index
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Algorithm {
+    /// Random projection trees of annoylib, search_k is the number of nodes inspected
+    Annoy,
+    /// Pure-Rust HNSW graph, search_k is the number of candidates kept on the first layer
+    Hnsw,
+    /// Inverted file with product quantization, search_k is the number of lists probed
+    IvfPq,
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Algorithm, Error> {
+        match s {
+            "annoy" => Ok(Algorithm::Annoy),
+            "hnsw" => Ok(Algorithm::Hnsw),
+            "ivfpq" => Ok(Algorithm::IvfPq),
+            _ => Err(Error::ParsingError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Algorithm::Annoy => "annoy",
+            Algorithm::Hnsw => "hnsw",
+            Algorithm::IvfPq => "ivfpq",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Structure searched by a request, borrowed from the index
+#[derive(Clone, Copy)]
+enum Backend<'a> {
+    Annoy,
+    Hnsw(&'a Hnsw),
+    IvfPq(&'a IvfPq),
+}
+
+/// Fail unless [preprocessing] outputs vectors of [dimension]
+fn check_preprocessing(preprocessing: &Pipeline, dimension: i32) -> Result<(), Error> {
+    let input = preprocessing
+        .input_dimension()
+        .unwrap_or(dimension as usize);
+    let output = preprocessing.output_dimension(input)?;
+    if output != dimension as usize {
+        return Err(Error::DimensionError(output, dimension as usize));
+    }
+    Ok(())
+}
+
+/// Fail unless [vector] can be indexed with [dimension] and [distance]
+fn check_vector(vector: &[f32], dimension: i32, distance: Distance) -> Result<(), Error> {
+    if vector.len() != dimension as usize {
+        return Err(Error::DimensionError(vector.len(), dimension as usize));
+    }
+    distance.check_vector(vector)
+}
+
+/// Vector upserted into a loaded index
+#[derive(Clone)]
+struct DeltaItem {
+    vector: Vec<f32>,
+    attributes: Attributes,
+    /// Version of the upsert
+    version: u64,
+}
+
+/// Changes of an index since it was built, see `MappingIndex::delete` and `MappingIndex::upsert`
+struct Updates<T> {
+    /// Annoy indexes of the deleted items, with the version of their deletion
+    deleted: HashMap<i32, u64>,
+    /// File the deletions are appended to, None when no tombstones file was loaded
+    tombstones_path: Option<PathBuf>,
+    /// Upserted items, searched exactly and shadowing the annoy index
+    delta: HashMap<T, DeltaItem>,
+    /// Upserted items deleted since, with the version of their deletion
+    removed: HashMap<T, u64>,
+    /// Number of upserts and deletions
+    version: u64,
+}
+
+impl<T> Updates<T>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+{
+    fn new() -> Updates<T> {
+        Updates {
+            deleted: HashMap::new(),
+            tombstones_path: None,
+            delta: HashMap::new(),
+            removed: HashMap::new(),
+            version: 0,
+        }
+    }
+
+    /// Return true when the annoy index [key] of [item] is deleted or shadowed by an upsert
+    fn skips(&self, key: i32, item: &T) -> bool {
+        self.deleted.contains_key(&key) || self.delta.contains_key(item)
+    }
+
+    /// Maximum number of items of the annoy index skipped by searches
+    fn skipped(&self) -> usize {
+        self.deleted.len() + self.delta.len()
+    }
+}
 
 pub struct MappingIndexBuilder<T>
 where
@@ -14,6 +138,9 @@ where
     index: AnnoyIndexBuilder,
     map: HashMap<T, i32>,
     inverse_map: HashMap<i32, T>,
+    attributes: Vec<Attributes>,
+    hnsw: Option<HnswBuilder>,
+    preprocessing: Pipeline,
 }
 
 pub struct MappingIndex<T>
@@ -22,8 +149,30 @@ where
 {
     index_id: String,
     index: AnnoyIndex,
+    /// Trees of the annoy index file searched with quantized item vectors in place of the
+    /// annoylib index, None unless loaded by [load_quantized]
+    trees: Option<AnnoyReader>,
     map: HashMap<T, i32>,
     inverse_map: HashMap<i32, T>,
+    /// Attributes of each item by annoy index, empty when no attributes were given
+    attributes: Vec<Attributes>,
+    /// Original vectors of the items by annoy index as little endian f32, None when not loaded
+    vectors: Option<Mmap>,
+    /// Number of precomputed neighbours of each item, 0 when not loaded
+    neighbour_count: usize,
+    /// Precomputed neighbours by annoy index, padded with -1
+    neighbours: Vec<i32>,
+    neighbour_distances: Vec<f32>,
+    /// HNSW graph of the items by annoy index, None when not built or loaded
+    hnsw: Option<Hnsw>,
+    /// Compressed codes of the items by annoy index, None when not built or loaded
+    ivfpq: Option<IvfPq>,
+    /// Algorithm of the searches not choosing one
+    algorithm: Algorithm,
+    /// Deleted and upserted items, merged into the searches until the index is compacted
+    updates: RwLock<Updates<T>>,
+    /// Transformation of the query vectors into the space of the indexed vectors
+    preprocessing: Pipeline,
 }
 
 impl<T> MappingIndexBuilder<T>
@@ -39,28 +188,125 @@ where
             index,
             map,
             inverse_map,
+            attributes: Vec::new(),
+            hnsw: None,
+            preprocessing: Pipeline::new(),
+        }
+    }
+
+    /// Builder storing the annoy index in [index_file_path] while items are added,
+    /// for datasets that do not fit in memory. The mapping is still kept in memory.
+    pub fn on_disk<P: AsRef<Path>>(
+        index_id: &str,
+        dimension: i32,
+        distance: Distance,
+        index_file_path: P,
+    ) -> Result<Self, Error> {
+        let index = AnnoyIndexBuilder::on_disk(dimension, distance, index_file_path)?;
+        Ok(MappingIndexBuilder {
+            index_id: index_id.to_owned(),
+            index,
+            map: HashMap::default(),
+            inverse_map: HashMap::default(),
+            attributes: Vec::new(),
+            hnsw: None,
+            preprocessing: Pipeline::new(),
+        })
+    }
+
+    /// Seed the random generator used to build the trees and the graph
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.index = self.index.with_seed(seed);
+        self.hnsw = self.hnsw.map(|hnsw| hnsw.with_seed(seed));
+        self
+    }
+
+    /// Also build a HNSW graph of the items with [m] neighbours per item and layer
+    /// and [ef_construction] candidates per insertion, storing vectors with [quantization],
+    /// see `HnswBuilder`. The annoy index keeps its f32 vectors, see [save_quantized].
+    pub fn with_hnsw(
+        mut self,
+        m: usize,
+        ef_construction: usize,
+        quantization: Quantization,
+    ) -> Self {
+        let mut hnsw = HnswBuilder::new(self.index.dimension(), *self.index.distance())
+            .with_m(m)
+            .with_ef_construction(ef_construction)
+            .with_quantization(quantization);
+        if let Some(seed) = self.index.seed() {
+            hnsw = hnsw.with_seed(seed);
         }
+        self.hnsw = Some(hnsw);
+        self
+    }
+
+    /// Transform the vectors put with [preprocessing], which must output vectors of the
+    /// dimension of the index. The built index applies it to the query vectors.
+    pub fn with_preprocessing(mut self, preprocessing: Pipeline) -> Result<Self, Error> {
+        check_preprocessing(&preprocessing, self.index.dimension())?;
+        self.preprocessing = preprocessing;
+        Ok(self)
     }
 
     pub fn put(&mut self, item: T, vector: &[f32]) -> Result<(), Error> {
+        self.put_with_attributes(item, vector, Attributes::new())
+    }
+
+    /// Add an item with attributes that can be used to filter search results.
+    /// Fail when the preprocessed vector has another dimension than the index, a component
+    /// that is not finite, or a zero norm for the angular distance.
+    pub fn put_with_attributes(
+        &mut self,
+        item: T,
+        vector: &[f32],
+        attributes: Attributes,
+    ) -> Result<(), Error> {
+        let vector = self.preprocessing.apply(vector)?;
+        check_vector(&vector, self.index.dimension(), *self.index.distance())?;
+        self.add(item, &vector, attributes)
+    }
+
+    /// Add an item with a vector already preprocessed and checked, e.g. indexed before
+    fn add(&mut self, item: T, vector: &[f32], attributes: Attributes) -> Result<(), Error> {
         let entry = self.map.entry(item);
         match entry {
             Entry::Occupied(_) => Err(Error::KeyAlreadyPresent),
             Entry::Vacant(entry) => {
                 let id = self.index.add_item(vector);
+                if let Some(hnsw) = self.hnsw.as_mut() {
+                    hnsw.add_item(vector);
+                }
                 entry.insert(id);
                 self.inverse_map.insert(id, item);
+                self.attributes.push(attributes);
                 Ok(())
             }
         }
     }
 
     pub fn build(self, n_tree: Option<i32>) -> MappingIndex<T> {
+        let attributes = if self.attributes.iter().all(|a| a.is_empty()) {
+            Vec::new()
+        } else {
+            self.attributes
+        };
         MappingIndex {
             index_id: self.index_id,
             index: self.index.build(n_tree),
+            trees: None,
             map: self.map,
             inverse_map: self.inverse_map,
+            attributes,
+            vectors: None,
+            neighbour_count: 0,
+            neighbours: Vec::new(),
+            neighbour_distances: Vec::new(),
+            hnsw: self.hnsw.map(|hnsw| hnsw.build()),
+            ivfpq: None,
+            algorithm: Algorithm::Annoy,
+            updates: RwLock::new(Updates::new()),
+            preprocessing: self.preprocessing,
         }
     }
 }
@@ -69,26 +315,647 @@ impl<T> MappingIndex<T>
 where
     T: std::cmp::Eq + std::hash::Hash + Copy + std::str::FromStr,
 {
+    /// Structure searched by [algorithm], failing when it was not built or loaded
+    fn backend(&self, algorithm: Algorithm) -> Result<Backend<'_>, Error> {
+        let backend = match algorithm {
+            Algorithm::Annoy if self.has_annoy() => Some(Backend::Annoy),
+            Algorithm::Annoy => None,
+            Algorithm::Hnsw => self.hnsw.as_ref().map(Backend::Hnsw),
+            Algorithm::IvfPq => self.ivfpq.as_ref().map(Backend::IvfPq),
+        };
+        backend.ok_or_else(|| Error::UnsupportedAlgorithm(algorithm.to_string()))
+    }
+
+    fn default_backend(&self) -> Backend<'_> {
+        self.backend(self.algorithm).unwrap_or(Backend::Annoy)
+    }
+
+    /// Return the [n] closest items to [w] that were neither deleted nor upserted. More
+    /// candidates are fetched in proportion to the skipped items, then doubled with [search_k]
+    /// until [n] items are left or the whole index was fetched.
+    fn raw_nns(
+        &self,
+        backend: Backend,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>) {
+        if n <= 0 {
+            return (Vec::new(), Vec::new());
+        }
+        let updates = self.updates.read().unwrap();
+        if updates.skipped() == 0 {
+            return self.backend_nns(backend, w, n, search_k);
+        }
+        let count = self.len();
+        let alive = count.saturating_sub(updates.skipped());
+        let mut candidates = (n as usize * count).div_ceil(alive.max(1)).min(count) as i32;
+        let mut search_k = search_k;
+        loop {
+            let (r, v) = self.backend_nns(backend, w, candidates, search_k);
+            let (ids, distances): (Vec<i32>, Vec<f32>) = r
+                .into_iter()
+                .zip(v)
+                .filter(|(i, _)| !updates.skips(*i, &self.inverse_map[i]))
+                .take(n as usize)
+                .unzip();
+            if ids.len() >= n as usize || candidates as usize >= count {
+                return (ids, distances);
+            }
+            candidates = candidates.saturating_mul(2).min(count as i32);
+            // a negative search_k is the default of the algorithm
+            search_k = search_k.map(|k| if k > 0 { k.saturating_mul(2) } else { k });
+        }
+    }
+
+    fn backend_nns(
+        &self,
+        backend: Backend,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>) {
+        match backend {
+            // query vectors are checked against the dimension by [preprocess]
+            Backend::Annoy => match self.trees {
+                Some(ref trees) => trees.get_nns_by_vector(w, n, search_k).unwrap_or_default(),
+                None => self.index.get_nns_by_vector(w, n, search_k),
+            },
+            Backend::Hnsw(hnsw) => hnsw.get_nns_by_vector(w, n, search_k),
+            Backend::IvfPq(ivfpq) => ivfpq.get_nns_by_vector(w, n, search_k),
+        }
+    }
+
+    /// Order of the distances returned by searches, closest first
+    fn closest_first(&self, a: f32, b: f32) -> std::cmp::Ordering {
+        let ordering = a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal);
+        match self.distance() {
+            Distance::DotProduct => ordering.reverse(),
+            _ => ordering,
+        }
+    }
+
+    /// Upserted items matching [filter] with their distance to [w], unsorted
+    fn delta_nns(&self, updates: &Updates<T>, w: &[f32], filter: Option<&Filter>) -> Vec<(T, f32)> {
+        updates
+            .delta
+            .iter()
+            .filter(|(_, item)| match filter {
+                Some(filter) => filter.matches(&item.attributes),
+                None => true,
+            })
+            .map(|(id, item)| (*id, self.distance().between(w, &item.vector)))
+            .collect()
+    }
+
+    /// Map the annoy indexes [r] at distances [v] of [w] to items, merged with the closest
+    /// upserted items matching [filter], and keep [n] of them
+    fn merge_delta(
+        &self,
+        r: Vec<i32>,
+        v: Vec<f32>,
+        w: &[f32],
+        n: i32,
+        filter: Option<&Filter>,
+    ) -> (Vec<T>, Vec<f32>) {
+        let base = r.iter().map(|i| self.inverse_map[i]).zip(v);
+        let updates = self.updates.read().unwrap();
+        if updates.delta.is_empty() {
+            return base.unzip();
+        }
+        // items upserted since the base search are shadowed too
+        let mut items: Vec<(T, f32)> = base
+            .filter(|(id, _)| !updates.delta.contains_key(id))
+            .chain(self.delta_nns(&updates, w, filter))
+            .collect();
+        items.sort_by(|a, b| self.closest_first(a.1, b.1));
+        items.truncate(n.max(0) as usize);
+        items.into_iter().unzip()
+    }
+
+    /// Algorithm of the searches not choosing one, annoy unless changed by [set_algorithm]
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Search with [algorithm] by default, failing when its structure was not built or loaded
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) -> Result<(), Error> {
+        self.backend(algorithm)?;
+        self.algorithm = algorithm;
+        Ok(())
+    }
+
+    /// Return false when the items are not in the annoy index, e.g. loaded without index file
+    pub fn has_annoy(&self) -> bool {
+        !self.index.is_empty() || self.trees.is_some() || self.is_empty()
+    }
+
+    /// Return true when annoy searches read quantized vectors, see [load_quantized]
+    pub fn has_quantized(&self) -> bool {
+        self.trees.is_some()
+    }
+
+    pub fn has_hnsw(&self) -> bool {
+        self.hnsw.is_some()
+    }
+
+    pub fn has_ivfpq(&self) -> bool {
+        self.ivfpq.is_some()
+    }
+
     pub fn get_nns_by_vector(
         &self,
         w: &[f32],
         n: i32,
         search_k: Option<i32>,
     ) -> (Vec<T>, Vec<f32>) {
-        let (r, v) = self.index.get_nns_by_vector(w, n, search_k);
-        (r.iter().map(|i| self.inverse_map[i]).collect(), v)
+        let (r, v) = self.raw_nns(self.default_backend(), w, n, search_k);
+        self.merge_delta(r, v, w, n, None)
     }
 
-    pub fn get_item_vector(&self, item: T) -> Option<Vec<f32>> {
+    /// [get_nns_by_vector] with [algorithm]
+    pub fn get_nns_by_vector_using(
+        &self,
+        algorithm: Algorithm,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+    ) -> Result<(Vec<T>, Vec<f32>), Error> {
+        let (r, v) = self.raw_nns(self.backend(algorithm)?, w, n, search_k);
+        Ok(self.merge_delta(r, v, w, n, None))
+    }
+
+    /// Return the [n] closer items to [w] whose attributes match [filter].
+    /// The number of candidates fetched from annoy and [search_k] are doubled until
+    /// [n] items match, the whole index was fetched or [max_candidates] is reached.
+    pub fn get_nns_by_vector_filtered(
+        &self,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+        filter: &Filter,
+        max_candidates: i32,
+    ) -> (Vec<T>, Vec<f32>) {
+        self.filtered_nns(
+            self.default_backend(),
+            w,
+            n,
+            search_k,
+            filter,
+            max_candidates,
+        )
+    }
+
+    /// [get_nns_by_vector_filtered] with [algorithm]
+    pub fn get_nns_by_vector_filtered_using(
+        &self,
+        algorithm: Algorithm,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+        filter: &Filter,
+        max_candidates: i32,
+    ) -> Result<(Vec<T>, Vec<f32>), Error> {
+        let backend = self.backend(algorithm)?;
+        Ok(self.filtered_nns(backend, w, n, search_k, filter, max_candidates))
+    }
+
+    fn filtered_nns(
+        &self,
+        backend: Backend,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+        filter: &Filter,
+        max_candidates: i32,
+    ) -> (Vec<T>, Vec<f32>) {
+        if n <= 0 {
+            return (Vec::new(), Vec::new());
+        }
+        let mut candidates = n.min(max_candidates);
+        let mut search_k = search_k;
+        loop {
+            let (r, v) = self.raw_nns(backend, w, candidates, search_k);
+            let exhausted = candidates as usize >= self.len() || candidates >= max_candidates;
+            let (ids, distances): (Vec<i32>, Vec<f32>) = r
+                .into_iter()
+                .zip(v)
+                .filter(|(i, _)| self.item_matches(*i, filter))
+                .take(n as usize)
+                .unzip();
+            if ids.len() >= n as usize || exhausted {
+                return self.merge_delta(ids, distances, w, n, Some(filter));
+            }
+            candidates = candidates.saturating_mul(2).min(max_candidates);
+            // a negative search_k is the default of the algorithm
+            search_k = search_k.map(|k| if k > 0 { k.saturating_mul(2) } else { k });
+        }
+    }
+
+    /// Return the items within [max_distance] of [w], at most [max_results],
+    /// and whether more items were within the distance
+    pub fn get_nns_within(
+        &self,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<T>, Vec<f32>, bool) {
+        self.nns_within(
+            self.default_backend(),
+            w,
+            max_distance,
+            max_results,
+            search_k,
+        )
+    }
+
+    /// [get_nns_within] with [algorithm]
+    pub fn get_nns_within_using(
+        &self,
+        algorithm: Algorithm,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        search_k: Option<i32>,
+    ) -> Result<(Vec<T>, Vec<f32>, bool), Error> {
+        let backend = self.backend(algorithm)?;
+        Ok(self.nns_within(backend, w, max_distance, max_results, search_k))
+    }
+
+    fn nns_within(
+        &self,
+        backend: Backend,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<T>, Vec<f32>, bool) {
+        let updates = self.updates.read().unwrap();
+        // skipped items within the distance are fetched on top of the results
+        let limit = max_results.saturating_add(updates.skipped() as i32);
+        let (r, v, truncated) = match backend {
+            Backend::Annoy => match self.trees {
+                Some(ref trees) => trees
+                    .get_nns_within(w, max_distance, limit, search_k)
+                    .unwrap_or_else(|_| (Vec::new(), Vec::new(), false)),
+                None => self.index.get_nns_within(w, max_distance, limit, search_k),
+            },
+            Backend::Hnsw(hnsw) => hnsw.get_nns_within(w, max_distance, limit, search_k),
+            Backend::IvfPq(ivfpq) => ivfpq.get_nns_within(w, max_distance, limit, search_k),
+        };
+        let within = |d: f32| self.closest_first(d, max_distance) != std::cmp::Ordering::Greater;
+        let mut items: Vec<(T, f32)> = r
+            .iter()
+            .filter(|i| !updates.skips(**i, &self.inverse_map[i]))
+            .map(|i| self.inverse_map[i])
+            .zip(v)
+            .chain(
+                self.delta_nns(&updates, w, None)
+                    .into_iter()
+                    .filter(|(_, d)| within(*d)),
+            )
+            .collect();
+        items.sort_by(|a, b| self.closest_first(a.1, b.1));
+        let truncated = truncated || items.len() > max_results.max(0) as usize;
+        items.truncate(max_results.max(0) as usize);
+        let (ids, distances) = items.into_iter().unzip();
+        (ids, distances, truncated)
+    }
+
+    /// Return true if the attributes of [item] match [filter]
+    pub fn matches(&self, item: T, filter: &Filter) -> bool {
+        if let Some(upserted) = self.updates.read().unwrap().delta.get(&item) {
+            return filter.matches(&upserted.attributes);
+        }
+        match self.map.get(&item) {
+            Some(i) => self.item_matches(*i, filter),
+            None => false,
+        }
+    }
+
+    fn item_matches(&self, item: i32, filter: &Filter) -> bool {
+        match self.attributes.get(item as usize) {
+            Some(attributes) => filter.matches(attributes),
+            None => filter.matches(&Attributes::new()),
+        }
+    }
+
+    /// Return the attributes of [item] when the index was built, ignoring upserts
+    pub fn get_item_attributes(&self, item: T) -> Option<&Attributes> {
         self.map
             .get(&item)
-            .and_then(|key| self.index.get_item(*key))
+            .and_then(|key| self.attributes.get(*key as usize))
+    }
+
+    pub fn has_attributes(&self) -> bool {
+        !self.attributes.is_empty()
+    }
+
+    /// Return the upserted vector of [item], else the one stored in the annoy index, dequantized
+    /// when it was loaded with quantized vectors, else in the HNSW graph, else the original
+    /// vector, else the one decoded from the IVF-PQ codes
+    pub fn get_item_vector(&self, item: T) -> Option<Vec<f32>> {
+        if let Some(upserted) = self.updates.read().unwrap().delta.get(&item) {
+            return Some(upserted.vector.clone());
+        }
+        self.indexed_vector(*self.map.get(&item)?)
+    }
+
+    /// Vector of [key] as searched, dequantized when the annoy trees were loaded with quantized
+    /// vectors, else the f32 one of the annoy index when it was loaded, else dequantized from
+    /// the HNSW graph or the IVF-PQ codes
+    fn indexed_vector(&self, key: i32) -> Option<Vec<f32>> {
+        self.trees
+            .as_ref()
+            .and_then(|trees| trees.get_item(key))
+            .or_else(|| self.index.get_item(key))
+            .or_else(|| self.hnsw.as_ref().and_then(|hnsw| hnsw.get_item(key)))
+            .or_else(|| self.original_vector(key))
+            .or_else(|| self.ivfpq.as_ref().and_then(|ivfpq| ivfpq.get_item(key)))
+    }
+
+    /// Return the original vector of [item] when a vectors file was loaded and it was not
+    /// upserted, the vector stored in the index otherwise
+    pub fn get_original_vector(&self, item: T) -> Option<Vec<f32>> {
+        let upserted = self.updates.read().unwrap().delta.contains_key(&item);
+        match self.map.get(&item) {
+            Some(key) if !upserted => self
+                .original_vector(*key)
+                .or_else(|| self.get_item_vector(item)),
+            _ => self.get_item_vector(item),
+        }
+    }
+
+    fn original_vector(&self, key: i32) -> Option<Vec<f32>> {
+        let vectors = self.vectors.as_ref()?;
+        let dimension = self.dimension() as usize;
+        let start = key as usize * dimension * 4;
+        Some(
+            vectors[start..start + dimension * 4]
+                .chunks(4)
+                .map(|c| {
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(c);
+                    f32::from_bits(u32::from_le_bytes(bytes))
+                })
+                .collect(),
+        )
+    }
+
+    /// Return the [n] precomputed closest items of [item], including itself, or None when less
+    /// than [n] neighbours were precomputed or left after deletions, or items were upserted
+    pub fn get_neighbours(&self, item: T, n: i32) -> Option<(Vec<T>, Vec<f32>)> {
+        if n <= 0 || n as usize > self.neighbour_count {
+            return None;
+        }
+        let start = *self.map.get(&item)? as usize * self.neighbour_count;
+        let end = start + self.neighbour_count;
+        let updates = self.updates.read().unwrap();
+        if !updates.delta.is_empty() {
+            return None;
+        }
+        let mut skipped = false;
+        let (ids, distances): (Vec<T>, Vec<f32>) = self.neighbours[start..end]
+            .iter()
+            .zip(&self.neighbour_distances[start..end])
+            .filter(|(i, _)| **i >= 0)
+            .filter(|(i, _)| {
+                let deleted = updates.deleted.contains_key(i);
+                skipped |= deleted;
+                !deleted
+            })
+            .take(n as usize)
+            .map(|(i, d)| (self.inverse_map[i], *d))
+            .unzip();
+        if skipped && ids.len() < n as usize {
+            return None;
+        }
+        Some((ids, distances))
+    }
+
+    /// Mark [items] as deleted so that searches skip them, appending the items of the annoy
+    /// index to the tombstones file when one was loaded. Return the number of items newly
+    /// deleted, unknown items being ignored.
+    pub fn delete(&self, items: &[T]) -> Result<usize, Error>
+    where
+        T: Display,
+    {
+        let mut updates = self.updates.write().unwrap();
+        let mut deleted = HashSet::new();
+        let mut removed = HashSet::new();
+        for item in items {
+            if updates.delta.contains_key(item) {
+                removed.insert(*item);
+            }
+            if let Some(key) = self.map.get(item) {
+                // upserted items are live even when their original was deleted, the deletion
+                // gets a new version so that [replay] applies it
+                let live = !updates.deleted.contains_key(key) || updates.delta.contains_key(item);
+                if live {
+                    deleted.insert(*key);
+                }
+            }
+        }
+        if let Some(ref path) = updates.tombstones_path {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut w = BufWriter::new(file);
+            for key in &deleted {
+                writeln!(w, "{}", self.inverse_map[key])?;
+            }
+            w.flush()?;
+        }
+        let count = deleted.len()
+            + removed
+                .iter()
+                .filter(|item| !self.map.contains_key(item))
+                .count();
+        updates.version += 1;
+        let version = updates.version;
+        for key in deleted {
+            updates.deleted.insert(key, version);
+        }
+        for item in removed {
+            updates.delta.remove(&item);
+            if !self.map.contains_key(&item) {
+                updates.removed.insert(item, version);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Add [item] with [vector], or replace its vector and attributes when it already exists.
+    /// Upserted items are searched exactly along the index until it is compacted.
+    /// [vector] is indexed as is, see [preprocess].
+    pub fn upsert(&self, item: T, vector: &[f32], attributes: Attributes) -> Result<(), Error> {
+        check_vector(vector, self.dimension(), *self.distance())?;
+        let mut updates = self.updates.write().unwrap();
+        updates.version += 1;
+        let version = updates.version;
+        updates.removed.remove(&item);
+        updates.delta.insert(
+            item,
+            DeltaItem {
+                vector: vector.to_vec(),
+                attributes,
+                version,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn is_deleted(&self, item: T) -> bool {
+        let updates = self.updates.read().unwrap();
+        if updates.delta.contains_key(&item) {
+            return false;
+        }
+        match self.map.get(&item) {
+            Some(key) => updates.deleted.contains_key(key),
+            None => updates.removed.contains_key(&item),
+        }
+    }
+
+    /// Number of items of the annoy index deleted
+    pub fn deleted_count(&self) -> usize {
+        self.updates.read().unwrap().deleted.len()
+    }
+
+    /// Number of items upserted, searched exactly until the index is compacted
+    pub fn upserted_count(&self) -> usize {
+        self.updates.read().unwrap().delta.len()
+    }
+
+    /// Number of upserts and deletions since the index was built or loaded
+    pub fn version(&self) -> u64 {
+        self.updates.read().unwrap().version
+    }
+
+    /// Fraction of the items deleted since the index was built, a rebuild reclaims them
+    pub fn tombstone_ratio(&self) -> f32 {
+        if self.is_empty() {
+            0.0
+        } else {
+            self.deleted_count() as f32 / self.len() as f32
+        }
+    }
+
+    /// Build a fresh index with [n_tree] trees of the items not deleted, with their upserted
+    /// vectors and attributes. Return it with the version of the updates it includes, the
+    /// later ones being applied by [replay]. Original vectors, neighbours and the structures
+    /// of the other algorithms are not carried over.
+    pub fn compact(&self, n_tree: Option<i32>) -> Result<(MappingIndex<T>, u64), Error> {
+        // copied so that updates are not blocked during the build
+        let (version, skipped, mut delta) = {
+            let updates = self.updates.read().unwrap();
+            let skipped: HashSet<i32> = (0..self.len() as i32)
+                .filter(|i| updates.skips(*i, &self.inverse_map[i]))
+                .collect();
+            let delta: Vec<(T, DeltaItem)> = updates
+                .delta
+                .iter()
+                .map(|(item, upserted)| (*item, upserted.clone()))
+                .collect();
+            (updates.version, skipped, delta)
+        };
+        // the indexed vectors are already preprocessed and checked
+        let mut builder =
+            MappingIndexBuilder::new(&self.index_id, self.dimension(), *self.distance());
+        if let Some(seed) = self.seed() {
+            builder = builder.with_seed(seed);
+        }
+        for key in (0..self.len() as i32).filter(|i| !skipped.contains(i)) {
+            let vector = self.indexed_vector(key).ok_or(Error::BuildError)?;
+            let attributes = self
+                .attributes
+                .get(key as usize)
+                .cloned()
+                .unwrap_or_default();
+            builder.add(self.inverse_map[&key], &vector, attributes)?;
+        }
+        delta.sort_by_key(|(_, upserted)| upserted.version);
+        for (item, upserted) in delta {
+            builder.add(item, &upserted.vector, upserted.attributes)?;
+        }
+        let mut index = builder.build(n_tree);
+        index.preprocessing = self.preprocessing.clone();
+        Ok((index, version))
+    }
+
+    /// Apply to [index] the upserts and deletions made after [version], e.g. while it was
+    /// built by [compact]
+    pub fn replay(&self, version: u64, index: &MappingIndex<T>) -> Result<(), Error>
+    where
+        T: Display,
+    {
+        let updates = self.updates.read().unwrap();
+        let mut changes: Vec<(u64, T, Option<&DeltaItem>)> = updates
+            .delta
+            .iter()
+            .filter(|(_, upserted)| upserted.version > version)
+            .map(|(item, upserted)| (upserted.version, *item, Some(upserted)))
+            .collect();
+        changes.extend(
+            updates
+                .deleted
+                .iter()
+                .filter(|(_, v)| **v > version)
+                .map(|(key, v)| (*v, self.inverse_map[key], None)),
+        );
+        changes.extend(
+            updates
+                .removed
+                .iter()
+                .filter(|(_, v)| **v > version)
+                .map(|(item, v)| (*v, *item, None)),
+        );
+        changes.sort_by_key(|(v, _, _)| *v);
+        for (_, item, upserted) in changes {
+            match upserted {
+                Some(upserted) => {
+                    index.upsert(item, &upserted.vector, upserted.attributes.clone())?
+                }
+                None => {
+                    index.delete(&[item])?;
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn dimension(&self) -> i32 {
         self.index.dimension()
     }
 
+    /// Transformation of the query vectors, the identity unless set
+    pub fn preprocessing(&self) -> &Pipeline {
+        &self.preprocessing
+    }
+
+    /// Apply [preprocessing] to the query vectors, e.g. the one the items were indexed with.
+    /// It must output vectors of the dimension of the index.
+    pub fn set_preprocessing(&mut self, preprocessing: Pipeline) -> Result<(), Error> {
+        check_preprocessing(&preprocessing, self.dimension())?;
+        self.preprocessing = preprocessing;
+        Ok(())
+    }
+
+    /// Transform the query [vector] into the space of the indexed vectors
+    pub fn preprocess(&self, vector: &[f32]) -> Result<Vec<f32>, Error> {
+        let v = self.preprocessing.apply(vector)?;
+        if v.len() != self.dimension() as usize {
+            return Err(Error::DimensionError(v.len(), self.dimension() as usize));
+        }
+        Ok(v)
+    }
+
+    pub fn distance(&self) -> &Distance {
+        self.index.distance()
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.index.seed()
+    }
+
     pub fn len(&self) -> usize {
         self.map.len()
     }
@@ -97,6 +964,426 @@ where
         self.map.len() == 0
     }
 
+    /// Save the annoy index to [index_file_path]. Indexes built on disk are already saved.
+    pub fn save_index<P: AsRef<Path>>(&self, index_file_path: P) -> Result<(), Error> {
+        self.index.save2(index_file_path, false)
+    }
+
+    /// Build a HNSW graph of the items stored in the annoy index, see `MappingIndexBuilder::with_hnsw`
+    pub fn build_hnsw(&mut self, m: usize, ef_construction: usize, quantization: Quantization) {
+        let mut builder = HnswBuilder::new(self.dimension(), *self.distance())
+            .with_m(m)
+            .with_ef_construction(ef_construction)
+            .with_quantization(quantization);
+        if let Some(seed) = self.seed() {
+            builder = builder.with_seed(seed);
+        }
+        for i in 0..self.len() as i32 {
+            let vector = self.index.get_item(i).unwrap_or_default();
+            builder.add_item(&vector);
+        }
+        self.hnsw = Some(builder.build());
+    }
+
+    /// Up to [size] distinct random annoy indexes, drawn with the seed of the index
+    fn sample(&self, size: usize) -> Vec<usize> {
+        let count = self.len();
+        let mut order: Vec<usize> = (0..count).collect();
+        let mut random = Kiss64Random::new(self.seed().unwrap_or(Kiss64Random::DEFAULT_SEED));
+        let size = size.min(count);
+        for i in 0..size {
+            let j = i + random.index(count - i);
+            order.swap(i, j);
+        }
+        order.truncate(size);
+        order
+    }
+
+    /// Train [builder] on up to [sample_size] random items and encode all items,
+    /// using their original vectors when a vectors file was loaded
+    pub fn build_ivfpq(&mut self, builder: IvfPqBuilder, sample_size: usize) -> Result<(), Error> {
+        let count = self.len();
+        let vector = |i: usize| {
+            self.original_vector(i as i32)
+                .or_else(|| self.index.get_item(i as i32))
+                .unwrap_or_default()
+        };
+        let sample: Vec<f32> = self
+            .sample(sample_size)
+            .iter()
+            .flat_map(|i| vector(*i))
+            .collect();
+        let mut encoder = builder.train(&sample)?;
+        for i in 0..count {
+            encoder.add_item(&vector(i));
+        }
+        self.ivfpq = Some(encoder.build());
+        Ok(())
+    }
+
+    /// Fraction of the exact [n] closest items found by [algorithm] with [search_k],
+    /// averaged over [queries] random items. The exact items are found by brute force
+    /// over the original vectors, else the f32 vectors of the annoy index.
+    pub fn recall(
+        &self,
+        algorithm: Algorithm,
+        n: usize,
+        queries: usize,
+        search_k: Option<i32>,
+    ) -> Result<f32, Error> {
+        let backend = self.backend(algorithm)?;
+        let vector = |i: usize| {
+            self.original_vector(i as i32)
+                .or_else(|| self.index.get_item(i as i32))
+        };
+        let vectors = (0..self.len())
+            .map(vector)
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| Error::UnsupportedAlgorithm("exact search".to_owned()))?;
+        let distance = *self.distance();
+        let sample = self.sample(queries);
+        // collected as searches lock the updates too
+        let skipped: HashSet<i32> = {
+            let updates = self.updates.read().unwrap();
+            (0..self.len() as i32)
+                .filter(|i| updates.skips(*i, &self.inverse_map[i]))
+                .collect()
+        };
+        let alive = self.len() - skipped.len();
+        let mut found = 0;
+        for q in &sample {
+            let query = &vectors[*q];
+            let mut exact: Vec<(f32, usize)> = vectors
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !skipped.contains(&(*i as i32)))
+                .map(|(i, v)| match distance {
+                    Distance::DotProduct => (-distance.between(query, v), i),
+                    _ => (distance.between(query, v), i),
+                })
+                .collect();
+            exact.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            let (ids, _) = self.raw_nns(backend, query, n as i32, search_k);
+            found += exact
+                .iter()
+                .take(n)
+                .filter(|(_, i)| ids.contains(&(*i as i32)))
+                .count();
+        }
+        let expected = sample.len() * n.min(alive);
+        Ok(if expected > 0 {
+            found as f32 / expected as f32
+        } else {
+            1.0
+        })
+    }
+
+    /// Save the IVF-PQ codes to [ivfpq_file_path], failing when they were not built
+    pub fn save_ivfpq<P: AsRef<Path>>(&self, ivfpq_file_path: P) -> Result<(), Error> {
+        match self.ivfpq {
+            Some(ref ivfpq) => ivfpq.save(ivfpq_file_path),
+            None => Err(Error::UnsupportedAlgorithm(Algorithm::IvfPq.to_string())),
+        }
+    }
+
+    /// Map the IVF-PQ codes saved by [save_ivfpq], built from the items in mapping order
+    pub fn load_ivfpq<P: AsRef<Path>>(&mut self, ivfpq_file_path: P) -> Result<(), Error> {
+        let ivfpq = IvfPq::load(ivfpq_file_path, self.dimension(), *self.distance())?;
+        if ivfpq.len() as usize != self.len() {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq codes of {} items for {} items",
+                ivfpq.len(),
+                self.len()
+            )));
+        }
+        self.ivfpq = Some(ivfpq);
+        Ok(())
+    }
+
+    /// Encode the f32 vectors of the annoy index with [quantization] into [quantized_file_path],
+    /// failing when the annoy index was not loaded
+    pub fn save_quantized<P: AsRef<Path>>(
+        &self,
+        quantized_file_path: P,
+        quantization: Quantization,
+    ) -> Result<(), Error> {
+        let mut vectors = Vec::with_capacity(self.len() * self.dimension() as usize);
+        for i in 0..self.len() as i32 {
+            let vector = self
+                .index
+                .get_item(i)
+                .ok_or_else(|| Error::UnsupportedAlgorithm(Algorithm::Annoy.to_string()))?;
+            vectors.extend(vector);
+        }
+        QuantizedVectors::new(&vectors, self.dimension(), quantization).save(quantized_file_path)
+    }
+
+    /// Map the trees of the annoy index file [index_file_path] and the vectors saved by
+    /// [save_quantized], which annoy searches then read in place of the item nodes of the file,
+    /// computing distances on the quantized components. Item vectors are dequantized.
+    /// Dot product and hamming indexes are not supported.
+    pub fn load_quantized<P: AsRef<Path>>(
+        &mut self,
+        index_file_path: P,
+        quantized_file_path: P,
+    ) -> Result<(), Error> {
+        let items = QuantizedVectors::load(quantized_file_path, self.dimension())?;
+        let trees = AnnoyReader::load(index_file_path, self.dimension(), *self.distance())?
+            .with_items(items)?;
+        if trees.len() as usize != self.len() {
+            return Err(Error::InvalidIndex(format!(
+                "annoy index of {} items for {} items",
+                trees.len(),
+                self.len()
+            )));
+        }
+        self.trees = Some(trees);
+        Ok(())
+    }
+
+    /// Map the f32 annoy index file of an index read by [load_mapping], e.g. as the reference
+    /// of [recall] when annoy searches read quantized vectors, see [load_quantized]
+    pub fn load_index<P: AsRef<Path>>(&self, index_file_path: P) -> Result<(), Error> {
+        self.index.load2(index_file_path, false)
+    }
+
+    /// Save the HNSW graph to [hnsw_file_path], failing when it was not built
+    pub fn save_hnsw<P: AsRef<Path>>(&self, hnsw_file_path: P) -> Result<(), Error> {
+        match self.hnsw {
+            Some(ref hnsw) => hnsw.save(hnsw_file_path),
+            None => Err(Error::UnsupportedAlgorithm(Algorithm::Hnsw.to_string())),
+        }
+    }
+
+    /// Map the HNSW graph saved by [save_hnsw], built from the items in mapping order
+    pub fn load_hnsw<P: AsRef<Path>>(&mut self, hnsw_file_path: P) -> Result<(), Error> {
+        let hnsw = Hnsw::load(hnsw_file_path, self.dimension(), *self.distance())?;
+        if hnsw.len() as usize != self.len() {
+            return Err(Error::InvalidIndex(format!(
+                "hnsw graph of {} items for {} items",
+                hnsw.len(),
+                self.len()
+            )));
+        }
+        self.hnsw = Some(hnsw);
+        Ok(())
+    }
+
+    /// Write the mapping file, one item per line in index order, as read by [load]
+    pub fn save_mapping<P: AsRef<Path>>(&self, mapping_file_path: P) -> Result<(), Error>
+    where
+        T: Display,
+    {
+        let mut w = BufWriter::new(File::create(mapping_file_path)?);
+        for i in 0..self.inverse_map.len() as i32 {
+            writeln!(w, "{}", self.inverse_map[&i])?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write the attributes file, one line of tab separated `key=value` per item in index order
+    pub fn save_attributes<P: AsRef<Path>>(&self, attributes_file_path: P) -> Result<(), Error> {
+        let mut w = BufWriter::new(File::create(attributes_file_path)?);
+        for attributes in &self.attributes {
+            writeln!(w, "{}", filter::format_attributes(attributes))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write the deleted items to [tombstones_file_path], one item per line
+    pub fn save_tombstones<P: AsRef<Path>>(&self, tombstones_file_path: P) -> Result<(), Error>
+    where
+        T: Display,
+    {
+        let updates = self.updates.read().unwrap();
+        let mut keys: Vec<&i32> = updates.deleted.keys().collect();
+        keys.sort();
+        let mut w = BufWriter::new(File::create(tombstones_file_path)?);
+        for key in keys {
+            writeln!(w, "{}", self.inverse_map[key])?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read the items deleted by [delete] from [tombstones_file_path] when it exists,
+    /// later deletions being appended to it. Unknown items are ignored.
+    pub fn load_tombstones<P: AsRef<Path>>(
+        &mut self,
+        tombstones_file_path: P,
+    ) -> Result<(), Error> {
+        let path = tombstones_file_path.as_ref();
+        let updates = self.updates.get_mut().unwrap();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                let item = line.parse::<T>().map_err(|_e| Error::ParsingError(line))?;
+                if let Some(key) = self.map.get(&item) {
+                    updates.deleted.insert(*key, 0);
+                }
+            }
+        }
+        updates.tombstones_path = Some(path.to_owned());
+        Ok(())
+    }
+
+    /// Read the attributes of the items, written in the same order as the mapping file
+    pub fn load_attributes<P: AsRef<Path>>(
+        &mut self,
+        attributes_file_path: P,
+    ) -> Result<(), Error> {
+        let attributes = filter::read_attributes(attributes_file_path)?;
+        if attributes.len() != self.len() {
+            return Err(Error::InvalidIndex(format!(
+                "{} attributes lines for {} items",
+                attributes.len(),
+                self.len()
+            )));
+        }
+        self.attributes = attributes;
+        Ok(())
+    }
+
+    /// Map the original vectors of the items, e.g. before normalization, used for exact scoring.
+    /// The file holds the components as little endian f32, one vector per item in mapping order.
+    pub fn load_vectors<P: AsRef<Path>>(&mut self, vectors_file_path: P) -> Result<(), Error> {
+        let file = File::open(vectors_file_path)?;
+        let size = file.metadata()?.len() as usize;
+        let expected = self.len() * self.dimension() as usize * 4;
+        if size != expected {
+            return Err(Error::InvalidIndex(format!(
+                "vectors file of {} bytes, expected {}",
+                size, expected
+            )));
+        }
+        if expected > 0 {
+            self.vectors = Some(unsafe { Mmap::map(&file)? });
+        }
+        Ok(())
+    }
+
+    /// Compute the [k] closest items of every item of [index] with its default algorithm
+    /// on [threads] threads and write them to [neighbours_file_path]. Deleted and upserted
+    /// items are skipped like in searches. Fail when [k] is not positive.
+    ///
+    /// The file holds k as a little endian i32, then for each item in mapping order
+    /// k i32 annoy indexes padded with -1 followed by their k f32 distances.
+    pub fn save_neighbours<P: AsRef<Path>>(
+        index: &Arc<MappingIndex<T>>,
+        neighbours_file_path: P,
+        k: i32,
+        search_k: Option<i32>,
+        threads: usize,
+    ) -> Result<(), Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        if k <= 0 {
+            return Err(Error::InvalidNeighbourCount(k));
+        }
+        let item_count = index.len();
+        let next = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+        let handles: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                let index = index.clone();
+                let next = next.clone();
+                let sender = sender.clone();
+                thread::spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= item_count {
+                        break;
+                    }
+                    let vector = index.get_item_vector(index.inverse_map[&(i as i32)]);
+                    let neighbours = index.raw_nns(
+                        index.default_backend(),
+                        &vector.unwrap_or_default(),
+                        k,
+                        search_k,
+                    );
+                    if sender.send((i, neighbours)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        drop(sender);
+
+        let k = k as usize;
+        let mut neighbours = vec![-1; item_count * k];
+        let mut distances = vec![0.0; item_count * k];
+        for (i, (ids, ds)) in receiver {
+            let start = i * k;
+            neighbours[start..start + ids.len()].copy_from_slice(&ids);
+            distances[start..start + ds.len()].copy_from_slice(&ds);
+        }
+        for handle in handles {
+            handle.join().map_err(|_| Error::BuildError)?;
+        }
+
+        let mut w = BufWriter::new(File::create(neighbours_file_path)?);
+        w.write_all(&(k as i32).to_le_bytes())?;
+        for i in 0..item_count {
+            for id in &neighbours[i * k..(i + 1) * k] {
+                w.write_all(&id.to_le_bytes())?;
+            }
+            for d in &distances[i * k..(i + 1) * k] {
+                w.write_all(&d.to_bits().to_le_bytes())?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read the neighbours written by [save_neighbours]
+    pub fn load_neighbours<P: AsRef<Path>>(
+        &mut self,
+        neighbours_file_path: P,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        File::open(neighbours_file_path)?.read_to_end(&mut buf)?;
+        let words: Vec<u32> = buf
+            .chunks(4)
+            .map(|c| {
+                let mut bytes = [0u8; 4];
+                bytes[..c.len()].copy_from_slice(c);
+                u32::from_le_bytes(bytes)
+            })
+            .collect();
+        let k = words.first().cloned().unwrap_or(0) as usize;
+        let expected = (self.len() * 2).checked_mul(k).map(|n| n + 1);
+        if buf.len() % 4 != 0 || k == 0 || expected != Some(words.len()) {
+            return Err(Error::InvalidIndex(format!(
+                "neighbours file of {} bytes for {} items",
+                buf.len(),
+                self.len()
+            )));
+        }
+        let mut neighbours = Vec::with_capacity(self.len() * k);
+        let mut neighbour_distances = Vec::with_capacity(self.len() * k);
+        for item in words[1..].chunks(2 * k) {
+            neighbours.extend(item[..k].iter().map(|w| *w as i32));
+            neighbour_distances.extend(item[k..].iter().map(|w| f32::from_bits(*w)));
+        }
+        // ids are padded with -1
+        if let Some(id) = neighbours
+            .iter()
+            .find(|i| **i < -1 || **i as usize >= self.len())
+        {
+            return Err(Error::InvalidIndex(format!(
+                "neighbour {} for {} items",
+                id,
+                self.len()
+            )));
+        }
+        self.neighbours = neighbours;
+        self.neighbour_distances = neighbour_distances;
+        self.neighbour_count = k;
+        Ok(())
+    }
+
     pub fn load<P: AsRef<Path>>(
         index_id: &str,
         index_file_path: P,
@@ -104,9 +1391,20 @@ where
         dimension: i32,
         distance: Distance,
         load_into_ram: bool,
+    ) -> Result<MappingIndex<T>, Error> {
+        let mapping = MappingIndex::load_mapping(index_id, mapping_file_path, dimension, distance)?;
+        mapping.index.load2(index_file_path, load_into_ram)?;
+        Ok(mapping)
+    }
+
+    /// Read the mapping file without annoy index, the items are searched with other algorithms
+    pub fn load_mapping<P: AsRef<Path>>(
+        index_id: &str,
+        mapping_file_path: P,
+        dimension: i32,
+        distance: Distance,
     ) -> Result<MappingIndex<T>, Error> {
         let index = AnnoyIndexBuilder::new(dimension, distance).build(None);
-        index.load2(index_file_path, load_into_ram)?;
 
         let mapping_file: File = File::open(mapping_file_path)?;
         let buf = BufReader::new(mapping_file);
@@ -124,8 +1422,19 @@ where
         Ok(MappingIndex {
             index_id: index_id.to_owned(),
             index,
+            trees: None,
             map: index_map,
             inverse_map: reverse_index_map,
+            attributes: Vec::new(),
+            vectors: None,
+            neighbour_count: 0,
+            neighbours: Vec::new(),
+            neighbour_distances: Vec::new(),
+            hnsw: None,
+            ivfpq: None,
+            algorithm: Algorithm::Annoy,
+            updates: RwLock::new(Updates::new()),
+            preprocessing: Pipeline::new(),
         })
     }
 }
@@ -140,3 +1449,452 @@ where
 }
 
 impl<T> Eq for MappingIndex<T> where T: std::cmp::Eq + std::hash::Hash + Copy + std::str::FromStr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::AttributeValue;
+
+    #[test]
+    fn filtered_search_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..1000 {
+            let mut attributes = Attributes::new();
+            attributes.insert("even".to_owned(), AttributeValue::Boolean(i % 2 == 0));
+            attributes.insert("rare".to_owned(), AttributeValue::Boolean(i % 250 == 0));
+            builder
+                .put_with_attributes(i, &[i as f32, 0.0], attributes)
+                .unwrap();
+        }
+        let index = builder.build(Some(10));
+
+        let even = Filter::Equals("even".to_owned(), AttributeValue::Boolean(true));
+        let (ids, distances) = index.get_nns_by_vector_filtered(&[11.0, 0.0], 4, None, &even, 1000);
+        assert_eq!(ids, vec![10, 12, 8, 14]);
+        assert_eq!(distances, vec![1.0, 1.0, 3.0, 3.0]);
+
+        let rare = Filter::Equals("rare".to_owned(), AttributeValue::Boolean(true));
+        let (ids, _) = index.get_nns_by_vector_filtered(&[0.0, 0.0], 4, None, &rare, 1000);
+        assert_eq!(ids, vec![0, 250, 500, 750]);
+
+        let (ids, _) = index.get_nns_by_vector_filtered(&[0.0, 0.0], 4, None, &rare, 100);
+        assert_eq!(ids, vec![0]);
+
+        let (ids, _) = index.get_nns_by_vector_filtered(&[0.0, 0.0], -1, Some(-1), &rare, 1000);
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn default_algorithm_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        assert!(index.has_annoy());
+        assert_eq!(index.algorithm(), Algorithm::Annoy);
+        let (ids, distances) = index.get_nns_by_vector(&[50.2, 0.0], 2, None);
+        assert_eq!(ids, vec![500, 510]);
+        let using = index
+            .get_nns_by_vector_using(Algorithm::Annoy, &[50.2, 0.0], 2, None)
+            .unwrap();
+        assert_eq!(using, (ids, distances));
+        let (ids, _, _) = index
+            .get_nns_within_using(Algorithm::Annoy, &[50.2, 0.0], 1.0, 10, None)
+            .unwrap();
+        assert_eq!(ids, vec![500, 510]);
+    }
+
+    #[test]
+    fn neighbours_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = Arc::new(builder.build(Some(10)));
+        let path = std::env::temp_dir().join("annoy_neighbours_test");
+        assert!(MappingIndex::save_neighbours(&index, &path, 0, None, 4).is_err());
+        MappingIndex::save_neighbours(&index, &path, 5, None, 4).unwrap();
+
+        let mut index = Arc::try_unwrap(index).ok().unwrap();
+        assert_eq!(index.get_neighbours(0, 1), None);
+        index.load_neighbours(&path).unwrap();
+
+        for item in 0..100 {
+            let (ids, distances) = index.get_neighbours(item * 10, 5).unwrap();
+            let (expected_ids, expected_distances) =
+                index.get_nns_by_vector(&[item as f32, 0.0], 5, None);
+            assert_eq!(ids[0], item * 10);
+            assert_eq!(ids.len(), expected_ids.len());
+            assert_eq!(distances, expected_distances);
+        }
+        assert_eq!(index.get_neighbours(0, 6), None);
+        assert_eq!(index.get_neighbours(0, 0), None);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let load = |corrupt: &[u8]| {
+            let path = std::env::temp_dir().join("annoy_neighbours_corrupt_test");
+            std::fs::write(&path, corrupt).unwrap();
+            let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+            builder.put(0, &[0.0, 0.0]).unwrap();
+            let mut index = builder.build(Some(1));
+            let loaded = index.load_neighbours(&path);
+            std::fs::remove_file(path).unwrap();
+            loaded
+        };
+        // k of 0, then a neighbour out of the index
+        assert!(load(&0i32.to_le_bytes()).is_err());
+        assert!(load(&bytes[..4 + 2 * 4 * 5]).is_err());
+        let mut valid = 1i32.to_le_bytes().to_vec();
+        valid.extend(&0i32.to_le_bytes());
+        valid.extend(&0f32.to_bits().to_le_bytes());
+        assert!(load(&valid).is_ok());
+        valid[4..8].copy_from_slice(&1i32.to_le_bytes());
+        assert!(load(&valid).is_err());
+
+        // items deleted before the tables are computed are not in them
+        index.delete(&[10]).unwrap();
+        let index = Arc::new(index);
+        MappingIndex::save_neighbours(&index, &path, 2, None, 4).unwrap();
+        let mut index = Arc::try_unwrap(index).ok().unwrap();
+        index.load_neighbours(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            index.get_neighbours(0, 2),
+            Some((vec![0, 20], vec![0.0, 2.0]))
+        );
+    }
+
+    #[test]
+    fn tombstones_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = Arc::new(builder.build(Some(10)));
+        let neighbours_path = std::env::temp_dir().join("annoy_tombstones_neighbours_test");
+        MappingIndex::save_neighbours(&index, &neighbours_path, 3, None, 4).unwrap();
+        let mut index = Arc::try_unwrap(index).ok().unwrap();
+        index.load_neighbours(&neighbours_path).unwrap();
+        std::fs::remove_file(neighbours_path).unwrap();
+        let path = std::env::temp_dir().join("annoy_tombstones_test");
+        let _ = std::fs::remove_file(&path);
+        index.load_tombstones(&path).unwrap();
+
+        assert_eq!(index.delete(&[500, 510, 510, 12345]).unwrap(), 2);
+        assert_eq!(index.delete(&[500]).unwrap(), 0);
+        assert!(index.is_deleted(510));
+        assert!(!index.is_deleted(520));
+        assert_eq!(index.deleted_count(), 2);
+        assert_eq!(index.tombstone_ratio(), 0.02);
+
+        let (ids, _) = index.get_nns_by_vector(&[50.1, 0.0], 3, None);
+        assert_eq!(ids, vec![490, 520, 480]);
+        let (ids, _) = index.get_nns_by_vector(&[50.1, 0.0], 3, Some(-1));
+        assert_eq!(ids, vec![490, 520, 480]);
+        assert!(index.get_nns_by_vector(&[50.1, 0.0], 0, None).0.is_empty());
+        assert!(index.get_nns_by_vector(&[50.1, 0.0], -1, None).0.is_empty());
+        let (ids, _, truncated) = index.get_nns_within(&[50.1, 0.0], 2.5, 2, None);
+        assert_eq!(ids, vec![490, 520]);
+        assert!(truncated);
+        assert_eq!(
+            index.get_neighbours(490, 2),
+            Some((vec![490, 480], vec![0.0, 1.0]))
+        );
+        assert_eq!(index.get_neighbours(490, 3), None);
+
+        let mut loaded = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            loaded.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let mut loaded = loaded.build(Some(10));
+        loaded.load_tombstones(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.deleted_count(), 2);
+        assert!(loaded.is_deleted(500));
+    }
+
+    #[test]
+    fn upsert_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        assert!(index.upsert(5000, &[50.5], Attributes::new()).is_err());
+        let mut attributes = Attributes::new();
+        attributes.insert("new".to_owned(), ::filter::AttributeValue::Boolean(true));
+        index.upsert(5000, &[50.5, 0.0], attributes).unwrap();
+        index.upsert(100, &[50.6, 0.0], Attributes::new()).unwrap();
+        assert_eq!(index.upserted_count(), 2);
+
+        let (ids, _) = index.get_nns_by_vector(&[10.1, 0.0], 3, None);
+        assert_eq!(ids, vec![110, 90, 120]);
+        let (ids, _) = index.get_nns_by_vector(&[50.5, 0.0], 2, None);
+        assert_eq!(ids, vec![5000, 100]);
+        let (ids, _, truncated) = index.get_nns_within(&[50.5, 0.0], 0.2, 10, None);
+        assert_eq!(ids, vec![5000, 100]);
+        assert!(!truncated);
+        let new = Filter::Equals("new".to_owned(), ::filter::AttributeValue::Boolean(true));
+        let (ids, _) = index.get_nns_by_vector_filtered(&[0.0, 0.0], 1, None, &new, 1000);
+        assert_eq!(ids, vec![5000]);
+        assert_eq!(index.get_item_vector(100), Some(vec![50.6, 0.0]));
+
+        assert_eq!(index.delete(&[5000]).unwrap(), 1);
+        assert!(index.is_deleted(5000));
+        let (ids, _) = index.get_nns_by_vector(&[50.5, 0.0], 1, None);
+        assert_eq!(ids, vec![100]);
+
+        let (compacted, version) = index.compact(Some(10)).unwrap();
+        assert_eq!(compacted.len(), 100);
+        assert_eq!(compacted.upserted_count(), 0);
+        assert_eq!(compacted.get_item_vector(100), Some(vec![50.6, 0.0]));
+        index.upsert(7000, &[70.0, 0.0], Attributes::new()).unwrap();
+        index.delete(&[500]).unwrap();
+        index.replay(version, &compacted).unwrap();
+        assert_eq!(compacted.upserted_count(), 1);
+        assert!(compacted.is_deleted(500));
+        let (ids, _) = compacted.get_nns_by_vector(&[50.4, 0.0], 2, None);
+        assert_eq!(ids, vec![100, 510]);
+    }
+
+    #[test]
+    fn replay_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        assert_eq!(index.delete(&[100]).unwrap(), 1);
+        index.upsert(100, &[50.2, 0.0], Attributes::new()).unwrap();
+        let (compacted, version) = index.compact(Some(10)).unwrap();
+        assert_eq!(compacted.get_item_vector(100), Some(vec![50.2, 0.0]));
+        // deleted again while the compacted index was built
+        assert_eq!(index.delete(&[100]).unwrap(), 1);
+        assert!(index.is_deleted(100));
+        index.replay(version, &compacted).unwrap();
+        assert!(compacted.is_deleted(100));
+        let (ids, _) = compacted.get_nns_by_vector(&[50.2, 0.0], 1, None);
+        assert_eq!(ids, vec![500]);
+    }
+
+    #[test]
+    fn validation_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Angular);
+        match builder.put(0, &[1.0, f32::NAN]) {
+            Err(Error::NonFiniteComponent(1)) => {}
+            r => panic!("{:?}", r),
+        }
+        match builder.put(0, &[0.0, 0.0]) {
+            Err(Error::ZeroVector) => {}
+            r => panic!("{:?}", r),
+        }
+        match builder.put(0, &[1.0, 0.0, 0.0]) {
+            Err(Error::DimensionError(3, 2)) => {}
+            r => panic!("{:?}", r),
+        }
+        builder.put(0, &[1.0, 0.0]).unwrap();
+        let index = builder.build(Some(1));
+        assert_eq!(index.len(), 1);
+        assert!(index
+            .upsert(1, &[f32::INFINITY, 0.0], Attributes::new())
+            .is_err());
+        assert!(index.upsert(1, &[0.0, 0.0], Attributes::new()).is_err());
+        assert_eq!(index.upserted_count(), 0);
+    }
+
+    #[test]
+    fn preprocessing_test() {
+        use preprocessing::{Pipeline, Step};
+        // keeps the first component, shifted by 1
+        let pipeline = Pipeline::new()
+            .with_step(Step::SubtractMean(vec![1.0, 0.0, 0.0]))
+            .with_step(Step::project(1, vec![1.0, 0.0, 0.0]).unwrap());
+        assert!(
+            MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean)
+                .with_preprocessing(pipeline.clone())
+                .is_err()
+        );
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 1, Distance::Euclidean)
+            .with_preprocessing(pipeline.clone())
+            .unwrap();
+        for i in 0..100 {
+            builder.put(i, &[i as f32 + 1.0, -1.0, 5.0]).unwrap();
+        }
+        assert!(builder.put(100, &[1.0]).is_err());
+        let index = builder.build(Some(10));
+        assert_eq!(index.get_item_vector(10), Some(vec![10.0]));
+        assert_eq!(index.preprocessing(), &pipeline);
+        let query = index.preprocess(&[11.2, 0.0, 0.0]).unwrap();
+        assert_eq!(query.len(), 1);
+        let (ids, _) = index.get_nns_by_vector(&query, 1, None);
+        assert_eq!(ids, vec![10]);
+        assert!(index.preprocess(&[10.0]).is_err());
+
+        let (compacted, _) = index.compact(Some(10)).unwrap();
+        assert_eq!(compacted.get_item_vector(10), Some(vec![10.0]));
+        assert_eq!(compacted.preprocessing(), &pipeline);
+    }
+
+    #[test]
+    fn hnsw_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean)
+            .with_hnsw(8, 100, Quantization::F32);
+        for i in 0..1000 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        let (annoy_ids, annoy_distances) = index.get_nns_by_vector(&[500.2, 0.0], 3, None);
+        let (ids, distances) = index
+            .get_nns_by_vector_using(Algorithm::Hnsw, &[500.2, 0.0], 3, None)
+            .unwrap();
+        assert_eq!(ids, vec![5000, 5010, 4990]);
+        assert_eq!(ids, annoy_ids);
+        assert_eq!(distances, annoy_distances);
+        // odd so that no tie straddles the exact neighbours
+        assert!(index.recall(Algorithm::Hnsw, 9, 50, None).unwrap() > 0.95);
+
+        let path = std::env::temp_dir().join("annoy_mapping_hnsw_test");
+        index.save_hnsw(&path).unwrap();
+        let mut loaded = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..1000 {
+            loaded.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let mut loaded = loaded.build(Some(1));
+        assert!(loaded.set_algorithm(Algorithm::Hnsw).is_err());
+        loaded.load_hnsw(&path).unwrap();
+        let mut rebuilt = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        rebuilt.put(0, &[0.0, 0.0]).unwrap();
+        let mut rebuilt = rebuilt.build(Some(1));
+        assert!(rebuilt.load_hnsw(&path).is_err());
+        rebuilt.build_hnsw(8, 100, Quantization::F16);
+        assert!(rebuilt.has_hnsw());
+        assert_eq!(rebuilt.recall(Algorithm::Hnsw, 1, 1, None).unwrap(), 1.0);
+        std::fs::remove_file(path).unwrap();
+        loaded.set_algorithm(Algorithm::Hnsw).unwrap();
+
+        let (ids, _, truncated) = loaded.get_nns_within(&[500.0, 0.0], 2.5, 100, None);
+        assert_eq!(ids, vec![5000, 4990, 5010, 4980, 5020]);
+        assert!(!truncated);
+        let even = Filter::Equals("even".to_owned(), ::filter::AttributeValue::Boolean(true));
+        let (ids, _) = loaded.get_nns_by_vector_filtered(&[500.0, 0.0], 3, None, &even, 100);
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn quantized_hnsw_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean)
+            .with_hnsw(8, 100, Quantization::Int8);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        let dir = std::env::temp_dir();
+        index
+            .save_mapping(dir.join("annoy_quantized_mapping"))
+            .unwrap();
+        index.save_hnsw(dir.join("annoy_quantized_hnsw")).unwrap();
+
+        // served from the graph alone, without the f32 vectors of annoy
+        let mut loaded = MappingIndex::<i64>::load_mapping(
+            "test",
+            dir.join("annoy_quantized_mapping"),
+            2,
+            Distance::Euclidean,
+        )
+        .unwrap();
+        loaded.load_hnsw(dir.join("annoy_quantized_hnsw")).unwrap();
+        std::fs::remove_file(dir.join("annoy_quantized_mapping")).unwrap();
+        std::fs::remove_file(dir.join("annoy_quantized_hnsw")).unwrap();
+        assert!(!loaded.has_annoy());
+        loaded.set_algorithm(Algorithm::Hnsw).unwrap();
+        let (ids, _) = loaded.get_nns_by_vector(&[50.2, 0.0], 1, None);
+        assert_eq!(ids, vec![500]);
+        let dequantized = loaded.get_item_vector(500).unwrap();
+        assert!(Distance::Euclidean.between(&dequantized, &[50.0, 0.0]) < 1.0);
+    }
+
+    #[test]
+    fn quantized_annoy_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        let dir = std::env::temp_dir();
+        let mapping_path = dir.join("annoy_quantized_annoy_mapping");
+        let index_path = dir.join("annoy_quantized_annoy_index");
+        let quantized_path = dir.join("annoy_quantized_annoy_vectors");
+        index.save_mapping(&mapping_path).unwrap();
+        index.save_index(&index_path).unwrap();
+        index
+            .save_quantized(&quantized_path, Quantization::Int8)
+            .unwrap();
+
+        // searched without the f32 vectors of annoylib
+        let mut loaded =
+            MappingIndex::<i64>::load_mapping("test", &mapping_path, 2, Distance::Euclidean)
+                .unwrap();
+        assert!(loaded.load_quantized(&index_path, &mapping_path).is_err());
+        loaded.load_quantized(&index_path, &quantized_path).unwrap();
+        assert!(loaded.has_annoy());
+        assert!(loaded.has_quantized());
+        let (ids, _) = loaded.get_nns_by_vector(&[50.2, 0.0], 2, None);
+        assert_eq!(ids, vec![500, 510]);
+        let (ids, _, truncated) = loaded.get_nns_within(&[50.0, 0.0], 0.5, 10, None);
+        assert_eq!(ids, vec![500]);
+        assert!(!truncated);
+        let dequantized = loaded.get_item_vector(500).unwrap();
+        assert_ne!(dequantized, vec![50.0, 0.0]);
+        assert!(Distance::Euclidean.between(&dequantized, &[50.0, 0.0]) < 1.0);
+
+        // the f32 index is the reference of the recall, searches stay quantized
+        assert!(loaded.recall(Algorithm::Annoy, 5, 50, None).is_err());
+        loaded.load_index(&index_path).unwrap();
+        assert_eq!(loaded.get_item_vector(500), Some(dequantized));
+        assert!(loaded.recall(Algorithm::Annoy, 5, 50, None).unwrap() > 0.7);
+        for path in &[mapping_path, index_path, quantized_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn ivfpq_test() {
+        let mut builder = MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..1000 {
+            builder.put(i * 10, &[i as f32, (i % 10) as f32]).unwrap();
+        }
+        let mut index = builder.build(Some(10));
+        assert!(index.set_algorithm(Algorithm::IvfPq).is_err());
+        let ivfpq = IvfPqBuilder::new(2, Distance::Euclidean)
+            .with_lists(10)
+            .with_subquantizers(2);
+        index.build_ivfpq(ivfpq, 1000).unwrap();
+        let (ids, _) = index
+            .get_nns_by_vector_using(Algorithm::IvfPq, &[500.0, 0.0], 1, Some(10))
+            .unwrap();
+        assert_eq!(ids, vec![5000]);
+
+        let dir = std::env::temp_dir();
+        index.save_mapping(dir.join("annoy_ivfpq_mapping")).unwrap();
+        index.save_ivfpq(dir.join("annoy_ivfpq_codes")).unwrap();
+        let mut loaded = MappingIndex::<i64>::load_mapping(
+            "test",
+            dir.join("annoy_ivfpq_mapping"),
+            2,
+            Distance::Euclidean,
+        )
+        .unwrap();
+        assert!(!loaded.has_annoy());
+        assert!(loaded.set_algorithm(Algorithm::Annoy).is_err());
+        loaded.load_ivfpq(dir.join("annoy_ivfpq_codes")).unwrap();
+        std::fs::remove_file(dir.join("annoy_ivfpq_mapping")).unwrap();
+        std::fs::remove_file(dir.join("annoy_ivfpq_codes")).unwrap();
+        loaded.set_algorithm(Algorithm::IvfPq).unwrap();
+
+        let (ids, _) = loaded.get_nns_by_vector(&[500.0, 0.0], 1, Some(10));
+        assert_eq!(ids, vec![5000]);
+        let decoded = loaded.get_item_vector(5000).unwrap();
+        assert!(Distance::Euclidean.between(&decoded, &[500.0, 0.0]) < 1.0);
+    }
+}
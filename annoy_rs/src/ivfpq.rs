@@ -0,0 +1,800 @@
+use distance::{dot, Distance};
+use err::Error;
+use random::Kiss64Random;
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::path::Path;
+use storage::Storage;
+
+/// "IVFP" in ascii
+const MAGIC: u32 = 0x5046_5649;
+const VERSION: u32 = 1;
+/// Header words: magic, version, dimension, item count, lists, sub-quantizers,
+/// centroids per sub-quantizer, unused
+const HEADER_WORDS: usize = 8;
+const KMEANS_ITERATIONS: usize = 20;
+/// Codes are stored on one byte
+const MAX_CENTROIDS: usize = 256;
+const DEFAULT_NPROBE: usize = 16;
+
+fn squared_euclidean(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Components of the sub-vectors of sub-quantizer [j] out of [m]
+fn subspace(dimension: usize, m: usize, j: usize) -> Range<usize> {
+    j * dimension / m..(j + 1) * dimension / m
+}
+
+/// Closest of the [dimension] [centroids] to [v]
+fn nearest(centroids: &[f32], dimension: usize, v: &[f32]) -> usize {
+    let mut best = 0;
+    let mut best_distance = f32::INFINITY;
+    for (i, centroid) in centroids.chunks(dimension).enumerate() {
+        let d = squared_euclidean(centroid, v);
+        if d < best_distance {
+            best = i;
+            best_distance = d;
+        }
+    }
+    best
+}
+
+/// Lloyd k-means of the [dimension] vectors of [data] into [k] centroids, at most one per vector.
+/// Empty clusters are moved to a random vector.
+fn kmeans(data: &[f32], dimension: usize, k: usize, random: &mut Kiss64Random) -> Vec<f32> {
+    let n = data.len() / dimension;
+    let k = k.min(n);
+    let mut order: Vec<usize> = (0..n).collect();
+    for i in 0..k {
+        let j = i + random.index(n - i);
+        order.swap(i, j);
+    }
+    let mut centroids: Vec<f32> = order[..k]
+        .iter()
+        .flat_map(|i| data[i * dimension..(i + 1) * dimension].iter().cloned())
+        .collect();
+    let mut assignments = vec![usize::MAX; n];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (i, v) in data.chunks(dimension).enumerate() {
+            let c = nearest(&centroids, dimension, v);
+            if c != assignments[i] {
+                assignments[i] = c;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        let mut counts = vec![0usize; k];
+        for x in centroids.iter_mut() {
+            *x = 0.0;
+        }
+        for (v, c) in data.chunks(dimension).zip(&assignments) {
+            counts[*c] += 1;
+            for (x, y) in centroids[c * dimension..(c + 1) * dimension]
+                .iter_mut()
+                .zip(v)
+            {
+                *x += y;
+            }
+        }
+        for (c, count) in counts.iter().enumerate() {
+            let centroid = &mut centroids[c * dimension..(c + 1) * dimension];
+            if *count == 0 {
+                let i = random.index(n);
+                centroid.copy_from_slice(&data[i * dimension..(i + 1) * dimension]);
+            } else {
+                for x in centroid.iter_mut() {
+                    *x /= *count as f32;
+                }
+            }
+        }
+    }
+    centroids
+}
+
+/// Copy of [v] as indexed: normalized for angular distances
+fn prepare(distance: Distance, v: &[f32]) -> Vec<f32> {
+    let mut v = v.to_vec();
+    if distance == Distance::Angular {
+        let norm = dot(&v, &v).sqrt();
+        if norm > 0.0 {
+            for x in v.iter_mut() {
+                *x /= norm;
+            }
+        }
+    }
+    v
+}
+
+/// Training parameters of an inverted file index with product quantization.
+///
+/// Items are assigned to the closest of [lists] k-means centroids, and the residual to that centroid
+/// is split in [subquantizers] sub-vectors, each stored as the one byte code of its closest
+/// centroid in a codebook of 256. Angular, euclidean and dot product distances are supported.
+pub struct IvfPqBuilder {
+    dimension: usize,
+    distance: Distance,
+    lists: usize,
+    subquantizers: usize,
+    seed: u64,
+}
+
+impl IvfPqBuilder {
+    pub const DEFAULT_LISTS: usize = 1024;
+    pub const DEFAULT_SUBQUANTIZERS: usize = 16;
+
+    pub fn new(dimension: i32, distance: Distance) -> IvfPqBuilder {
+        IvfPqBuilder {
+            dimension: dimension as usize,
+            distance,
+            lists: IvfPqBuilder::DEFAULT_LISTS,
+            subquantizers: IvfPqBuilder::DEFAULT_SUBQUANTIZERS.min(dimension.max(1) as usize),
+            seed: Kiss64Random::DEFAULT_SEED,
+        }
+    }
+
+    /// Number of coarse centroids, each item being searched only when its list is probed
+    pub fn with_lists(mut self, lists: usize) -> IvfPqBuilder {
+        self.lists = lists.max(1);
+        self
+    }
+
+    /// Number of one byte codes of each item, at most the dimension
+    pub fn with_subquantizers(mut self, subquantizers: usize) -> IvfPqBuilder {
+        self.subquantizers = subquantizers.max(1).min(self.dimension.max(1));
+        self
+    }
+
+    /// Seed of the k-means initializations
+    pub fn with_seed(mut self, seed: u64) -> IvfPqBuilder {
+        self.seed = seed;
+        self
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.dimension as i32
+    }
+
+    pub fn distance(&self) -> &Distance {
+        &self.distance
+    }
+
+    /// Train the coarse quantizer and the codebooks on the vectors of [sample], laid out one
+    /// after the other, and return an encoder of the items
+    /// ```
+    /// use annoy_rs::distance::Distance;
+    /// use annoy_rs::ivfpq::IvfPqBuilder;
+    /// let vectors: Vec<f32> = (0..1000).flat_map(|i| vec![i as f32, (i % 10) as f32]).collect();
+    /// let mut encoder = IvfPqBuilder::new(2, Distance::Euclidean)
+    ///     .with_lists(10)
+    ///     .with_subquantizers(2)
+    ///     .train(&vectors)
+    ///     .unwrap();
+    /// for v in vectors.chunks(2) {
+    ///     encoder.add_item(v);
+    /// }
+    /// let index = encoder.build();
+    /// let (results, _) = index.get_nns_by_vector(&[500.0, 0.0], 1, None);
+    /// assert_eq!(results, vec![500]);
+    /// ```
+    pub fn train(self, sample: &[f32]) -> Result<IvfPqEncoder, Error> {
+        match self.distance {
+            Distance::Angular | Distance::Euclidean | Distance::DotProduct => {}
+            distance => return Err(Error::UnsupportedDistance(distance)),
+        }
+        let dimension = self.dimension;
+        if dimension == 0 || sample.len() < dimension {
+            return Err(Error::BuildError);
+        }
+        let sample: Vec<f32> = sample[..sample.len() / dimension * dimension]
+            .chunks(dimension)
+            .flat_map(|v| prepare(self.distance, v))
+            .collect();
+        let mut random = Kiss64Random::new(self.seed);
+        let coarse = kmeans(&sample, dimension, self.lists, &mut random);
+
+        let residuals: Vec<f32> = sample
+            .chunks(dimension)
+            .flat_map(|v| {
+                let c = nearest(&coarse, dimension, v);
+                let centroid = &coarse[c * dimension..(c + 1) * dimension];
+                v.iter()
+                    .zip(centroid)
+                    .map(|(x, y)| x - y)
+                    .collect::<Vec<f32>>()
+            })
+            .collect();
+        let centroids = MAX_CENTROIDS.min(residuals.len() / dimension);
+        let mut codebooks = Vec::with_capacity(centroids * dimension);
+        for j in 0..self.subquantizers {
+            let range = subspace(dimension, self.subquantizers, j);
+            let sub: Vec<f32> = residuals
+                .chunks(dimension)
+                .flat_map(|r| r[range.clone()].iter().cloned())
+                .collect();
+            codebooks.extend(kmeans(&sub, range.len(), centroids, &mut random));
+        }
+
+        Ok(IvfPqEncoder {
+            dimension,
+            distance: self.distance,
+            subquantizers: self.subquantizers,
+            centroids,
+            coarse,
+            codebooks,
+            lists: Vec::new(),
+            codes: Vec::new(),
+        })
+    }
+}
+
+/// Encoder of the items with a trained quantizer, only keeping their codes in memory
+pub struct IvfPqEncoder {
+    dimension: usize,
+    distance: Distance,
+    subquantizers: usize,
+    /// Number of centroids of each codebook
+    centroids: usize,
+    coarse: Vec<f32>,
+    /// Codebook of each sub-quantizer, of centroids * sub-vector dimension floats
+    codebooks: Vec<f32>,
+    /// List of each item
+    lists: Vec<u32>,
+    codes: Vec<u8>,
+}
+
+impl IvfPqEncoder {
+    pub fn add_item(&mut self, v: &[f32]) -> i32 {
+        let id = self.len();
+        let dimension = self.dimension;
+        let v = prepare(self.distance, &v[..dimension]);
+        let list = nearest(&self.coarse, dimension, &v);
+        let centroid = &self.coarse[list * dimension..(list + 1) * dimension];
+        let residual: Vec<f32> = v.iter().zip(centroid).map(|(x, y)| x - y).collect();
+        for j in 0..self.subquantizers {
+            let range = subspace(dimension, self.subquantizers, j);
+            let codebook =
+                &self.codebooks[self.centroids * range.start..self.centroids * range.end];
+            self.codes
+                .push(nearest(codebook, range.len(), &residual[range]) as u8);
+        }
+        self.lists.push(list as u32);
+        id
+    }
+
+    pub fn len(&self) -> i32 {
+        self.lists.len() as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.is_empty()
+    }
+
+    /// Group the codes by list in memory
+    pub fn build(self) -> IvfPq {
+        let count = self.lists.len();
+        let lists = self.coarse.len() / self.dimension;
+        let m = self.subquantizers;
+        let mut offsets = vec![0u32; lists + 1];
+        for list in &self.lists {
+            offsets[*list as usize + 1] += 1;
+        }
+        for l in 0..lists {
+            offsets[l + 1] += offsets[l];
+        }
+        let mut next = offsets.clone();
+        let mut ids = vec![0u32; count];
+        let mut positions = vec![0u32; count];
+        let mut codes = vec![0u8; count * m];
+        for (i, list) in self.lists.iter().enumerate() {
+            let p = next[*list as usize] as usize;
+            next[*list as usize] += 1;
+            ids[p] = i as u32;
+            positions[i] = p as u32;
+            codes[p * m..(p + 1) * m].copy_from_slice(&self.codes[i * m..(i + 1) * m]);
+        }
+
+        let mut words = vec![
+            MAGIC,
+            VERSION,
+            self.dimension as u32,
+            count as u32,
+            lists as u32,
+            m as u32,
+            self.centroids as u32,
+            0,
+        ];
+        words.extend(self.coarse.iter().map(|x| x.to_bits()));
+        words.extend(self.codebooks.iter().map(|x| x.to_bits()));
+        words.extend(offsets);
+        words.extend(ids);
+        words.extend(positions);
+        words.extend(codes.chunks(4).map(|c| {
+            let mut bytes = [0u8; 4];
+            bytes[..c.len()].copy_from_slice(c);
+            u32::from_ne_bytes(bytes)
+        }));
+        IvfPq::from_storage(Storage::Owned(words), self.dimension, self.distance)
+            .expect("invalid ivfpq index")
+    }
+}
+
+/// Inverted file index with product quantization, built by `IvfPqEncoder` or memory mapped from a file.
+///
+/// The file holds native endian words: a header, the coarse centroids, the codebooks,
+/// the start of each list, the items grouped by list, the position of each item in that order
+/// and finally the codes of the items in that order.
+pub struct IvfPq {
+    distance: Distance,
+    storage: Storage,
+    dimension: usize,
+    count: usize,
+    lists: usize,
+    subquantizers: usize,
+    centroids: usize,
+    codebooks_offset: usize,
+    offsets_offset: usize,
+    ids_offset: usize,
+    positions_offset: usize,
+    codes_offset: usize,
+}
+
+impl IvfPq {
+    fn from_storage(
+        storage: Storage,
+        dimension: usize,
+        distance: Distance,
+    ) -> Result<IvfPq, Error> {
+        let (count, lists, subquantizers, centroids) = {
+            let words = storage.words();
+            if words.len() < HEADER_WORDS || words[0] != MAGIC || words[1] != VERSION {
+                return Err(Error::InvalidIndex("not an ivfpq file".to_owned()));
+            }
+            if words[2] as usize != dimension {
+                return Err(Error::InvalidIndex(format!(
+                    "ivfpq dimension {}, expected {}",
+                    words[2], dimension
+                )));
+            }
+            (
+                words[3] as usize,
+                words[4] as usize,
+                words[5] as usize,
+                words[6] as usize,
+            )
+        };
+        // every item has an id and a position, which bounds the sizes below
+        let length = storage.words().len();
+        if count > length || lists > length || centroids > length {
+            return Err(Error::InvalidIndex("truncated ivfpq file".to_owned()));
+        }
+        if lists == 0 || centroids == 0 || centroids > MAX_CENTROIDS {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq file of {} lists and {} centroids",
+                lists, centroids
+            )));
+        }
+        let codebooks_offset = HEADER_WORDS + lists * dimension;
+        let offsets_offset = codebooks_offset + centroids * dimension;
+        let ids_offset = offsets_offset + lists + 1;
+        let positions_offset = ids_offset + count;
+        let codes_offset = positions_offset + count;
+        let expected = codes_offset + (count * subquantizers).div_ceil(4);
+        if storage.words().len() != expected || subquantizers == 0 || subquantizers > dimension {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq file of {} words, expected {}",
+                storage.words().len(),
+                expected
+            )));
+        }
+        let ivfpq = IvfPq {
+            distance,
+            storage,
+            dimension,
+            count,
+            lists,
+            subquantizers,
+            centroids,
+            codebooks_offset,
+            offsets_offset,
+            ids_offset,
+            positions_offset,
+            codes_offset,
+        };
+        ivfpq.check_lists()?;
+        Ok(ivfpq)
+    }
+
+    /// Check that searches stay within the index: the lists are consecutive ranges covering
+    /// the items, the ids and positions are in range and the codes are codewords
+    fn check_lists(&self) -> Result<(), Error> {
+        let words = self.storage.words();
+        let offsets = &words[self.offsets_offset..self.ids_offset];
+        if offsets[0] != 0 || offsets[self.lists] as usize != self.count {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq lists from {} to {} for {} items",
+                offsets[0], offsets[self.lists], self.count
+            )));
+        }
+        if let Some(list) = (0..self.lists).find(|l| offsets[*l] > offsets[*l + 1]) {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq list {} ends before it starts",
+                list
+            )));
+        }
+        let ids = &words[self.ids_offset..self.positions_offset];
+        let positions = &words[self.positions_offset..self.codes_offset];
+        if let Some(id) = ids
+            .iter()
+            .chain(positions)
+            .find(|i| **i as usize >= self.count)
+        {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq id or position {} for {} items",
+                id, self.count
+            )));
+        }
+        if let Some(code) = self.codes().iter().find(|c| **c as usize >= self.centroids) {
+            return Err(Error::InvalidIndex(format!(
+                "ivfpq code {} for {} centroids",
+                code, self.centroids
+            )));
+        }
+        Ok(())
+    }
+
+    /// Map the index file at [path] built with vectors of dimension [dimension]
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        dimension: i32,
+        distance: Distance,
+    ) -> Result<IvfPq, Error> {
+        IvfPq::from_storage(Storage::map(path)?, dimension as usize, distance)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.storage.save(path)
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.dimension as i32
+    }
+
+    pub fn distance(&self) -> &Distance {
+        &self.distance
+    }
+
+    pub fn len(&self) -> i32 {
+        self.count as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn centroid(&self, list: usize) -> &[f32] {
+        self.storage
+            .floats(HEADER_WORDS + list * self.dimension, self.dimension)
+    }
+
+    /// Centroids of sub-quantizer [j]
+    fn codebook(&self, j: usize) -> &[f32] {
+        let range = subspace(self.dimension, self.subquantizers, j);
+        self.storage.floats(
+            self.codebooks_offset + self.centroids * range.start,
+            self.centroids * range.len(),
+        )
+    }
+
+    fn codes(&self) -> &[u8] {
+        self.storage
+            .bytes(self.codes_offset, self.count * self.subquantizers)
+    }
+
+    /// Positions of the items of [list] in list order
+    fn list_range(&self, list: usize) -> Range<usize> {
+        let offsets = &self.storage.words()[self.offsets_offset..self.ids_offset];
+        offsets[list] as usize..offsets[list + 1] as usize
+    }
+
+    /// Return the decoded vector of [item], an approximation of the indexed one
+    pub fn get_item(&self, item: i32) -> Option<Vec<f32>> {
+        if item < 0 || item >= self.len() {
+            return None;
+        }
+        let words = self.storage.words();
+        let position = words[self.positions_offset + item as usize];
+        let offsets = &words[self.offsets_offset..self.ids_offset];
+        // last list starting at or before the position
+        let (mut low, mut high) = (0, self.lists);
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if offsets[middle] <= position {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        let mut v = self.centroid(low).to_vec();
+        let m = self.subquantizers;
+        let code = &self.codes()[position as usize * m..(position as usize + 1) * m];
+        for (j, c) in code.iter().enumerate() {
+            let range = subspace(self.dimension, m, j);
+            let sub = range.len();
+            let codeword = &self.codebook(j)[*c as usize * sub..(*c as usize + 1) * sub];
+            for (x, y) in v[range].iter_mut().zip(codeword) {
+                *x += y;
+            }
+        }
+        Some(v)
+    }
+
+    /// Distances from the sub-vectors of [q] to every codeword, by sub-quantizer.
+    /// Negated inner products for dot product indexes, squared distances otherwise.
+    fn distance_table(&self, q: &[f32], table: &mut Vec<f32>) {
+        table.clear();
+        for j in 0..self.subquantizers {
+            let range = subspace(self.dimension, self.subquantizers, j);
+            let sub = &q[range.clone()];
+            for codeword in self.codebook(j).chunks(range.len()) {
+                table.push(match self.distance {
+                    Distance::DotProduct => -dot(sub, codeword),
+                    _ => squared_euclidean(sub, codeword),
+                });
+            }
+        }
+    }
+
+    /// Return the [n] closer item to vector [w] among the lists of the [nprobe] closest centroids.
+    /// When using None for nprobe it probes 16 lists.
+    /// Distances are estimated from the codes, and the inner product for dot product indexes.
+    pub fn get_nns_by_vector(
+        &self,
+        w: &[f32],
+        n: i32,
+        nprobe: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>) {
+        let dot_product = self.distance == Distance::DotProduct;
+        let q = prepare(self.distance, &w[..self.dimension]);
+        let n = if n < 0 { self.count } else { n as usize };
+        let nprobe = match nprobe {
+            Some(nprobe) if nprobe > 0 => nprobe as usize,
+            _ => DEFAULT_NPROBE,
+        };
+        let mut probes: Vec<(f32, usize)> = (0..self.lists)
+            .map(|list| {
+                let centroid = self.centroid(list);
+                let key = if dot_product {
+                    -dot(&q, centroid)
+                } else {
+                    squared_euclidean(&q, centroid)
+                };
+                (key, list)
+            })
+            .collect();
+        probes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        probes.truncate(nprobe);
+
+        let by_key = |a: &(f32, u32), b: &(f32, u32)| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(Ordering::Equal)
+                .then(a.1.cmp(&b.1))
+        };
+        let m = self.subquantizers;
+        let ids = &self.storage.words()[self.ids_offset..self.positions_offset];
+        let codes = self.codes();
+        let mut table = Vec::with_capacity(m * self.centroids);
+        if dot_product {
+            // the inner product with the centroid is added to the one with the residual
+            self.distance_table(&q, &mut table);
+        }
+        let mut results: Vec<(f32, u32)> = Vec::new();
+        for (key, list) in probes {
+            let base = if dot_product {
+                key
+            } else {
+                let residual: Vec<f32> = q
+                    .iter()
+                    .zip(self.centroid(list))
+                    .map(|(x, y)| x - y)
+                    .collect();
+                self.distance_table(&residual, &mut table);
+                0.0
+            };
+            for p in self.list_range(list) {
+                let mut d = base;
+                for (j, c) in codes[p * m..(p + 1) * m].iter().enumerate() {
+                    d += table[j * self.centroids + *c as usize];
+                }
+                results.push((d, ids[p]));
+            }
+            if results.len() > n.saturating_mul(2).max(1024) {
+                results.sort_by(by_key);
+                results.truncate(n);
+            }
+        }
+        results.sort_by(by_key);
+        results.truncate(n);
+        results
+            .into_iter()
+            .map(|(d, i)| {
+                let d = if dot_product { -d } else { d.max(0.0).sqrt() };
+                (i as i32, d)
+            })
+            .unzip()
+    }
+
+    /// Return items within [max_distance] of [w] in the probed lists, closest first,
+    /// up to [max_results] of them. The last value is true when more items were within the distance.
+    /// For dot product indexes, items with an inner product of at least [max_distance] are returned.
+    pub fn get_nns_within(
+        &self,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        nprobe: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>, bool) {
+        let max_results = max_results.max(0);
+        let (ids, distances) = self.get_nns_by_vector(w, max_results.saturating_add(1), nprobe);
+        let count = distances
+            .iter()
+            .take_while(|d| match self.distance {
+                Distance::DotProduct => **d >= max_distance,
+                _ => **d <= max_distance,
+            })
+            .count();
+        let truncated = count > max_results as usize;
+        let count = count.min(max_results as usize);
+        (
+            ids[..count].to_vec(),
+            distances[..count].to_vec(),
+            truncated,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Standard;
+    use rand::prelude::*;
+
+    fn random_vectors(n: usize, f: usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(1);
+        rng.sample_iter(&Standard).take(n * f).collect()
+    }
+
+    fn recall(distance: Distance) -> f64 {
+        let vectors = random_vectors(2000, 16);
+        let mut encoder = IvfPqBuilder::new(16, distance)
+            .with_lists(16)
+            .with_subquantizers(8)
+            .train(&vectors)
+            .unwrap();
+        for v in vectors.chunks(16) {
+            encoder.add_item(v);
+        }
+        let index = encoder.build();
+        let queries = random_vectors(50, 16);
+        let mut found = 0;
+        for q in queries.chunks(16) {
+            let mut exact: Vec<(f32, i32)> = vectors
+                .chunks(16)
+                .enumerate()
+                .map(|(i, v)| match distance {
+                    Distance::DotProduct => (-distance.between(q, v), i as i32),
+                    _ => (distance.between(q, v), i as i32),
+                })
+                .collect();
+            exact.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let expected: Vec<i32> = exact.iter().take(10).map(|e| e.1).collect();
+            let (ids, _) = index.get_nns_by_vector(q, 100, Some(16));
+            found += expected.iter().filter(|id| ids.contains(id)).count();
+        }
+        found as f64 / (50 * 10) as f64
+    }
+
+    #[test]
+    fn recall_test() {
+        // the 10 closest items are among the 100 closest codes
+        assert!(recall(Distance::Euclidean) > 0.9);
+        assert!(recall(Distance::Angular) > 0.9);
+        assert!(recall(Distance::DotProduct) > 0.9);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let vectors = random_vectors(500, 8);
+        let mut encoder = IvfPqBuilder::new(8, Distance::Euclidean)
+            .with_lists(8)
+            .with_subquantizers(3)
+            .train(&vectors[..800])
+            .unwrap();
+        for v in vectors.chunks(8) {
+            encoder.add_item(v);
+        }
+        let index = encoder.build();
+        let path = std::env::temp_dir().join("annoy_ivfpq_test");
+        index.save(&path).unwrap();
+        let loaded = IvfPq::load(&path, 8, Distance::Euclidean).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 500);
+        for i in 0..50 {
+            let v = &vectors[i * 8..(i + 1) * 8];
+            assert_eq!(
+                loaded.get_nns_by_vector(v, 10, None),
+                index.get_nns_by_vector(v, 10, None)
+            );
+            let decoded = loaded.get_item(i as i32).unwrap();
+            assert!(Distance::Euclidean.between(v, &decoded) < 0.5);
+        }
+        assert_eq!(loaded.get_item(500), None);
+        assert!(IvfPq::load(&path, 8, Distance::Euclidean).is_err());
+
+        let (all, distances, truncated) = loaded.get_nns_within(&vectors[..8], 0.5, 1000, Some(8));
+        assert!(distances.iter().all(|d| *d <= 0.5));
+        assert!(!truncated);
+        let (ids, _, truncated) = loaded.get_nns_within(&vectors[..8], 0.5, 3, Some(8));
+        assert_eq!(ids[..], all[..ids.len()]);
+        assert_eq!(truncated, all.len() > 3);
+    }
+
+    #[test]
+    fn corrupt_test() {
+        let vectors = random_vectors(100, 4);
+        let mut encoder = IvfPqBuilder::new(4, Distance::Euclidean)
+            .with_lists(4)
+            .with_subquantizers(2)
+            .train(&vectors)
+            .unwrap();
+        for v in vectors.chunks(4) {
+            encoder.add_item(v);
+        }
+        let path = std::env::temp_dir().join("annoy_ivfpq_corrupt");
+        encoder.build().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let word = |i: usize| {
+            u32::from_ne_bytes([
+                bytes[4 * i],
+                bytes[4 * i + 1],
+                bytes[4 * i + 2],
+                bytes[4 * i + 3],
+            ])
+        };
+        let load = |i: usize, value: u32| {
+            let mut corrupt = bytes.clone();
+            corrupt[4 * i..4 * i + 4].copy_from_slice(&value.to_ne_bytes());
+            std::fs::write(&path, &corrupt).unwrap();
+            IvfPq::load(&path, 4, Distance::Euclidean)
+        };
+        let centroids = word(6) as usize;
+        let offsets_offset = HEADER_WORDS + 4 * 4 + centroids * 4;
+        let ids_offset = offsets_offset + 5;
+        let codes_offset = ids_offset + 2 * 100;
+        assert!(load(4, word(4)).is_ok());
+        // lists, centroids, list offsets, id, position and code of the first item
+        assert!(load(4, 0).is_err());
+        assert!(load(6, 0).is_err());
+        assert!(load(offsets_offset + 1, 101).is_err());
+        assert!(load(offsets_offset + 4, 99).is_err());
+        assert!(load(ids_offset, 100).is_err());
+        assert!(load(ids_offset + 100, 100).is_err());
+        assert!(load(codes_offset, word(codes_offset) | 0xff).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_distance() {
+        let vectors = random_vectors(10, 4);
+        assert!(IvfPqBuilder::new(4, Distance::Manhattan)
+            .train(&vectors)
+            .is_err());
+        assert!(IvfPqBuilder::new(4, Distance::Euclidean)
+            .train(&[])
+            .is_err());
+    }
+}
@@ -1,14 +1,33 @@
 #![feature(duration_as_u128)]
 #![feature(trait_alias)]
 extern crate libc;
+extern crate memmap;
+#[cfg(feature = "pure")]
+extern crate num_cpus;
 #[cfg(test)]
 extern crate rand;
 
+#[cfg(feature = "native")]
 pub mod annoy;
+#[cfg(feature = "pure")]
+pub mod builder;
+pub mod distance;
 pub mod err;
+pub mod filter;
+pub mod hnsw;
+#[cfg(feature = "native")]
 pub mod idmapping;
+pub mod ivfpq;
+pub mod preprocessing;
+pub mod quantization;
+#[cfg_attr(not(feature = "pure"), allow(dead_code))]
+mod random;
+pub mod reader;
+mod storage;
+#[cfg(feature = "native")]
 mod vector;
 
+#[cfg(feature = "native")]
 mod native {
     #![allow(non_upper_case_globals)]
     #![allow(non_camel_case_types)]
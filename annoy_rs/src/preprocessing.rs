@@ -0,0 +1,206 @@
+use err::Error;
+use std::fs;
+use std::path::Path;
+
+/// Transformation applied to query vectors before they are searched,
+/// and to item vectors before they are indexed
+#[derive(Debug, PartialEq, Clone)]
+pub enum Step {
+    /// Divide by the euclidean norm, zero vectors are kept unchanged
+    Normalize,
+    /// Subtract a mean vector, of the dimension of the vectors
+    SubtractMean(Vec<f32>),
+    /// Multiply by a row major matrix of [rows] x [columns], mapping vectors of dimension
+    /// [columns] to vectors of dimension [rows]
+    Project {
+        rows: usize,
+        columns: usize,
+        matrix: Vec<f32>,
+    },
+}
+
+impl Step {
+    /// Projection by the row major matrix of [rows] rows stored in [matrix]
+    pub fn project(rows: usize, matrix: Vec<f32>) -> Result<Step, Error> {
+        if rows == 0 || matrix.is_empty() || !matrix.len().is_multiple_of(rows) {
+            return Err(Error::ParsingError(format!(
+                "projection matrix of {} values into {} rows",
+                matrix.len(),
+                rows
+            )));
+        }
+        Ok(Step::Project {
+            rows,
+            columns: matrix.len() / rows,
+            matrix,
+        })
+    }
+
+    /// Dimension of the vectors this step applies to, None when any dimension is accepted
+    fn input_dimension(&self) -> Option<usize> {
+        match self {
+            Step::Normalize => None,
+            Step::SubtractMean(mean) => Some(mean.len()),
+            Step::Project { columns, .. } => Some(*columns),
+        }
+    }
+
+    fn apply(&self, v: &mut Vec<f32>) -> Result<(), Error> {
+        if let Some(dimension) = self.input_dimension() {
+            if v.len() != dimension {
+                return Err(Error::DimensionError(v.len(), dimension));
+            }
+        }
+        match self {
+            Step::Normalize => {
+                let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    v.iter_mut().for_each(|x| *x /= norm);
+                }
+            }
+            Step::SubtractMean(mean) => {
+                for (x, m) in v.iter_mut().zip(mean) {
+                    *x -= m;
+                }
+            }
+            Step::Project {
+                columns, matrix, ..
+            } => {
+                *v = matrix
+                    .chunks(*columns)
+                    .map(|row| row.iter().zip(v.iter()).map(|(a, x)| a * x).sum())
+                    .collect();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Steps applied in order to the vectors of an index, the identity when empty
+///
+/// ```
+/// use annoy_rs::preprocessing::{Pipeline, Step};
+///
+/// let pipeline = Pipeline::new()
+///     .with_step(Step::SubtractMean(vec![1.0, 1.0]))
+///     .with_step(Step::Normalize);
+/// assert_eq!(pipeline.apply(&[4.0, 5.0]).unwrap(), vec![0.6, 0.8]);
+/// assert!(pipeline.apply(&[4.0]).is_err());
+/// ```
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { steps: Vec::new() }
+    }
+
+    pub fn with_step(mut self, step: Step) -> Pipeline {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Dimension of the vectors the pipeline applies to, None when any dimension is accepted
+    pub fn input_dimension(&self) -> Option<usize> {
+        self.steps.iter().find_map(Step::input_dimension)
+    }
+
+    /// Dimension of the vectors of dimension [input] once transformed,
+    /// failing when a step does not accept the dimension of the previous one
+    pub fn output_dimension(&self, input: usize) -> Result<usize, Error> {
+        let mut dimension = input;
+        for step in &self.steps {
+            if let Some(expected) = step.input_dimension() {
+                if dimension != expected {
+                    return Err(Error::DimensionError(dimension, expected));
+                }
+            }
+            if let Step::Project { rows, .. } = step {
+                dimension = *rows;
+            }
+        }
+        Ok(dimension)
+    }
+
+    /// Transform [vector] with every step
+    pub fn apply(&self, vector: &[f32]) -> Result<Vec<f32>, Error> {
+        let mut v = vector.to_vec();
+        for step in &self.steps {
+            step.apply(&mut v)?;
+        }
+        Ok(v)
+    }
+}
+
+/// Read a file of little endian f32, e.g. a mean vector or a projection matrix
+pub fn read_floats<P: AsRef<Path>>(path: P) -> Result<Vec<f32>, Error> {
+    let bytes = fs::read(path.as_ref())?;
+    if !bytes.len().is_multiple_of(4) {
+        return Err(Error::ParsingError(format!(
+            "{} of {} bytes as f32",
+            path.as_ref().display(),
+            bytes.len()
+        )));
+    }
+    Ok(bytes
+        .chunks(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+/// Write [values] as little endian f32, read back by [read_floats]
+pub fn write_floats<P: AsRef<Path>>(path: P, values: &[f32]) -> Result<(), Error> {
+    let bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect();
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_test() {
+        // projects on the sum and the difference of the components
+        let projection = Step::project(2, vec![1.0, 1.0, 0.0, 1.0, -1.0, 0.0]).unwrap();
+        let pipeline = Pipeline::new()
+            .with_step(Step::SubtractMean(vec![1.0, 0.0, 5.0]))
+            .with_step(projection)
+            .with_step(Step::Normalize);
+        assert_eq!(pipeline.input_dimension(), Some(3));
+        assert_eq!(pipeline.output_dimension(3).unwrap(), 2);
+        assert!(pipeline.output_dimension(2).is_err());
+        assert_eq!(pipeline.apply(&[4.5, 0.5, 7.0]).unwrap(), vec![0.8, 0.6]);
+        assert_eq!(pipeline.apply(&[1.0, 0.0, 0.0]).unwrap(), vec![0.0, 0.0]);
+        match pipeline.apply(&[1.0, 2.0]) {
+            Err(Error::DimensionError(2, 3)) => {}
+            r => panic!("{:?}", r),
+        }
+        assert!(Step::project(4, vec![1.0; 6]).is_err());
+        assert_eq!(Pipeline::new().apply(&[1.0, 2.0]).unwrap(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn floats_test() {
+        let path = std::env::temp_dir().join("annoy_rs_floats_test");
+        let values = vec![1.5, -2.0, 0.0, 1e-3];
+        write_floats(&path, &values).unwrap();
+        assert_eq!(read_floats(&path).unwrap(), values);
+        std::fs::write(&path, [0u8; 5]).unwrap();
+        assert!(read_floats(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
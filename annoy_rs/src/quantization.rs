@@ -0,0 +1,400 @@
+use distance::Distance;
+use err::Error;
+use std::fmt;
+use std::path::Path;
+use std::slice;
+use std::str::FromStr;
+use storage::Storage;
+
+/// "QVEC" in ascii
+const MAGIC: u32 = 0x4345_5651;
+const VERSION: u32 = 1;
+/// Header words: magic, version, dimension, item count, quantization
+const HEADER_WORDS: usize = 5;
+
+/// Representation of the item vectors stored in a HNSW graph or in `QuantizedVectors`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Quantization {
+    F32,
+    /// IEEE half precision floats, half the size of f32
+    F16,
+    /// Signed bytes scaled by the maximum absolute value of each dimension, a quarter of the size
+    Int8,
+}
+
+impl FromStr for Quantization {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Quantization, Error> {
+        match s {
+            "f32" => Ok(Quantization::F32),
+            "f16" => Ok(Quantization::F16),
+            "int8" => Ok(Quantization::Int8),
+            _ => Err(Error::ParsingError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Quantization::F32 => "f32",
+            Quantization::F16 => "f16",
+            Quantization::Int8 => "int8",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Nearest half precision float of [x], rounding to even
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // infinity or nan
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (half, shift) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // subnormal, the implicit bit becomes part of the mantissa
+        let shift = (14 - e) as u32;
+        ((mantissa | 0x80_0000) >> shift, shift)
+    } else {
+        (((e as u32) << 10) | (mantissa >> 13), 13)
+    };
+    let remainder = (mantissa | if e <= 0 { 0x80_0000 } else { 0 }) & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    // a carry into the exponent rounds to the next power of two, or to infinity
+    let rounded = if remainder > halfway || (remainder == halfway && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+    sign | rounded as u16
+}
+
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = u32::from(h & 0x8000) << 16;
+    let exponent = u32::from((h >> 10) & 0x1f);
+    let mantissa = u32::from(h & 0x3ff);
+    match exponent {
+        0 => {
+            let x = mantissa as f32 / 16_777_216.0;
+            if sign != 0 {
+                -x
+            } else {
+                x
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+impl Quantization {
+    pub(crate) fn from_word(word: u32) -> Result<Quantization, Error> {
+        match word {
+            0 => Ok(Quantization::F32),
+            1 => Ok(Quantization::F16),
+            2 => Ok(Quantization::Int8),
+            _ => Err(Error::InvalidIndex(format!(
+                "unknown quantization {}",
+                word
+            ))),
+        }
+    }
+
+    pub(crate) fn to_word(self) -> u32 {
+        match self {
+            Quantization::F32 => 0,
+            Quantization::F16 => 1,
+            Quantization::Int8 => 2,
+        }
+    }
+
+    /// Number of words storing [count] vectors of [dimension]
+    pub(crate) fn words(self, count: usize, dimension: usize) -> usize {
+        match self {
+            Quantization::F32 => count * dimension,
+            Quantization::F16 => (count * dimension).div_ceil(2),
+            // scales, then the components
+            Quantization::Int8 => dimension + (count * dimension).div_ceil(4),
+        }
+    }
+
+    /// Encode the [dimension] vectors of [vectors], laid out one after the other
+    pub(crate) fn encode(self, vectors: &[f32], dimension: usize) -> Vec<u32> {
+        match self {
+            Quantization::F32 => vectors.iter().map(|x| x.to_bits()).collect(),
+            Quantization::F16 => vectors
+                .chunks(2)
+                .map(|c| {
+                    c.iter()
+                        .enumerate()
+                        .fold(0, |w, (k, x)| w | u32::from(f32_to_f16(*x)) << (16 * k))
+                })
+                .collect(),
+            Quantization::Int8 => {
+                let mut scales = vec![0.0f32; dimension];
+                for v in vectors.chunks(dimension) {
+                    for (scale, x) in scales.iter_mut().zip(v) {
+                        *scale = scale.max(x.abs());
+                    }
+                }
+                for scale in scales.iter_mut() {
+                    *scale /= 127.0;
+                }
+                let codes: Vec<u8> = vectors
+                    .iter()
+                    .enumerate()
+                    .map(|(k, x)| {
+                        let scale = scales[k % dimension];
+                        let code = if scale > 0.0 {
+                            (x / scale).round()
+                        } else {
+                            0.0
+                        };
+                        code.clamp(-127.0, 127.0) as i8 as u8
+                    })
+                    .collect();
+                let mut words: Vec<u32> = scales.iter().map(|x| x.to_bits()).collect();
+                words.extend(codes.chunks(4).map(|c| {
+                    c.iter()
+                        .enumerate()
+                        .fold(0, |w, (k, b)| w | u32::from(*b) << (8 * k))
+                }));
+                words
+            }
+        }
+    }
+}
+
+/// Vectors encoded by `Quantization::encode`
+pub(crate) struct Vectors<'a> {
+    pub quantization: Quantization,
+    pub dimension: usize,
+    pub words: &'a [u32],
+}
+
+impl<'a> Vectors<'a> {
+    /// Component [k] of the vectors laid out one after the other
+    fn component(&self, k: usize) -> f32 {
+        let words = self.words;
+        match self.quantization {
+            Quantization::F32 => f32::from_bits(words[k]),
+            Quantization::F16 => f16_to_f32((words[k / 2] >> (16 * (k % 2))) as u16),
+            Quantization::Int8 => {
+                let code = (words[self.dimension + k / 4] >> (8 * (k % 4))) as u8 as i8;
+                f32::from(code) * f32::from_bits(words[k % self.dimension])
+            }
+        }
+    }
+
+    fn components(&self, i: usize) -> impl Iterator<Item = f32> + '_ {
+        (i * self.dimension..(i + 1) * self.dimension).map(move |k| self.component(k))
+    }
+
+    /// Vector [i] dequantized
+    pub fn get(&self, i: usize) -> Vec<f32> {
+        self.components(i).collect()
+    }
+
+    /// Distance from [q] to vector [i], computed on its quantized components
+    pub fn between(&self, distance: Distance, q: &[f32], i: usize) -> f32 {
+        match self.quantization {
+            Quantization::F32 => {
+                let words = &self.words[i * self.dimension..(i + 1) * self.dimension];
+                let v =
+                    unsafe { slice::from_raw_parts(words.as_ptr() as *const f32, self.dimension) };
+                distance.between(q, v)
+            }
+            _ => distance.between_iter(q, self.components(i)),
+        }
+    }
+}
+
+/// Item vectors encoded with a quantization, built in memory or memory mapped from a file.
+///
+/// The file holds native endian words: a header, then the vectors encoded by
+/// `Quantization::encode`.
+pub struct QuantizedVectors {
+    storage: Storage,
+    dimension: usize,
+    quantization: Quantization,
+    count: usize,
+}
+
+impl QuantizedVectors {
+    /// Encode [vectors] of [dimension], laid out one after the other, with [quantization]
+    /// ```
+    /// use annoy_rs::quantization::{Quantization, QuantizedVectors};
+    /// let vectors = QuantizedVectors::new(&[1.0, 0.5, -1.0, 0.25], 2, Quantization::F16);
+    /// assert_eq!(vectors.len(), 2);
+    /// assert_eq!(vectors.get_item(1), Some(vec![-1.0, 0.25]));
+    /// ```
+    pub fn new(vectors: &[f32], dimension: i32, quantization: Quantization) -> QuantizedVectors {
+        let dimension = dimension as usize;
+        let count = vectors.len() / dimension.max(1);
+        let mut words = vec![
+            MAGIC,
+            VERSION,
+            dimension as u32,
+            count as u32,
+            quantization.to_word(),
+        ];
+        words.extend(quantization.encode(&vectors[..count * dimension], dimension));
+        QuantizedVectors {
+            storage: Storage::Owned(words),
+            dimension,
+            quantization,
+            count,
+        }
+    }
+
+    fn from_storage(storage: Storage, dimension: usize) -> Result<QuantizedVectors, Error> {
+        let (count, quantization) = {
+            let words = storage.words();
+            if words.len() < HEADER_WORDS || words[0] != MAGIC || words[1] != VERSION {
+                return Err(Error::InvalidIndex(
+                    "not a quantized vectors file".to_owned(),
+                ));
+            }
+            if words[2] as usize != dimension {
+                return Err(Error::InvalidIndex(format!(
+                    "quantized vectors of dimension {}, expected {}",
+                    words[2], dimension
+                )));
+            }
+            (words[3] as usize, Quantization::from_word(words[4])?)
+        };
+        let expected = count
+            .checked_mul(dimension)
+            .map(|_| HEADER_WORDS + quantization.words(count, dimension));
+        if expected != Some(storage.words().len()) {
+            return Err(Error::InvalidIndex(format!(
+                "quantized vectors file of {} words for {} vectors",
+                storage.words().len(),
+                count
+            )));
+        }
+        Ok(QuantizedVectors {
+            storage,
+            dimension,
+            quantization,
+            count,
+        })
+    }
+
+    /// Map the file at [path] saved with vectors of dimension [dimension]
+    pub fn load<P: AsRef<Path>>(path: P, dimension: i32) -> Result<QuantizedVectors, Error> {
+        QuantizedVectors::from_storage(Storage::map(path)?, dimension as usize)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.storage.save(path)
+    }
+
+    pub(crate) fn vectors(&self) -> Vectors<'_> {
+        Vectors {
+            quantization: self.quantization,
+            dimension: self.dimension,
+            words: &self.storage.words()[HEADER_WORDS..],
+        }
+    }
+
+    pub fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    pub fn dimension(&self) -> i32 {
+        self.dimension as i32
+    }
+
+    pub fn len(&self) -> i32 {
+        self.count as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Return the vector of [item], dequantized
+    pub fn get_item(&self, item: i32) -> Option<Vec<f32>> {
+        if item < 0 || item >= self.len() {
+            return None;
+        }
+        Some(self.vectors().get(item as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f16_test() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(0.0), 0);
+        // smallest subnormal
+        assert_eq!(f32_to_f16(5.960_464_5e-8), 1);
+        for x in &[1.0, -0.5, 0.333, 1234.5, 6.1e-5, 3.0e-7, 0.0] {
+            let y = f16_to_f32(f32_to_f16(*x));
+            assert!((x - y).abs() <= x.abs() / 1024.0 + 6e-8, "{} {}", x, y);
+        }
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    }
+
+    #[test]
+    fn encode_test() {
+        let vectors = vec![1.0, -0.5, 0.25, 0.0, 100.0, 3.0, -1.0, 0.5, -0.75];
+        for quantization in &[Quantization::F32, Quantization::F16, Quantization::Int8] {
+            let words = quantization.encode(&vectors, 3);
+            assert_eq!(words.len(), quantization.words(3, 3));
+            let encoded = Vectors {
+                quantization: *quantization,
+                dimension: 3,
+                words: &words,
+            };
+            for (i, v) in vectors.chunks(3).enumerate() {
+                let decoded = encoded.get(i);
+                for (x, y) in v.iter().zip(&decoded) {
+                    // int8 components are within half a step of the scale of their dimension
+                    assert!((x - y).abs() <= 100.0 / 254.0 + 1e-6, "{} {}", x, y);
+                }
+                let d = encoded.between(Distance::Euclidean, &[0.0, 0.0, 0.0], i);
+                assert_eq!(d, Distance::Euclidean.between(&[0.0, 0.0, 0.0], &decoded));
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_vectors_test() {
+        let vectors = vec![1.0, -0.5, 0.25, 0.0, 100.0, 3.0];
+        let path = std::env::temp_dir().join("annoy_quantized_vectors_test");
+        let quantized = QuantizedVectors::new(&vectors, 3, Quantization::Int8);
+        quantized.save(&path).unwrap();
+        assert!(QuantizedVectors::load(&path, 2).is_err());
+        let loaded = QuantizedVectors::load(&path, 3).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.quantization(), Quantization::Int8);
+        assert_eq!(loaded.get_item(1), quantized.get_item(1));
+        assert_eq!(loaded.get_item(2), None);
+
+        // truncated file
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(QuantizedVectors::load(&path, 3).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+/// Port of annoy's `Kiss64Random`, so that splits are chosen the same way as annoylib
+pub struct Kiss64Random {
+    x: u64,
+    y: u64,
+    z: u64,
+    c: u64,
+}
+
+impl Kiss64Random {
+    pub const DEFAULT_SEED: u64 = 1_234_567_890_987_654_321;
+
+    pub fn new(seed: u64) -> Kiss64Random {
+        Kiss64Random {
+            x: seed,
+            y: 362_436_362_436_362_436,
+            z: 1_066_149_217_761_810,
+            c: 123_456_123_456_123_456,
+        }
+    }
+
+    pub fn kiss(&mut self) -> u64 {
+        // Linear congruence generator
+        self.z = self.z.wrapping_mul(6_906_969_069).wrapping_add(1_234_567);
+
+        // Xor shift
+        self.y ^= self.y << 13;
+        self.y ^= self.y >> 17;
+        self.y ^= self.y << 43;
+
+        // Multiply-with-carry
+        let t = (self.x << 58).wrapping_add(self.c);
+        self.c = self.x >> 6;
+        self.x = self.x.wrapping_add(t);
+        if self.x < t {
+            self.c += 1;
+        }
+
+        self.x.wrapping_add(self.y).wrapping_add(self.z)
+    }
+
+    pub fn flip(&mut self) -> bool {
+        self.kiss() & 1 == 1
+    }
+
+    pub fn index(&mut self, n: usize) -> usize {
+        (self.kiss() % n as u64) as usize
+    }
+}
+
+impl Default for Kiss64Random {
+    fn default() -> Kiss64Random {
+        Kiss64Random::new(Kiss64Random::DEFAULT_SEED)
+    }
+}
@@ -0,0 +1,478 @@
+use distance::{self, Distance};
+use err::Error;
+use memmap::Mmap;
+use quantization::QuantizedVectors;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::path::Path;
+use std::slice;
+
+/// Read-only annoy index backed by a memory mapped file in the annoy 1.15.2 format.
+/// It does not need the C++ library and returns the same results as `annoy::AnnoyIndex`,
+/// unless the item vectors are read from quantized vectors, see [with_items].
+pub struct AnnoyReader {
+    dimension: i32,
+    distance: Distance,
+    mmap: Mmap,
+    node_size: usize,
+    node_count: i32,
+    max_descendants: i32,
+    roots: Vec<i32>,
+    item_count: i32,
+    /// Vectors read in place of the item nodes, None to read the f32 vectors of the file
+    items: Option<QuantizedVectors>,
+}
+
+/// Number of candidates fetched by the first batch of a radius search
+const RADIUS_FIRST_BATCH: i32 = 64;
+
+/// Search queue entry, ordered like `std::pair<T, S>` in annoylib
+#[derive(PartialEq)]
+struct Candidate(f32, i32);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.0
+            .partial_cmp(&other.0)
+            .unwrap_or(Ordering::Equal)
+            .then(self.1.cmp(&other.1))
+    }
+}
+
+impl AnnoyReader {
+    /// Map the index file at [path] built with vectors of dimension [dimension]
+    /// ```no_run
+    /// use annoy_rs::distance::Distance;
+    /// use annoy_rs::reader::AnnoyReader;
+    /// let index = AnnoyReader::load("test.tree", 3, Distance::Angular).unwrap();
+    /// let (results, distances) = index.get_nns_by_vector(&[1.0, 0.5, 0.5], 2, None).unwrap();
+    /// ```
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        dimension: i32,
+        distance: Distance,
+    ) -> Result<AnnoyReader, Error> {
+        distance.check_pure_support()?;
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let node_size = distance.node_size(dimension as usize);
+        if mmap.is_empty() || mmap.len() % node_size != 0 {
+            return Err(Error::InvalidIndex(format!(
+                "file size {} is not a multiple of node size {}",
+                mmap.len(),
+                node_size
+            )));
+        }
+
+        let mut reader = AnnoyReader {
+            dimension,
+            distance,
+            node_size,
+            node_count: (mmap.len() / node_size) as i32,
+            max_descendants: ((node_size - distance.children_offset()) / 4) as i32,
+            mmap,
+            roots: Vec::new(),
+            item_count: 0,
+            items: None,
+        };
+        reader.find_roots();
+        Ok(reader)
+    }
+
+    /// Roots are the last nodes of the file, sharing the highest descendant count.
+    /// When there is more than one tree, the first root is also followed by a copy of all roots.
+    fn find_roots(&mut self) {
+        let mut m = -1;
+        for i in (0..self.node_count).rev() {
+            let k = self.n_descendants(i);
+            if m == -1 || k == m {
+                self.roots.push(i);
+                m = k;
+            } else {
+                break;
+            }
+        }
+        if self.roots.len() > 1
+            && self.child(self.roots[0], 0) == self.child(self.roots[self.roots.len() - 1], 0)
+        {
+            self.roots.pop();
+        }
+        self.item_count = m;
+    }
+
+    /// Read the item vectors from [items] instead of the file, whose item nodes are then never
+    /// read, so that only the trees are paged in. Distances are computed on the quantized
+    /// components and [get_item] returns the dequantized vectors.
+    pub fn with_items(mut self, items: QuantizedVectors) -> Result<AnnoyReader, Error> {
+        if items.dimension() != self.dimension || items.len() != self.item_count {
+            return Err(Error::InvalidIndex(format!(
+                "{} quantized vectors of dimension {} for {} items of dimension {}",
+                items.len(),
+                items.dimension(),
+                self.item_count,
+                self.dimension
+            )));
+        }
+        self.items = Some(items);
+        Ok(self)
+    }
+
+    fn node(&self, i: i32) -> &[u8] {
+        let start = i as usize * self.node_size;
+        &self.mmap[start..start + self.node_size]
+    }
+
+    fn read_i32(&self, i: i32, offset: usize) -> i32 {
+        let node = self.node(i);
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&node[offset..offset + 4]);
+        i32::from_ne_bytes(buf)
+    }
+
+    fn read_f32(&self, i: i32, offset: usize) -> f32 {
+        f32::from_bits(self.read_i32(i, offset) as u32)
+    }
+
+    fn n_descendants(&self, i: i32) -> i32 {
+        self.read_i32(i, 0)
+    }
+
+    fn child(&self, i: i32, nr: usize) -> i32 {
+        self.read_i32(i, self.distance.children_offset() + 4 * nr)
+    }
+
+    fn children(&self, i: i32) -> Vec<i32> {
+        (0..self.n_descendants(i) as usize)
+            .map(|nr| self.child(i, nr))
+            .collect()
+    }
+
+    /// Squared norm stored in angular item nodes, 0 if absent
+    fn norm(&self, i: i32) -> f32 {
+        match self.distance {
+            Distance::Angular => self.read_f32(i, 4),
+            _ => 0.0,
+        }
+    }
+
+    /// Plane offset stored in Minkowski split nodes
+    fn offset(&self, i: i32) -> f32 {
+        match self.distance {
+            Distance::Angular => 0.0,
+            _ => self.read_f32(i, 4),
+        }
+    }
+
+    fn vector(&self, i: i32) -> &[f32] {
+        let node = self.node(i);
+        let v = &node[self.distance.vector_offset()..];
+        // nodes sizes are multiples of 4 and the mapping is page aligned
+        unsafe { slice::from_raw_parts(v.as_ptr() as *const f32, self.dimension as usize) }
+    }
+
+    /// Return the dimension of the vectors of the index
+    pub fn dimension(&self) -> i32 {
+        self.dimension
+    }
+
+    /// Return the distance function used to build the index
+    pub fn distance(&self) -> &Distance {
+        &self.distance
+    }
+
+    /// Return the number of trees in the index
+    pub fn tree_count(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn len(&self) -> i32 {
+        self.item_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.item_count == 0
+    }
+
+    pub fn get_item(&self, item: i32) -> Option<Vec<f32>> {
+        if item < 0 || item >= self.len() {
+            return None;
+        }
+        match self.items {
+            Some(ref items) => items.get_item(item),
+            None => Some(self.vector(item).to_vec()),
+        }
+    }
+
+    /// Return the [n] closer item to item index [item] searching [search_k] nodes
+    /// When using None for search_k it uses [n_trees * n]
+    pub fn get_nns_by_item(
+        &self,
+        item: i32,
+        n: i32,
+        search_k: Option<i32>,
+    ) -> (Vec<i32>, Vec<f32>) {
+        match self.items {
+            Some(ref items) => match items.get_item(item) {
+                Some(v) => self.get_all_nns(&v, n, search_k),
+                None => (Vec::new(), Vec::new()),
+            },
+            None if item < 0 || item >= self.len() => (Vec::new(), Vec::new()),
+            None => self.get_all_nns(self.vector(item), n, search_k),
+        }
+    }
+
+    /// Return the [n] closer item to vector [w] searching [search_k] nodes
+    /// When using None for search_k it uses [n_trees * n]
+    /// Fails with `Error::DimensionError` when [w] is not of the index dimension
+    pub fn get_nns_by_vector(
+        &self,
+        w: &[f32],
+        n: i32,
+        search_k: Option<i32>,
+    ) -> Result<(Vec<i32>, Vec<f32>), Error> {
+        if w.len() != self.dimension as usize {
+            return Err(Error::DimensionError(w.len(), self.dimension as usize));
+        }
+        Ok(self.get_all_nns(w, n, search_k))
+    }
+
+    /// Return the items within [max_distance] of vector [w], closest first, searching
+    /// [search_k] nodes for each batch of candidates. At most [max_results] items are
+    /// returned, the last element tells if more items were within the distance.
+    pub fn get_nns_within(
+        &self,
+        w: &[f32],
+        max_distance: f32,
+        max_results: i32,
+        search_k: Option<i32>,
+    ) -> Result<(Vec<i32>, Vec<f32>, bool), Error> {
+        let limit = max_results.max(0).saturating_add(1);
+        let mut n = limit.min(RADIUS_FIRST_BATCH);
+        let mut search_k = search_k;
+        loop {
+            let (mut results, mut distances) = self.get_nns_by_vector(w, n, search_k)?;
+            let count = distances.iter().take_while(|d| **d <= max_distance).count();
+            // a candidate beyond the distance or fewer candidates than asked: all were found
+            if count < results.len() || (results.len() as i32) < n {
+                results.truncate(count);
+                distances.truncate(count);
+                return Ok((results, distances, false));
+            }
+            if n >= limit {
+                results.truncate(max_results.max(0) as usize);
+                distances.truncate(max_results.max(0) as usize);
+                return Ok((results, distances, true));
+            }
+            n = n.saturating_mul(2).min(limit);
+            search_k = search_k.map(|k| k.saturating_mul(2));
+        }
+    }
+
+    /// Search from [v], which must be of the index dimension
+    fn get_all_nns(&self, v: &[f32], n: i32, search_k: Option<i32>) -> (Vec<i32>, Vec<f32>) {
+        let v_norm = match self.distance {
+            Distance::Angular => distance::dot(v, v),
+            _ => 0.0,
+        };
+        // annoylib takes n as size_t and compares against search_k as size_t,
+        // so negative values mean no limit
+        let n = if n < 0 { usize::MAX } else { n as usize };
+        let search_k = match search_k {
+            Some(k) if k >= 0 => k as usize,
+            Some(k) if k != -1 => usize::MAX,
+            _ => n.saturating_mul(self.roots.len()),
+        };
+
+        let mut queue = BinaryHeap::new();
+        for root in &self.roots {
+            queue.push(Candidate(f32::INFINITY, *root));
+        }
+
+        let mut nns: Vec<i32> = Vec::new();
+        while nns.len() < search_k {
+            let Candidate(d, i) = match queue.pop() {
+                Some(top) => top,
+                None => break,
+            };
+            // item nodes come first, and are not read when the vectors are quantized
+            if i < self.item_count && (self.items.is_some() || self.n_descendants(i) == 1) {
+                nns.push(i);
+                continue;
+            }
+            let n_descendants = self.n_descendants(i);
+            if n_descendants <= self.max_descendants {
+                nns.extend(self.children(i));
+            } else {
+                let margin = self.distance.margin(self.vector(i), self.offset(i), v);
+                queue.push(Candidate(d.min(margin), self.child(i, 1)));
+                queue.push(Candidate(d.min(-margin), self.child(i, 0)));
+            }
+        }
+
+        nns.sort();
+        nns.dedup();
+        let mut nns_dist: Vec<Candidate> = match self.items {
+            Some(ref items) => {
+                let vectors = items.vectors();
+                nns.into_iter()
+                    .filter(|j| *j < self.item_count)
+                    .map(|j| Candidate(vectors.between(self.distance, v, j as usize), j))
+                    .collect()
+            }
+            None => nns
+                .into_iter()
+                .filter(|j| self.n_descendants(*j) == 1)
+                .map(|j| {
+                    let d = self
+                        .distance
+                        .distance(self.vector(j), self.norm(j), v, v_norm);
+                    Candidate(d, j)
+                })
+                .collect(),
+        };
+        nns_dist.sort();
+        nns_dist.truncate(n);
+
+        // quantized distances are already normalized
+        let normalized = self.items.is_some();
+        nns_dist
+            .into_iter()
+            .map(|Candidate(d, j)| {
+                let d = if normalized {
+                    d
+                } else {
+                    self.distance.normalized_distance(d)
+                };
+                (j, d)
+            })
+            .unzip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_test_tree() {
+        let index = AnnoyReader::load("test.tree", 3, Distance::Angular).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get_item(1), Some(vec![0.0, 1.0, 0.0]));
+        assert_eq!(index.get_item(3), None);
+
+        let (results, distances) = index.get_nns_by_item(0, 3, None);
+        assert_eq!(results[0], 0);
+        assert_eq!(distances[0], 0.0);
+
+        let (results, _) = index.get_nns_by_vector(&[0.0, 1.0, 0.0], 1, None).unwrap();
+        assert_eq!(results, vec![1]);
+        assert!(index.get_nns_by_vector(&[0.0, 1.0], 1, None).is_err());
+        assert!(index
+            .get_nns_by_vector(&[0.0, 1.0, 0.0, 0.0], 1, None)
+            .is_err());
+    }
+
+    #[test]
+    fn quantized_items() {
+        use quantization::Quantization;
+
+        let load = || AnnoyReader::load("test.tree", 3, Distance::Angular).unwrap();
+        let index = load();
+        let vectors: Vec<f32> = (0..3).flat_map(|i| index.get_item(i).unwrap()).collect();
+        let short = QuantizedVectors::new(&vectors[..6], 3, Quantization::F16);
+        assert!(load().with_items(short).is_err());
+
+        let items = QuantizedVectors::new(&vectors, 3, Quantization::F16);
+        let quantized = load().with_items(items).unwrap();
+        assert_eq!(quantized.get_item(1), index.get_item(1));
+        assert_eq!(quantized.get_item(3), None);
+        let (ids, distances) = quantized
+            .get_nns_by_vector(&[0.1, 1.0, 0.0], 3, None)
+            .unwrap();
+        let (expected_ids, expected_distances) =
+            index.get_nns_by_vector(&[0.1, 1.0, 0.0], 3, None).unwrap();
+        assert_eq!(ids, expected_ids);
+        for (d, expected) in distances.iter().zip(expected_distances) {
+            assert!((d - expected).abs() < 1e-3, "{} {}", d, expected);
+        }
+        let (ids, _, truncated) = quantized
+            .get_nns_within(&[0.0, 1.0, 0.0], 0.1, 10, None)
+            .unwrap();
+        assert_eq!(ids, vec![1]);
+        assert!(!truncated);
+    }
+
+    #[cfg(feature = "native")]
+    mod native {
+        use super::super::*;
+        use annoy::AnnoyIndexBuilder;
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        fn assert_same_results(left: &(Vec<i32>, Vec<f32>), right: &(Vec<i32>, Vec<f32>)) {
+            assert_eq!(left.0, right.0);
+            let left_bits: Vec<u32> = left.1.iter().map(|d| d.to_bits()).collect();
+            let right_bits: Vec<u32> = right.1.iter().map(|d| d.to_bits()).collect();
+            assert_eq!(left_bits, right_bits);
+        }
+
+        fn compare_with_native(distance: Distance) {
+            const F: usize = 20;
+            let n = 1000;
+            let mut rng = thread_rng();
+            let mut builder = AnnoyIndexBuilder::new(F as i32, distance);
+            for _i in 0..n {
+                let v: Vec<f32> = rng.sample_iter(&Standard).take(F).collect();
+                builder.add_item(v.as_slice());
+            }
+            let index = builder.build(Some(10));
+            let path = std::env::temp_dir().join(format!("annoy_reader_{:?}.tree", distance));
+            index.save2(&path, false).unwrap();
+
+            let reader = AnnoyReader::load(&path, F as i32, distance).unwrap();
+            assert_eq!(reader.len(), index.len());
+            assert_eq!(reader.tree_count(), 10);
+
+            for _i in 0..100 {
+                let item = rng.gen_range(0, n);
+                assert_eq!(reader.get_item(item), index.get_item(item));
+                assert_same_results(
+                    &reader.get_nns_by_item(item, 10, None),
+                    &index.get_nns_by_item(item, 10, None),
+                );
+
+                let v: Vec<f32> = rng.sample_iter(&Standard).take(F).collect();
+                assert_same_results(
+                    &reader.get_nns_by_vector(&v, 10, Some(100)).unwrap(),
+                    &index.get_nns_by_vector(&v, 10, Some(100)),
+                );
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn same_results_as_native_angular() {
+            compare_with_native(Distance::Angular);
+        }
+
+        #[test]
+        fn same_results_as_native_euclidean() {
+            compare_with_native(Distance::Euclidean);
+        }
+
+        #[test]
+        fn same_results_as_native_manhattan() {
+            compare_with_native(Distance::Manhattan);
+        }
+    }
+}
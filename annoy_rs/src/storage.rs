@@ -0,0 +1,84 @@
+use err::Error;
+use memmap::Mmap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::slice;
+
+/// Native endian words of an index built in memory or memory mapped from a file
+pub enum Storage {
+    Owned(Vec<u32>),
+    Mapped(Mmap),
+}
+
+impl Storage {
+    /// Map the file at [path], whose size must be a multiple of 4
+    pub fn map<P: AsRef<Path>>(path: P) -> Result<Storage, Error> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() % 4 != 0 {
+            return Err(Error::InvalidIndex(format!(
+                "file size {} is not a multiple of 4",
+                mmap.len()
+            )));
+        }
+        Ok(Storage::Mapped(mmap))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_replacing(path, |w| {
+            for word in self.words() {
+                w.write_all(&word.to_ne_bytes())?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn words(&self) -> &[u32] {
+        match self {
+            Storage::Owned(words) => words,
+            // the mapping is page aligned and its size was checked to be a multiple of 4
+            Storage::Mapped(mmap) => unsafe {
+                slice::from_raw_parts(mmap.as_ptr() as *const u32, mmap.len() / 4)
+            },
+        }
+    }
+
+    /// [len] floats stored from word [start]
+    pub fn floats(&self, start: usize, len: usize) -> &[f32] {
+        let words = &self.words()[start..start + len];
+        unsafe { slice::from_raw_parts(words.as_ptr() as *const f32, len) }
+    }
+
+    /// [len] bytes stored from word [start]
+    pub fn bytes(&self, start: usize, len: usize) -> &[u8] {
+        let words = &self.words()[start..start + len.div_ceil(4)];
+        unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, len) }
+    }
+}
+
+/// Write the file at [path] with [write] into a temporary file of the same directory, then
+/// rename it over [path], so that the mappings of the previous file stay valid
+pub fn write_replacing<P, F>(path: P, write: F) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let path = path.as_ref();
+    let mut name = path.file_name().ok_or(Error::InvalidPath)?.to_owned();
+    name.push(".tmp");
+    let tmp_path = path.with_file_name(name);
+    let written = File::create(&tmp_path)
+        .map_err(Error::from)
+        .and_then(|file| {
+            let mut w = BufWriter::new(file);
+            write(&mut w)?;
+            w.flush()?;
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&tmp_path, path).map_err(Error::from));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    written
+}
@@ -3,6 +3,76 @@
 
 typedef ::AnnoyIndexInterface<int32_t, float> *annoy_ptr_t;
 
+// Hamming indexes store u64-packed vectors. Like the HammingWrapper of annoymodule.cc,
+// this exposes them through the float interface: components greater than 0.5 are set bits.
+class HammingWrapper : public ::AnnoyIndexInterface<int32_t, float>
+{
+  private:
+    int32_t _f_external, _f_internal;
+    ::AnnoyIndex<int32_t, uint64_t, ::Hamming, ::Kiss64Random> _index;
+
+    void _pack(const float *src, uint64_t *dst)
+    {
+        for (int32_t i = 0; i < _f_internal; i++)
+        {
+            dst[i] = 0;
+            for (int32_t j = 0; j < 64 && i * 64 + j < _f_external; j++)
+            {
+                dst[i] |= (uint64_t)(src[i * 64 + j] > 0.5) << j;
+            }
+        }
+    }
+
+    void _unpack(const uint64_t *src, float *dst)
+    {
+        for (int32_t i = 0; i < _f_external; i++)
+        {
+            dst[i] = (src[i / 64] >> (i % 64)) & 1;
+        }
+    }
+
+  public:
+    HammingWrapper(int f) : _f_external(f), _f_internal((f + 63) / 64), _index((f + 63) / 64) {}
+
+    void add_item(int32_t item, const float *w)
+    {
+        vector<uint64_t> w_internal(_f_internal, 0);
+        _pack(w, &w_internal[0]);
+        _index.add_item(item, &w_internal[0]);
+    }
+    void build(int q) { _index.build(q); }
+    void unbuild() { _index.unbuild(); }
+    bool save(const char *filename, bool prefault) { return _index.save(filename, prefault); }
+    void unload() { _index.unload(); }
+    bool load(const char *filename, bool prefault) { return _index.load(filename, prefault); }
+    float get_distance(int32_t i, int32_t j) { return _index.get_distance(i, j); }
+    void get_nns_by_item(int32_t item, size_t n, size_t search_k, vector<int32_t> *result, vector<float> *distances)
+    {
+        vector<uint64_t> distances_internal;
+        _index.get_nns_by_item(item, n, search_k, result, &distances_internal);
+        distances->insert(distances->end(), distances_internal.begin(), distances_internal.end());
+    }
+    void get_nns_by_vector(const float *w, size_t n, size_t search_k, vector<int32_t> *result, vector<float> *distances)
+    {
+        vector<uint64_t> w_internal(_f_internal, 0);
+        _pack(w, &w_internal[0]);
+        vector<uint64_t> distances_internal;
+        _index.get_nns_by_vector(&w_internal[0], n, search_k, result, &distances_internal);
+        distances->insert(distances->end(), distances_internal.begin(), distances_internal.end());
+    }
+    int32_t get_n_items() { return _index.get_n_items(); }
+    int32_t get_n_trees() { return _index.get_n_trees(); }
+    void verbose(bool v) { _index.verbose(v); }
+    void get_item(int32_t item, float *v)
+    {
+        vector<uint64_t> v_internal(_f_internal, 0);
+        _index.get_item(item, &v_internal[0]);
+        _unpack(&v_internal[0], v);
+    }
+    void set_seed(int q) { _index.set_seed(q); }
+    bool on_disk_build(const char *filename) { return _index.on_disk_build(filename); }
+};
+
 rust_annoy_index_t rust_annoy_index_angular_init(int f)
 {
     rust_annoy_index_t ptr = new ::AnnoyIndex<int32_t, float, ::Angular, ::Kiss64Random>(f);
@@ -18,6 +88,16 @@ rust_annoy_index_t rust_annoy_index_manhattan_init(int f)
     rust_annoy_index_t ptr = new ::AnnoyIndex<int32_t, float, ::Manhattan, ::Kiss64Random>(f);
     return ptr;
 }
+rust_annoy_index_t rust_annoy_index_dot_product_init(int f)
+{
+    rust_annoy_index_t ptr = new ::AnnoyIndex<int32_t, float, ::DotProduct, ::Kiss64Random>(f);
+    return ptr;
+}
+rust_annoy_index_t rust_annoy_index_hamming_init(int f)
+{
+    annoy_ptr_t ptr = new HammingWrapper(f);
+    return ptr;
+}
 struct f_vector
 {
     vector<float> *vec;
@@ -98,6 +178,12 @@ void rust_annoy_index_build(rust_annoy_index_t self, int q)
     typed_ptr->build(q);
 }
 
+bool rust_annoy_index_on_disk_build(rust_annoy_index_t self, const char *filename)
+{
+    annoy_ptr_t typed_ptr = cast(self);
+    return typed_ptr->on_disk_build(filename);
+}
+
 bool rust_annoy_index_save(rust_annoy_index_t self, const char *filename, bool prefault)
 {
     annoy_ptr_t typed_ptr = cast(self);
@@ -134,6 +220,12 @@ void rust_annoy_index_verbose(rust_annoy_index_t self, bool v)
     typed_ptr->verbose(v);
 }
 
+void rust_annoy_index_set_seed(rust_annoy_index_t self, uint64_t seed)
+{
+    annoy_ptr_t typed_ptr = cast(self);
+    typed_ptr->set_seed(seed);
+}
+
 void rust_annoy_index_get_nns_by_item(rust_annoy_index_t self, int item, int n, int search_k, i_vector *result, f_vector *distances)
 {
     annoy_ptr_t typed_ptr = cast(self);
@@ -25,10 +25,13 @@ EXTERNC typedef void *rust_annoy_index_t;
 EXTERNC rust_annoy_index_t rust_annoy_index_angular_init(int f);
 EXTERNC rust_annoy_index_t rust_annoy_index_euclidian_init(int f);
 EXTERNC rust_annoy_index_t rust_annoy_index_manhattan_init(int f);
+EXTERNC rust_annoy_index_t rust_annoy_index_dot_product_init(int f);
+EXTERNC rust_annoy_index_t rust_annoy_index_hamming_init(int f);
 
 EXTERNC void rust_annoy_index_destroy(rust_annoy_index_t self);
 EXTERNC void rust_annoy_index_add_item(rust_annoy_index_t self, int item, const float *w);
 EXTERNC void rust_annoy_index_build(rust_annoy_index_t self, int q);
+EXTERNC bool rust_annoy_index_on_disk_build(rust_annoy_index_t self, const char *filename);
 EXTERNC bool rust_annoy_index_save(rust_annoy_index_t self, const char *filename, bool prefault);
 EXTERNC void rust_annoy_index_unload(rust_annoy_index_t self);
 EXTERNC bool rust_annoy_index_load(rust_annoy_index_t self, const char *filename, bool prefault);
@@ -39,4 +42,5 @@ EXTERNC void rust_annoy_index_get_nns_by_item(rust_annoy_index_t self, int item,
 EXTERNC void rust_annoy_index_get_nns_by_vector(rust_annoy_index_t self, const float *w, int n, int search_k, i_vector *result, f_vector *distances);
 EXTERNC int rust_annoy_index_get_n_item(rust_annoy_index_t self);
 EXTERNC void rust_annoy_index_verbose(rust_annoy_index_t self, bool v);
+EXTERNC void rust_annoy_index_set_seed(rust_annoy_index_t self, uint64_t seed);
 EXTERNC void rust_annoy_index_get_item(rust_annoy_index_t self, int item, float *v);
@@ -1,7 +1,7 @@
 #!/bin/sh
 set +ev
 
-ANNOY_VERSION='1.14.0'
+ANNOY_VERSION='1.15.2'
 
 mkdir -p build
 (
@@ -0,0 +1,340 @@
+use annoy_rs::annoy::Distance;
+use annoy_rs::filter;
+use annoy_rs::idmapping::MappingIndexBuilder;
+use err::Error;
+use futures::{Future, Stream};
+use hyper::Body;
+use knn::{Knn, PreprocessingStep};
+use serde_json;
+use service::UpsertItem;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// First line of a build upload, followed by one `UpsertItem` per line
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct BuildRequest {
+    /// Name the index is loaded under, and directory it is saved to in the index root
+    pub index_name: String,
+    /// Dimension of the uploaded vectors, before preprocessing
+    pub dimension: i32,
+    /// One of angular, euclidean, manhattan, dot or hamming. Defaults to euclidean.
+    pub distance: Option<String>,
+    /// Number of trees, annoy's default when not set
+    pub n_tree: Option<i32>,
+    pub seed: Option<u64>,
+    /// Steps applied to the uploaded vectors and saved with the index, applied to its queries.
+    /// Their files are relative to the index root.
+    pub preprocessing: Vec<PreprocessingStep>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Uploading,
+    Building,
+    Loaded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JobStatus {
+    pub state: JobState,
+    /// Number of items uploaded
+    pub items: usize,
+    pub error: Option<String>,
+}
+
+struct Job {
+    status: JobStatus,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Index builds of a server by index name, the last one of each name being kept
+pub struct BuildJobs {
+    /// Directory the built indexes are saved to, one sub-directory per index
+    root: PathBuf,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl BuildJobs {
+    pub fn new(root: PathBuf) -> BuildJobs {
+        BuildJobs {
+            root,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register the build of [name], failing when one is already running
+    fn start(&self, name: &str) -> Result<Arc<AtomicBool>, Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(name) {
+            if job.status.state == JobState::Uploading || job.status.state == JobState::Building {
+                return Err(Error::BuildRunning(name.to_owned()));
+            }
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        jobs.insert(
+            name.to_owned(),
+            Job {
+                status: JobStatus {
+                    state: JobState::Uploading,
+                    items: 0,
+                    error: None,
+                },
+                cancelled: cancelled.clone(),
+            },
+        );
+        Ok(cancelled)
+    }
+
+    fn update<F: FnOnce(&mut JobStatus)>(&self, name: &str, f: F) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(name) {
+            f(&mut job.status);
+        }
+    }
+
+    /// Record the outcome of the build of [name], or the error it failed with
+    fn finish(&self, name: &str, result: Result<JobState, &Error>) {
+        self.update(name, |status| match result {
+            Ok(state) => status.state = state,
+            Err(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            }
+        });
+    }
+
+    /// Cancel the build of [name]. A running annoy build completes but is not loaded.
+    pub fn cancel(&self, name: &str) -> Result<JobStatus, Error> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(name).ok_or(Error::NotFound)?;
+        job.cancelled.store(true, Ordering::SeqCst);
+        Ok(job.status.clone())
+    }
+
+    pub fn statuses(&self) -> HashMap<String, JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, job)| (name.clone(), job.status.clone()))
+            .collect()
+    }
+}
+
+/// Items of a build being uploaded
+struct Upload {
+    builds: Arc<BuildJobs>,
+    /// Bytes after the last complete line
+    pending: Vec<u8>,
+    request: BuildRequest,
+    builder: Option<MappingIndexBuilder<i64>>,
+    cancelled: Arc<AtomicBool>,
+    items: usize,
+}
+
+impl Upload {
+    fn new(builds: Arc<BuildJobs>) -> Upload {
+        Upload {
+            builds,
+            pending: Vec::new(),
+            request: BuildRequest::default(),
+            builder: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            items: 0,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.pending.extend_from_slice(chunk);
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.line(&line)?;
+        }
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(Error::CancelledFuture);
+        }
+        self.builds
+            .update(&self.request.index_name, |status| status.items = self.items);
+        Ok(())
+    }
+
+    fn line(&mut self, line: &[u8]) -> Result<(), Error> {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(());
+        }
+        let builder = match self.builder {
+            Some(ref mut builder) => builder,
+            None => return self.start(serde_json::from_slice::<BuildRequest>(line)?),
+        };
+        let item = serde_json::from_slice::<UpsertItem>(line)?;
+        if item.vector.len() != self.request.dimension as usize {
+            return Err(Error::DimensionError(
+                item.vector.len(),
+                self.request.dimension as usize,
+            ));
+        }
+        let attributes = filter::parse_attributes(&item.attributes)?;
+        builder.put_with_attributes(item.id, &item.vector, attributes)?;
+        self.items += 1;
+        Ok(())
+    }
+
+    fn start(&mut self, request: BuildRequest) -> Result<(), Error> {
+        let name = &request.index_name;
+        // the name is a directory of the index root
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+            return Err(Error::ParsingError(format!("index name {:?}", name)));
+        }
+        if request.dimension <= 0 {
+            return Err(Error::ParsingError(format!(
+                "dimension {}",
+                request.dimension
+            )));
+        }
+        let distance = match request.distance {
+            Some(ref distance) => distance.parse::<Distance>()?,
+            None => Distance::Euclidean,
+        };
+        let preprocessing = Knn::read_preprocessing(&request.preprocessing, &self.builds.root)?;
+        let dimension = preprocessing.output_dimension(request.dimension as usize)?;
+        let mut builder = MappingIndexBuilder::new(name, dimension as i32, distance)
+            .with_preprocessing(preprocessing)?;
+        self.cancelled = self.builds.start(name)?;
+        if let Some(seed) = request.seed {
+            builder = builder.with_seed(seed);
+        }
+        self.builder = Some(builder);
+        self.request = request;
+        Ok(())
+    }
+
+    /// Build, save and load the uploaded index on a background thread
+    fn finish(mut self, knn: Knn) -> Result<JobStatus, Error> {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.line(&line)?;
+        }
+        let builder = self
+            .builder
+            .take()
+            .ok_or_else(|| Error::ParsingError("empty build upload".to_owned()))?;
+        let name = self.request.index_name.clone();
+        let n_tree = self.request.n_tree;
+        let path = self.builds.root.join(&name);
+        let cancelled = self.cancelled.clone();
+        let builds = self.builds.clone();
+        builds.update(&name, |status| {
+            status.state = JobState::Building;
+            status.items = self.items;
+        });
+        // index names do not start with a dot
+        let building = self.builds.root.join(format!(".{}.building", name));
+        thread::spawn(move || {
+            info!("Building index {} into {}", name, path.display());
+            let result: Result<JobState, Error> = (|| {
+                let index = builder.build(n_tree);
+                if cancelled.load(Ordering::SeqCst) {
+                    return Ok(JobState::Cancelled);
+                }
+                if building.exists() {
+                    fs::remove_dir_all(&building)?;
+                }
+                Knn::save(&index, &building)?;
+                if cancelled.load(Ordering::SeqCst) {
+                    return Ok(JobState::Cancelled);
+                }
+                replace_dir(&building, &path)?;
+                Knn::load(knn.index_write.clone(), &name, &path)?;
+                knn.invalidate(&name);
+                Ok(JobState::Loaded)
+            })();
+            if building.exists() {
+                let _ = fs::remove_dir_all(&building);
+            }
+            if let Err(ref e) = result {
+                error!("Build of index {} failed: {}", name, e);
+            }
+            builds.finish(&name, result.as_ref().map(|state| *state));
+        });
+        let status = self.builds.statuses().remove(&self.request.index_name);
+        status.ok_or(Error::NotFound)
+    }
+
+    /// Record the failure of the upload, or its cancellation
+    fn fail(&self, error: &Error) {
+        if self.builder.is_none() {
+            return;
+        }
+        let result = if self.cancelled.load(Ordering::SeqCst) {
+            Ok(JobState::Cancelled)
+        } else {
+            Err(error)
+        };
+        self.builds.finish(&self.request.index_name, result);
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // the upload stopped without error, e.g. the client disconnected
+        if self.builder.is_some() {
+            self.builds.update(&self.request.index_name, |status| {
+                if status.state == JobState::Uploading {
+                    status.state = JobState::Failed;
+                    status.error = Some("upload interrupted".to_owned());
+                }
+            });
+        }
+    }
+}
+
+/// Move the directory [from] to [to], removing the files of a previous index at [to] so that
+/// none of them is loaded with the new one. Those still mapped by the served index stay valid.
+fn replace_dir(from: &Path, to: &Path) -> Result<(), Error> {
+    if !to.exists() {
+        fs::rename(from, to)?;
+        return Ok(());
+    }
+    let replaced = from.with_extension("replaced");
+    if replaced.exists() {
+        fs::remove_dir_all(&replaced)?;
+    }
+    fs::rename(to, &replaced)?;
+    fs::rename(from, to)?;
+    fs::remove_dir_all(&replaced)?;
+    Ok(())
+}
+
+/// Stream the build upload [body] into a new index, built and loaded in the background.
+/// Return the status of the build once the upload completed.
+pub fn build(
+    body: Body,
+    knn: Knn,
+    builds: Arc<BuildJobs>,
+) -> impl Future<Item = JobStatus, Error = Error> {
+    body.map_err(Error::from)
+        .fold(Upload::new(builds), |mut upload, chunk| {
+            match upload.push(&chunk) {
+                Ok(()) => Ok(upload),
+                Err(e) => {
+                    upload.fail(&e);
+                    Err(e)
+                }
+            }
+        })
+        .and_then(move |upload| {
+            let builds = upload.builds.clone();
+            let name = upload.request.index_name.clone();
+            upload.finish(knn).map_err(|e| {
+                builds.finish(&name, Err(&e));
+                e
+            })
+        })
+}
@@ -0,0 +1,193 @@
+use knn::{SearchOptions, SearchResult};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Query of a cached search
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QueryKey {
+    Id(i64),
+    /// Hash of the vector quantised to 1e-4, close vectors share their results
+    Vector(u64),
+}
+
+impl QueryKey {
+    const QUANTIZATION: f32 = 1e4;
+
+    pub fn vector(vector: &[f32]) -> QueryKey {
+        let mut hasher = DefaultHasher::new();
+        for x in vector {
+            ((x * QueryKey::QUANTIZATION).round() as i64).hash(&mut hasher);
+        }
+        QueryKey::Vector(hasher.finish())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    index: String,
+    /// Version of the index, changed when it is reloaded
+    version: u64,
+    query: QueryKey,
+    n: i32,
+    search_k: i32,
+    options: u64,
+}
+
+/// Hash of the options changing the results of a search
+fn options_hash(options: &SearchOptions) -> u64 {
+    let mut exclude: Vec<&i64> = options.exclude.iter().collect();
+    exclude.sort();
+    let mut hasher = DefaultHasher::new();
+    format!(
+        "{:?}|{}|{:?}|{:?}|{:?}|{}|{}|{:?}|{:?}",
+        options.filter,
+        options.max_candidates,
+        exclude,
+        options.max_distance,
+        options.diversity,
+        options.diversity_candidates,
+        options.rerank_factor,
+        options.rerank_distance,
+        options.algorithm
+    )
+    .hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Default)]
+struct Entries {
+    results: HashMap<Key, (SearchResult, u64)>,
+    /// Keys by last use, the first one is evicted
+    uses: BTreeMap<u64, Key>,
+    clock: u64,
+    versions: HashMap<String, u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub size: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub hit_rate: f64,
+}
+
+/// Bounded LRU cache of search results
+pub struct QueryCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Cache lookup, holding the key to store the result of a miss
+pub struct Lookup {
+    key: Key,
+    pub result: Option<SearchResult>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> QueryCache {
+        QueryCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(
+        &self,
+        index: &str,
+        query: QueryKey,
+        n: i32,
+        search_k: i32,
+        options: &SearchOptions,
+    ) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+        let key = Key {
+            index: index.to_owned(),
+            version: entries.versions.get(index).cloned().unwrap_or(0),
+            query,
+            n,
+            search_k,
+            options: options_hash(options),
+        };
+        entries.clock += 1;
+        let clock = entries.clock;
+        let used = match entries.results.get_mut(&key) {
+            Some((result, used)) => Some((result.clone(), std::mem::replace(used, clock))),
+            None => None,
+        };
+        match used {
+            Some((result, used)) => {
+                entries.uses.remove(&used);
+                entries.uses.insert(clock, key.clone());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Lookup {
+                    key,
+                    result: Some(result),
+                }
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Lookup { key, result: None }
+            }
+        }
+    }
+
+    /// Store the [result] of a missed [lookup], evicting the least recently used entry when full
+    pub fn insert(&self, lookup: Lookup, result: SearchResult) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some((_, used)) = entries.results.insert(lookup.key.clone(), (result, clock)) {
+            entries.uses.remove(&used);
+        }
+        entries.uses.insert(clock, lookup.key);
+        while entries.results.len() > self.capacity {
+            let oldest = match entries.uses.keys().next() {
+                Some(used) => *used,
+                None => break,
+            };
+            if let Some(key) = entries.uses.remove(&oldest) {
+                entries.results.remove(&key);
+            }
+        }
+    }
+
+    /// Forget the results of [index], when it is reloaded
+    pub fn invalidate(&self, index: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        *entries.versions.entry(index.to_owned()).or_insert(0) += 1;
+        let stale: Vec<(Key, u64)> = entries
+            .results
+            .iter()
+            .filter(|(key, _)| key.index == index)
+            .map(|(key, (_, used))| (key.clone(), *used))
+            .collect();
+        for (key, used) in stale {
+            entries.results.remove(&key);
+            entries.uses.remove(&used);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            capacity: self.capacity,
+            size: self.entries.lock().unwrap().results.len(),
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
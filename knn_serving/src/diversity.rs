@@ -0,0 +1,84 @@
+use annoy_rs::idmapping;
+
+/// Cosine similarity of two item vectors, 0 when one of them is null
+fn similarity(x: &[f32], y: &[f32]) -> f32 {
+    let mut xy = 0.0;
+    let mut xx = 0.0;
+    let mut yy = 0.0;
+    for (a, b) in x.iter().zip(y) {
+        xy += a * b;
+        xx += a * a;
+        yy += b * b;
+    }
+    if xx > 0.0 && yy > 0.0 {
+        xy / (xx * yy).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Maximal marginal relevance re-rank of candidates [ids] sorted by increasing [distances].
+///
+/// Items are selected one at a time, maximizing
+/// `lambda * relevance - (1 - lambda) * max similarity to the selected items`,
+/// where relevance is the distance rescaled to [0, 1] and similarity the cosine of item vectors.
+/// A [lambda] of 1 keeps the original order. At most [n] items are returned when [n] is positive,
+/// with their original distances.
+pub fn mmr(
+    index: &idmapping::MappingIndex<i64>,
+    ids: Vec<i64>,
+    distances: Vec<f32>,
+    n: i32,
+    lambda: f32,
+) -> (Vec<i64>, Vec<f32>) {
+    let n = if n > 0 {
+        (n as usize).min(ids.len())
+    } else {
+        ids.len()
+    };
+    let (min, max) = match (distances.first(), distances.last()) {
+        (Some(min), Some(max)) => (*min, *max),
+        _ => return (ids, distances),
+    };
+    let relevance = |d: f32| {
+        if max > min {
+            1.0 - (d - min) / (max - min)
+        } else {
+            1.0
+        }
+    };
+
+    let mut candidates: Vec<(i64, f32, Vec<f32>)> = ids
+        .into_iter()
+        .zip(distances)
+        .map(|(id, d)| (id, d, index.get_item_vector(id).unwrap_or_default()))
+        .collect();
+    // highest similarity of each candidate to the selected items
+    let mut max_similarity = vec![f32::NEG_INFINITY; candidates.len()];
+    let mut selected_ids = Vec::with_capacity(n);
+    let mut selected_distances = Vec::with_capacity(n);
+    while selected_ids.len() < n {
+        let mut best = 0;
+        let mut best_score = f32::NEG_INFINITY;
+        for (i, (_, d, _)) in candidates.iter().enumerate() {
+            let penalty = if max_similarity[i].is_finite() {
+                max_similarity[i]
+            } else {
+                0.0
+            };
+            let score = lambda * relevance(*d) - (1.0 - lambda) * penalty;
+            if score > best_score {
+                best = i;
+                best_score = score;
+            }
+        }
+        let (id, d, vector) = candidates.remove(best);
+        max_similarity.remove(best);
+        for (i, (_, _, other)) in candidates.iter().enumerate() {
+            max_similarity[i] = max_similarity[i].max(similarity(&vector, other));
+        }
+        selected_ids.push(id);
+        selected_distances.push(d);
+    }
+    (selected_ids, selected_distances)
+}
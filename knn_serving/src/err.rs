@@ -4,7 +4,20 @@ use std::fmt;
 pub enum Error {
     NoIndexLoaded(String),
     SerializationError(capnp::Error),
+    /// Dimension of a vector, then of the index
     DimensionError(usize, usize),
+    /// Position of the first component of a vector that is NaN or infinite
+    NonFiniteComponent(usize),
+    /// Query of zero norm on an angular index
+    ZeroVector,
+    /// Result count out of bounds, e.g. negative
+    InvalidResultCount(i32),
+    /// Search k out of bounds, e.g. negative
+    InvalidSearchK(i32),
+    /// Number of candidates fetched to re-rank the results out of bounds
+    InvalidCandidateCount(i32),
+    /// Weight of a multi query search that is not positive and finite
+    InvalidWeight(f32),
     CancelledFuture,
     IoError(::std::io::Error),
     ParsingError(String),
@@ -14,6 +27,16 @@ pub enum Error {
     HttpError(hyper::http::Error),
     NotFound,
     JsonParsingError(serde_json::Error),
+    NoQuery,
+    IncompatibleIndexes(String, String),
+    ShardError(String),
+    /// Index reloaded while it was compacted
+    IndexReplaced(String),
+    BuildRunning(String),
+    BuildsDisabled,
+    Timeout,
+    /// Request shed, the server or the index having too many requests in flight
+    Overloaded,
 }
 
 impl From<::capnp::Error> for Error {
@@ -30,7 +53,14 @@ impl From<std::io::Error> for Error {
 
 impl From<annoy_rs::err::Error> for Error {
     fn from(err: annoy_rs::err::Error) -> Self {
-        Error::IndexError(err)
+        match err {
+            annoy_rs::err::Error::DimensionError(actual, expected) => {
+                Error::DimensionError(actual, expected)
+            }
+            annoy_rs::err::Error::NonFiniteComponent(i) => Error::NonFiniteComponent(i),
+            annoy_rs::err::Error::ZeroVector => Error::ZeroVector,
+            err => Error::IndexError(err),
+        }
     }
 }
 
@@ -56,11 +86,19 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::NoIndexLoaded(value) => write!(f, "No index loaded for {}", value),
-            Error::DimensionError(expected, result) => write!(
+            Error::DimensionError(actual, expected) => write!(
                 f,
                 "Dimension does not match expected {} got {}",
-                expected, result
+                expected, actual
             ),
+            Error::NonFiniteComponent(i) => {
+                write!(f, "Component {} of the query vector is not finite", i)
+            }
+            Error::ZeroVector => write!(f, "Query vector of zero norm on an angular index"),
+            Error::InvalidResultCount(value) => write!(f, "Invalid result count {}", value),
+            Error::InvalidSearchK(value) => write!(f, "Invalid search k {}", value),
+            Error::InvalidCandidateCount(value) => write!(f, "Invalid candidate count {}", value),
+            Error::InvalidWeight(value) => write!(f, "Invalid query weight {}", value),
             Error::CancelledFuture => write!(f, "Operation has been cancelled"),
             Error::IoError(io) => io.fmt(f),
             Error::ParsingError(value) => write!(f, "Error parsing {}", value),
@@ -71,6 +109,22 @@ impl std::fmt::Display for Error {
             Error::HyperError(err) => err.fmt(f),
             Error::JsonParsingError(err) => err.fmt(f),
             Error::NoProductVectorFound(value) => value.fmt(f),
+            Error::NoQuery => write!(f, "No positive query in the request"),
+            Error::IncompatibleIndexes(first, other) => write!(
+                f,
+                "Index {} does not have the dimension and distance of {}",
+                other, first
+            ),
+            Error::ShardError(value) => write!(f, "Shard error: {}", value),
+            Error::IndexReplaced(value) => {
+                write!(f, "Index {} was replaced during its compaction", value)
+            }
+            Error::BuildRunning(value) => write!(f, "Index {} is already being built", value),
+            Error::BuildsDisabled => {
+                write!(f, "Index builds are disabled, KNN_INDEX_ROOT is not set")
+            }
+            Error::Timeout => write!(f, "Timeout"),
+            Error::Overloaded => write!(f, "Too many requests in flight"),
         }
     }
 }
@@ -0,0 +1,192 @@
+use annoy_rs::idmapping;
+use capnp::message::{Builder, HeapAllocator};
+use err::Error;
+use futures::{future, Future};
+use knn::{Knn, KnnMapRead, SearchOptions};
+use knn_serving_api::service_capnp::{knn_request_fan_out, knn_response};
+use pool::WorkerPool;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// An index searched by a fan-out request
+struct Target {
+    name: String,
+    index: Arc<idmapping::MappingIndex<i64>>,
+    weight: f32,
+    offset: f32,
+}
+
+/// Item of the merged results
+struct Item {
+    id: i64,
+    distance: f32,
+    index: usize,
+}
+
+/// Match [name] against [pattern], where `*` matches any sequence of characters
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return false;
+    }
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Indexes listed by [request] or matching its pattern, all sharing a dimension and a distance
+fn read_targets(
+    map: &KnnMapRead,
+    request: knn_request_fan_out::Reader,
+) -> Result<Vec<Target>, Error> {
+    let mut targets = Vec::new();
+    for index in request.get_indexes()?.iter() {
+        let name = index.get_name()?;
+        // a zero or negative weight would flatten or flip the distances
+        let weight = index.get_weight();
+        if !weight.is_finite() || weight <= 0.0 {
+            return Err(Error::InvalidWeight(weight));
+        }
+        targets.push(Target {
+            name: name.to_owned(),
+            index: Knn::get_index2(map.clone(), name)?,
+            weight,
+            offset: index.get_offset(),
+        });
+    }
+    let pattern = request.get_index_pattern()?;
+    if !pattern.is_empty() {
+        let mut matching = Vec::new();
+        map.for_each(|name, indexes| {
+            if glob_matches(pattern, name) && !targets.iter().any(|t| &t.name == name) {
+                matching.push(Target {
+                    name: name.clone(),
+                    index: indexes[0].clone(),
+                    weight: 1.0,
+                    offset: 0.0,
+                });
+            }
+        });
+        matching.sort_by(|a, b| a.name.cmp(&b.name));
+        targets.extend(matching);
+    }
+
+    let first = match targets.first() {
+        Some(first) => first,
+        None => return Err(Error::NoIndexLoaded(pattern.to_owned())),
+    };
+    for target in &targets[1..] {
+        if target.index.dimension() != first.index.dimension()
+            || target.index.distance() != first.index.distance()
+        {
+            return Err(Error::IncompatibleIndexes(
+                first.name.clone(),
+                target.name.clone(),
+            ));
+        }
+    }
+    Ok(targets)
+}
+
+/// Search [targets] in parallel on [workers] and merge the [n] closest items by weighted
+/// distance, failing with `Error::Timeout` once [deadline] passed
+fn search(
+    targets: &[Target],
+    vector: Vec<f32>,
+    k: i32,
+    n: i32,
+    options: SearchOptions,
+    workers: &WorkerPool,
+    deadline: Instant,
+) -> impl Future<Item = Vec<Item>, Error = Error> {
+    let searches: Vec<_> = targets
+        .iter()
+        .map(|target| {
+            let index = target.index.clone();
+            let vector = vector.clone();
+            let options = options.clone();
+            workers.run(deadline, move || {
+                Knn::search(index, vector, k, n, options).wait()
+            })
+        })
+        .collect();
+    let scales: Vec<(f32, f32)> = targets.iter().map(|t| (t.weight, t.offset)).collect();
+    future::join_all(searches).map(move |results| {
+        let mut items = Vec::new();
+        for (i, (result, (weight, offset))) in results.into_iter().zip(scales).enumerate() {
+            for (id, d) in result.ids.into_iter().zip(result.distances) {
+                items.push(Item {
+                    id,
+                    distance: weight * d + offset,
+                    index: i,
+                });
+            }
+        }
+        items.sort_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.index.cmp(&b.index))
+        });
+        if n > 0 {
+            items.truncate(n as usize);
+        }
+        items
+    })
+}
+
+fn create_response(response_builder: knn_response::Builder, targets: &[Target], items: &[Item]) {
+    let mut list = response_builder.init_items(items.len() as u32);
+    for (i, element) in items.iter().enumerate() {
+        let mut item: knn_response::item::Builder = list.reborrow().get(i as u32);
+        item.set_id(element.id);
+        item.set_distance(element.distance);
+        item.set_index_name(&targets[element.index].name);
+    }
+}
+
+/// Search the indexes of [request] on [workers] before [deadline]
+pub fn search_fan_out(
+    map: KnnMapRead,
+    request: knn_request_fan_out::Reader,
+    workers: &WorkerPool,
+    deadline: Instant,
+) -> Box<dyn Future<Item = Builder<HeapAllocator>, Error = Error> + Send> {
+    let targets = match read_targets(&map, request) {
+        Ok(targets) => targets,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let vector: Vec<f32> = match request.get_vector() {
+        Ok(vector) => vector.iter().collect(),
+        Err(e) => return Box::new(future::err(Error::from(e))),
+    };
+    let options = match request
+        .get_filter()
+        .map_err(Error::from)
+        .and_then(|filter| SearchOptions::new(filter, request.get_max_candidates(), f32::INFINITY))
+    {
+        Ok(options) => options,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let k = request.get_search_k();
+    let n = request.get_result_count();
+    let res = search(&targets, vector, k, n, options, workers, deadline).map(move |items| {
+        let mut message = ::capnp::message::Builder::new_default();
+        create_response(
+            message.init_root::<knn_response::Builder>(),
+            &targets,
+            &items,
+        );
+        message
+    });
+    Box::new(res)
+}
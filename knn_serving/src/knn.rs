@@ -1,14 +1,267 @@
 use annoy_rs::annoy::Distance;
-use annoy_rs::idmapping;
+use annoy_rs::filter::{AttributeValue, Attributes, Filter};
+use annoy_rs::idmapping::{self, Algorithm};
+use annoy_rs::ivfpq::IvfPqBuilder;
+use annoy_rs::preprocessing::{self, Pipeline, Step};
+use annoy_rs::quantization::Quantization;
+use build::BuildJobs;
+use cache::{QueryCache, QueryKey};
 use capnp::message::{Builder, HeapAllocator};
+use diversity;
 use err::Error;
 use evmap::{ReadHandle, WriteHandle};
 use futures::{future, Future};
-use knn_serving_api::service_capnp::{knn_request, knn_request_by_id, knn_response};
+use knn_serving_api::service_capnp::{
+    attribute_value, filter, knn_request, knn_request_by_id, knn_response,
+};
+use limiter::{Limiter, Limits};
+use pool::{Timeouts, WorkerPool};
+use serde_json;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Optional `metadata.json` file of an index directory
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct IndexMetadata {
+    /// One of angular, euclidean, manhattan, dot or hamming. Defaults to euclidean.
+    pub distance: Option<String>,
+    /// Seed used to build the trees, if any
+    pub seed: Option<u64>,
+    /// Algorithm of the requests not choosing one, annoy, hnsw or ivfpq.
+    /// Defaults to annoy, or to the structure found when there is no annoy index.
+    pub algorithm: Option<String>,
+    /// Steps applied in order to the query vectors, the items having been indexed with them
+    pub preprocessing: Vec<PreprocessingStep>,
+}
+
+/// Step of the preprocessing of an index. Files hold little endian f32 and are relative to
+/// the index directory.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PreprocessingStep {
+    /// Divide by the euclidean norm
+    Normalize,
+    /// Subtract the mean vector of [file]
+    SubtractMean { file: String },
+    /// Multiply by the row major matrix of [file] with [dimension] rows, the dimension
+    /// of the projected vectors
+    Project { file: String, dimension: usize },
+}
+
+/// Per request search parameters besides the query, result count and search_k
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    pub filter: Option<Filter>,
+    /// Maximum number of candidates fetched to find enough items matching the filter
+    pub max_candidates: i32,
+    /// Ids removed from the results, more items are fetched to compensate
+    pub exclude: HashSet<i64>,
+    /// Return all items within this distance instead of the closest ones,
+    /// the result count is then a limit on the number of items
+    pub max_distance: Option<f32>,
+    /// Lambda of the maximal marginal relevance re-rank, None to keep the closest items
+    pub diversity: Option<f32>,
+    /// Number of candidates re-ranked for diversity
+    pub diversity_candidates: i32,
+    /// Candidates are fetched by multiples of the result count and scored exactly when above 1
+    pub rerank_factor: i32,
+    /// Distance used to score candidates on their original vectors instead of the index distance
+    pub rerank_distance: Option<Distance>,
+    /// Algorithm searched, None for the default one of the index
+    pub algorithm: Option<Algorithm>,
+    /// The query is already in the space of the indexed vectors, e.g. the vector of an item,
+    /// and is not preprocessed
+    pub preprocessed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub ids: Vec<i64>,
+    pub distances: Vec<f32>,
+    /// True when a radius search had more items within the distance than returned
+    pub truncated: bool,
+}
+
+impl SearchOptions {
+    const DEFAULT_MAX_CANDIDATES: i32 = 10_000;
+    const MAX_RADIUS_RESULTS: i32 = 10_000;
+    const MAX_RESULT_COUNT: i32 = 10_000;
+    /// Bound on search_k, searches with more candidates are better served by an exact scan
+    const MAX_SEARCH_K: i32 = 1_000_000;
+    /// Bound on the candidates fetched to filter or re-rank the results
+    const MAX_CANDIDATES: i32 = 10 * SearchOptions::MAX_RESULT_COUNT;
+    const DEFAULT_DIVERSITY_FACTOR: i32 = 4;
+
+    pub fn new(
+        filter: filter::Reader,
+        max_candidates: i32,
+        max_distance: f32,
+    ) -> Result<SearchOptions, Error> {
+        if max_candidates > SearchOptions::MAX_CANDIDATES {
+            return Err(Error::InvalidCandidateCount(max_candidates));
+        }
+        Ok(SearchOptions {
+            filter: read_filter(filter)?,
+            max_candidates: if max_candidates > 0 {
+                max_candidates
+            } else {
+                SearchOptions::DEFAULT_MAX_CANDIDATES
+            },
+            exclude: HashSet::new(),
+            max_distance: if max_distance.is_finite() {
+                Some(max_distance)
+            } else {
+                None
+            },
+            diversity: None,
+            diversity_candidates: 0,
+            rerank_factor: 0,
+            rerank_distance: None,
+            algorithm: None,
+            preprocessed: false,
+        })
+    }
+
+    /// Re-rank [candidates] items with a diversity [lambda] below 1,
+    /// using a multiple of the result count when [candidates] is 0
+    pub fn with_diversity(mut self, lambda: f32, candidates: i32) -> SearchOptions {
+        if lambda < 1.0 {
+            self.diversity = Some(lambda.max(0.0));
+            self.diversity_candidates = candidates;
+        }
+        self
+    }
+
+    /// Fetch [factor] times more candidates and score them exactly, with [distance]
+    /// on the original vectors unless empty
+    pub fn with_rerank(mut self, factor: i32, distance: &str) -> Result<SearchOptions, Error> {
+        self.rerank_factor = factor;
+        if !distance.is_empty() {
+            self.rerank_distance = Some(distance.parse::<Distance>()?);
+        }
+        Ok(self)
+    }
+
+    fn reranked(&self) -> bool {
+        self.rerank_factor > 1 || self.rerank_distance.is_some()
+    }
+
+    /// Number of items kept before the diversity re-rank for a search of [n] items
+    fn diversity_candidates(&self, n: i32) -> i32 {
+        match self.diversity {
+            Some(_) if self.diversity_candidates > 0 => self.diversity_candidates.max(n),
+            Some(_) => n.saturating_mul(SearchOptions::DEFAULT_DIVERSITY_FACTOR),
+            None => n,
+        }
+    }
+
+    /// Number of items to fetch for a search of [n] items
+    fn candidates(&self, n: i32) -> i32 {
+        let n = self.diversity_candidates(n);
+        if self.rerank_factor > 1 {
+            n.saturating_mul(self.rerank_factor)
+        } else {
+            n
+        }
+    }
+
+    fn from_request(request: knn_request::Reader) -> Result<SearchOptions, Error> {
+        let mut options = SearchOptions::new(
+            request.get_filter()?,
+            request.get_max_candidates(),
+            request.get_max_distance(),
+        )?
+        .with_diversity(
+            request.get_diversity_lambda(),
+            request.get_diversity_candidates(),
+        )
+        .with_rerank(request.get_rerank_factor(), request.get_rerank_distance()?)?;
+        options.algorithm = match request.get_algorithm().map_err(capnp::Error::from)? {
+            knn_request::Algorithm::IndexDefault => None,
+            knn_request::Algorithm::Annoy => Some(Algorithm::Annoy),
+            knn_request::Algorithm::Hnsw => Some(Algorithm::Hnsw),
+            knn_request::Algorithm::Ivfpq => Some(Algorithm::IvfPq),
+        };
+        Ok(options)
+    }
+
+    fn from_request_by_id(request: knn_request_by_id::Reader) -> Result<SearchOptions, Error> {
+        let mut options = SearchOptions::new(
+            request.get_filter()?,
+            request.get_max_candidates(),
+            request.get_max_distance(),
+        )?
+        .with_diversity(
+            request.get_diversity_lambda(),
+            request.get_diversity_candidates(),
+        )
+        .with_rerank(request.get_rerank_factor(), request.get_rerank_distance()?)?;
+        options.algorithm = match request.get_algorithm().map_err(capnp::Error::from)? {
+            knn_request_by_id::Algorithm::IndexDefault => None,
+            knn_request_by_id::Algorithm::Annoy => Some(Algorithm::Annoy),
+            knn_request_by_id::Algorithm::Hnsw => Some(Algorithm::Hnsw),
+            knn_request_by_id::Algorithm::Ivfpq => Some(Algorithm::IvfPq),
+        };
+        options.exclude = request.get_exclude_ids()?.iter().collect();
+        options.preprocessed = true;
+        if !request.get_include_product() {
+            options.exclude.insert(request.get_product_id());
+        }
+        Ok(options)
+    }
+}
+
+fn read_filter(reader: filter::Reader) -> Result<Option<Filter>, Error> {
+    let filter = match reader.which().map_err(capnp::Error::from)? {
+        filter::Which::All(()) => return Ok(None),
+        filter::Which::Equals(equals) => Filter::Equals(
+            equals.get_key()?.to_owned(),
+            read_attribute_value(equals.get_value()?)?,
+        ),
+        filter::Which::OneOf(one_of) => {
+            let mut values = Vec::new();
+            for value in one_of.get_values()?.iter() {
+                values.push(read_attribute_value(value)?);
+            }
+            Filter::OneOf(one_of.get_key()?.to_owned(), values)
+        }
+        filter::Which::Range(range) => Filter::Range {
+            key: range.get_key()?.to_owned(),
+            min: range.get_min(),
+            max: range.get_max(),
+        },
+        filter::Which::And(filters) => Filter::And(read_filters(filters?)?),
+        filter::Which::Or(filters) => Filter::Or(read_filters(filters?)?),
+        filter::Which::Not(filter) => {
+            // not all matches nothing, like not and([])
+            let filter = read_filter(filter?)?.unwrap_or_else(|| Filter::And(Vec::new()));
+            Filter::Not(Box::new(filter))
+        }
+    };
+    Ok(Some(filter))
+}
+
+fn read_filters(readers: capnp::struct_list::Reader<filter::Owned>) -> Result<Vec<Filter>, Error> {
+    let mut filters = Vec::with_capacity(readers.len() as usize);
+    for reader in readers.iter() {
+        filters.push(read_filter(reader)?.unwrap_or_else(|| Filter::And(Vec::new())));
+    }
+    Ok(filters)
+}
+
+fn read_attribute_value(reader: attribute_value::Reader) -> Result<AttributeValue, Error> {
+    Ok(match reader.which().map_err(capnp::Error::from)? {
+        attribute_value::Which::Text(text) => AttributeValue::Text(text?.to_owned()),
+        attribute_value::Which::Number(number) => AttributeValue::Number(number),
+        attribute_value::Which::Boolean(boolean) => AttributeValue::Boolean(boolean),
+    })
+}
 
 pub type KnnMapRead = ReadHandle<String, Arc<idmapping::MappingIndex<i64>>>;
 pub type KnnMapWrite = Arc<Mutex<WriteHandle<String, Arc<idmapping::MappingIndex<i64>>>>>;
@@ -17,6 +270,17 @@ pub type KnnMapWrite = Arc<Mutex<WriteHandle<String, Arc<idmapping::MappingIndex
 pub struct Knn {
     pub index_read: KnnMapRead,
     pub index_write: KnnMapWrite,
+    pub cache: Option<Arc<QueryCache>>,
+    /// Index builds of the server, None when no index root was set
+    pub builds: Option<Arc<BuildJobs>>,
+    /// Held by upserts, deletions and compaction swaps, so that no update is lost
+    /// on an index being replaced
+    update_lock: Arc<Mutex<()>>,
+    /// Threads running the searches
+    pub workers: Arc<WorkerPool>,
+    pub timeouts: Timeouts,
+    /// Admits the searches, shedding them when overloaded
+    pub limiter: Arc<Limiter>,
 }
 
 impl Knn {
@@ -25,12 +289,59 @@ impl Knn {
         Knn {
             index_read: r,
             index_write: Arc::new(Mutex::new(w)),
+            cache: None,
+            builds: None,
+            update_lock: Arc::new(Mutex::new(())),
+            workers: Arc::new(WorkerPool::new(num_cpus::get())),
+            timeouts: Timeouts::default(),
+            limiter: Arc::new(Limiter::new(Limits::new(num_cpus::get()))),
+        }
+    }
+
+    /// Run the searches on [threads] threads
+    pub fn with_workers(mut self, threads: usize) -> Knn {
+        self.workers = Arc::new(WorkerPool::new(threads));
+        self
+    }
+
+    /// Give requests [default] to complete unless they ask for another timeout, up to [max]
+    pub fn with_timeouts(mut self, default: Duration, max: Duration) -> Knn {
+        self.timeouts = Timeouts::new(default, max);
+        self
+    }
+
+    /// Search at most as many requests at once as [limits] allow, queueing or shedding the others
+    pub fn with_limits(mut self, limits: Limits) -> Knn {
+        self.limiter = Arc::new(Limiter::new(limits));
+        self
+    }
+
+    /// Cache the results of up to [capacity] searches
+    pub fn with_cache(mut self, capacity: usize) -> Knn {
+        if capacity > 0 {
+            self.cache = Some(Arc::new(QueryCache::new(capacity)));
         }
+        self
+    }
+
+    /// Build indexes uploaded to the server into sub-directories of [root]
+    pub fn with_builds<P: AsRef<Path>>(mut self, root: P) -> Knn {
+        self.builds = Some(Arc::new(BuildJobs::new(root.as_ref().to_owned())));
+        self
     }
 
     const INDEX_FILE_NAME: &'static str = "index";
     const MAPPING_FILE_NAME: &'static str = "mapping";
     const DIMENSION_FILE_NAME: &'static str = "dimension";
+    const METADATA_FILE_NAME: &'static str = "metadata.json";
+    const ATTRIBUTES_FILE_NAME: &'static str = "attributes";
+    const VECTORS_FILE_NAME: &'static str = "vectors";
+    const NEIGHBOURS_FILE_NAME: &'static str = "neighbours";
+    const HNSW_FILE_NAME: &'static str = "hnsw";
+    const IVFPQ_FILE_NAME: &'static str = "ivfpq";
+    const QUANTIZED_FILE_NAME: &'static str = "quantized";
+    const TOMBSTONES_FILE_NAME: &'static str = "tombstones";
+    const PREPROCESSING_FILE_NAME: &'static str = "preprocessing";
 
     fn read_dimension_file<P: AsRef<Path>>(path: P) -> Result<i32, Error> {
         let path = path.as_ref();
@@ -40,52 +351,675 @@ impl Knn {
         Ok(buf.parse::<i32>().map_err(|_| Error::ParsingError(buf))?)
     }
 
-    pub fn load<P: AsRef<Path>>(
-        index_write: KnnMapWrite,
-        name: &str,
+    fn read_metadata_file<P: AsRef<Path>>(path: P) -> Result<IndexMetadata, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(IndexMetadata::default());
+        }
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Read the pipeline of [steps], their files being relative to [directory]
+    pub fn read_preprocessing(
+        steps: &[PreprocessingStep],
+        directory: &Path,
+    ) -> Result<Pipeline, Error> {
+        let mut pipeline = Pipeline::new();
+        for step in steps {
+            let step = match step {
+                PreprocessingStep::Normalize => Step::Normalize,
+                PreprocessingStep::SubtractMean { file } => {
+                    Step::SubtractMean(preprocessing::read_floats(directory.join(file))?)
+                }
+                PreprocessingStep::Project { file, dimension } => Step::project(
+                    *dimension,
+                    preprocessing::read_floats(directory.join(file))?,
+                )?,
+            };
+            pipeline = pipeline.with_step(step);
+        }
+        Ok(pipeline)
+    }
+
+    /// Write the files of [pipeline] to the index directory [path] and return its steps
+    fn save_preprocessing(
+        pipeline: &Pipeline,
+        path: &Path,
+    ) -> Result<Vec<PreprocessingStep>, Error> {
+        let mut steps = Vec::new();
+        for (i, step) in pipeline.steps().iter().enumerate() {
+            let file = format!("{}_{}", Knn::PREPROCESSING_FILE_NAME, i);
+            let step = match step {
+                Step::Normalize => PreprocessingStep::Normalize,
+                Step::SubtractMean(mean) => {
+                    preprocessing::write_floats(path.join(&file), mean)?;
+                    PreprocessingStep::SubtractMean { file }
+                }
+                Step::Project { rows, matrix, .. } => {
+                    preprocessing::write_floats(path.join(&file), matrix)?;
+                    PreprocessingStep::Project {
+                        file,
+                        dimension: *rows,
+                    }
+                }
+            };
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    /// Write [index] to the directory [path] with the layout expected by [load].
+    /// An index built on disk must already be at `path/index`.
+    pub fn save<P: AsRef<Path>>(
+        index: &idmapping::MappingIndex<i64>,
         path: P,
     ) -> Result<(), Error> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        if index.has_annoy() {
+            index.save_index(path.join(Knn::INDEX_FILE_NAME))?;
+        }
+        index.save_mapping(path.join(Knn::MAPPING_FILE_NAME))?;
+        if index.has_attributes() {
+            index.save_attributes(path.join(Knn::ATTRIBUTES_FILE_NAME))?;
+        }
+        if index.has_hnsw() {
+            index.save_hnsw(path.join(Knn::HNSW_FILE_NAME))?;
+        }
+        if index.has_ivfpq() {
+            index.save_ivfpq(path.join(Knn::IVFPQ_FILE_NAME))?;
+        }
+        let tombstones_path = path.join(Knn::TOMBSTONES_FILE_NAME);
+        if index.deleted_count() > 0 {
+            index.save_tombstones(tombstones_path)?;
+        } else if tombstones_path.exists() {
+            std::fs::remove_file(tombstones_path)?;
+        }
+        std::fs::write(
+            path.join(Knn::DIMENSION_FILE_NAME),
+            index.dimension().to_string(),
+        )?;
+
+        let metadata = IndexMetadata {
+            distance: Some(index.distance().to_string()),
+            seed: index.seed(),
+            algorithm: Some(index.algorithm().to_string()),
+            preprocessing: Knn::save_preprocessing(index.preprocessing(), path)?,
+        };
+        let file = File::create(path.join(Knn::METADATA_FILE_NAME))?;
+        serde_json::to_writer_pretty(file, &metadata)?;
+        Ok(())
+    }
+
+    /// Read the index directory [path] under the name [name]
+    pub fn read_index<P: AsRef<Path>>(
+        name: &str,
+        path: P,
+    ) -> Result<idmapping::MappingIndex<i64>, Error> {
         let path = path.as_ref().to_owned();
         let dimension = Knn::read_dimension_file(path.clone().join(Knn::DIMENSION_FILE_NAME))?;
+        let metadata = Knn::read_metadata_file(path.clone().join(Knn::METADATA_FILE_NAME))?;
+        let distance = match metadata.distance {
+            Some(ref distance) => distance.parse::<Distance>()?,
+            None => Distance::Euclidean,
+        };
 
-        info!("Loading index {} from {}", name, path.display());
-
-        let index = idmapping::MappingIndex::load(
+        info!(
+            "Loading index {} from {} with {:?} distance, built with seed {:?}",
             name,
-            path.clone().join(Knn::INDEX_FILE_NAME),
-            path.clone().join(Knn::MAPPING_FILE_NAME),
-            dimension,
-            Distance::Euclidean,
-            true,
-        )?;
+            path.display(),
+            distance,
+            metadata.seed
+        );
+
+        let index_path = path.join(Knn::INDEX_FILE_NAME);
+        let quantized_path = path.join(Knn::QUANTIZED_FILE_NAME);
+        let mut index = if index_path.exists() && !quantized_path.exists() {
+            idmapping::MappingIndex::load(
+                name,
+                index_path.clone(),
+                path.clone().join(Knn::MAPPING_FILE_NAME),
+                dimension,
+                distance,
+                true,
+            )?
+        } else {
+            // compressed and quantized indexes are served without the full vectors of annoy
+            idmapping::MappingIndex::load_mapping(
+                name,
+                path.join(Knn::MAPPING_FILE_NAME),
+                dimension,
+                distance,
+            )?
+        };
+        if quantized_path.exists() {
+            index.load_quantized(index_path, quantized_path)?;
+        }
+        let attributes_path = path.join(Knn::ATTRIBUTES_FILE_NAME);
+        if attributes_path.exists() {
+            index.load_attributes(attributes_path)?;
+        }
+        let vectors_path = path.join(Knn::VECTORS_FILE_NAME);
+        if vectors_path.exists() {
+            index.load_vectors(vectors_path)?;
+        }
+        let neighbours_path = path.join(Knn::NEIGHBOURS_FILE_NAME);
+        if neighbours_path.exists() {
+            index.load_neighbours(neighbours_path)?;
+        }
+        let hnsw_path = path.join(Knn::HNSW_FILE_NAME);
+        if hnsw_path.exists() {
+            index.load_hnsw(hnsw_path)?;
+        }
+        let ivfpq_path = path.join(Knn::IVFPQ_FILE_NAME);
+        if ivfpq_path.exists() {
+            index.load_ivfpq(ivfpq_path)?;
+        }
+        index.load_tombstones(path.join(Knn::TOMBSTONES_FILE_NAME))?;
+        let algorithm = match metadata.algorithm {
+            Some(ref algorithm) => Some(algorithm.parse::<Algorithm>()?),
+            None if !index.has_annoy() && index.has_ivfpq() => Some(Algorithm::IvfPq),
+            None if !index.has_annoy() && index.has_hnsw() => Some(Algorithm::Hnsw),
+            None => None,
+        };
+        if let Some(algorithm) = algorithm {
+            index.set_algorithm(algorithm)?;
+        }
+        if !metadata.preprocessing.is_empty() {
+            index.set_preprocessing(Knn::read_preprocessing(&metadata.preprocessing, &path)?)?;
+        }
+        Ok(index)
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        index_write: KnnMapWrite,
+        name: &str,
+        path: P,
+    ) -> Result<(), Error> {
+        let index = Knn::read_index(name, path)?;
         info!(
             "Index {} with {} items was loaded succesfully",
             name,
             index.len()
         );
+        // replaces the index previously loaded under this name
         index_write
             .lock()
             .unwrap()
-            .insert(name.to_owned(), Arc::new(index));
+            .update(name.to_owned(), Arc::new(index));
         index_write.lock().unwrap().refresh();
         Ok(())
     }
 
-    pub fn search(
-        index: Arc<idmapping::MappingIndex<i64>>,
+    /// Delete [ids] from the index [name] until it is compacted or reloaded from a rebuilt
+    /// directory, returning the number of ids newly deleted
+    pub fn delete(&self, name: &str, ids: &[i64]) -> Result<usize, Error> {
+        let _lock = self.update_lock.lock().unwrap();
+        let index = self.get_index(name)?;
+        let deleted = index.delete(ids)?;
+        info!(
+            "Deleted {} items of index {}, {:.2}% of its items are deleted",
+            deleted,
+            name,
+            100.0 * index.tombstone_ratio()
+        );
+        self.invalidate(name);
+        Ok(deleted)
+    }
+
+    /// Add or replace [items] of the index [name], searched exactly until it is compacted
+    pub fn upsert(&self, name: &str, items: Vec<(i64, Vec<f32>, Attributes)>) -> Result<(), Error> {
+        let _lock = self.update_lock.lock().unwrap();
+        let index = self.get_index(name)?;
+        // preprocessed first so that a request is applied entirely or not at all
+        let mut preprocessed = Vec::with_capacity(items.len());
+        for (id, vector, attributes) in items {
+            preprocessed.push((id, index.preprocess(&vector)?, attributes));
+        }
+        for (id, vector, attributes) in preprocessed {
+            index.upsert(id, &vector, attributes)?;
+        }
+        debug!(
+            "Index {} has {} upserted items",
+            name,
+            index.upserted_count()
+        );
+        self.invalidate(name);
+        Ok(())
+    }
+
+    /// Build the index [name] with its upserts and without its deleted items into the directory
+    /// [path] on a background thread, then replace it with the new index
+    pub fn compact<P: AsRef<Path>>(
+        &self,
+        name: &str,
+        path: P,
+        n_tree: Option<i32>,
+    ) -> Result<(), Error> {
+        let index = self.get_index(name)?;
+        let knn = self.clone();
+        let name = name.to_owned();
+        let path = path.as_ref().to_owned();
+        thread::spawn(move || {
+            if let Err(e) = knn.swap_compacted(&name, &index, &path, n_tree) {
+                error!("Compaction of index {} failed: {}", name, e);
+            }
+        });
+        Ok(())
+    }
+
+    fn swap_compacted(
+        &self,
+        name: &str,
+        index: &Arc<idmapping::MappingIndex<i64>>,
+        path: &Path,
+        n_tree: Option<i32>,
+    ) -> Result<(), Error> {
+        info!(
+            "Compacting index {} with {} upserted and {} deleted items into {}",
+            name,
+            index.upserted_count(),
+            index.deleted_count(),
+            path.display()
+        );
+        let (mut compacted, version) = index.compact(n_tree)?;
+        Knn::save(&compacted, path)?;
+        compacted.load_tombstones(path.join(Knn::TOMBSTONES_FILE_NAME))?;
+
+        let _lock = self.update_lock.lock().unwrap();
+        if !Arc::ptr_eq(index, &self.get_index(name)?) {
+            return Err(Error::IndexReplaced(name.to_owned()));
+        }
+        // updates made during the build
+        index.replay(version, &compacted)?;
+        info!(
+            "Index {} was compacted to {} items, {} updates were replayed",
+            name,
+            compacted.len(),
+            index.version() - version
+        );
+        let mut index_write = self.index_write.lock().unwrap();
+        index_write.update(name.to_owned(), Arc::new(compacted));
+        index_write.refresh();
+        self.invalidate(name);
+        Ok(())
+    }
+
+    /// Drop the cached results of the index [name]
+    pub fn invalidate(&self, name: &str) {
+        if let Some(ref cache) = self.cache {
+            cache.invalidate(name);
+        }
+    }
+
+    /// Fail when [vector] has a component that is not finite
+    pub fn check_finite(vector: &[f32]) -> Result<(), Error> {
+        match vector.iter().position(|x| !x.is_finite()) {
+            Some(i) => Err(Error::NonFiniteComponent(i)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the bounds of [k] and [n] and return [vector] in the space of the indexed vectors,
+    /// failing when it cannot be searched
+    fn query_vector(
+        index: &idmapping::MappingIndex<i64>,
         vector: Vec<f32>,
         k: i32,
         n: i32,
-    ) -> impl Future<Item = (std::vec::Vec<i64>, std::vec::Vec<f32>), Error = Error> {
-        debug!("New request: @{}, for {} item", k, n);
+        options: &SearchOptions,
+    ) -> Result<Vec<f32>, Error> {
+        // radius searches return every item within the distance when n is 0
+        let min_n = if options.max_distance.is_some() { 0 } else { 1 };
+        if n < min_n || n > SearchOptions::MAX_RESULT_COUNT {
+            return Err(Error::InvalidResultCount(n));
+        }
+        if k < 0 || k > SearchOptions::MAX_SEARCH_K {
+            return Err(Error::InvalidSearchK(k));
+        }
+        let candidates = options.candidates(n);
+        if candidates > SearchOptions::MAX_CANDIDATES {
+            return Err(Error::InvalidCandidateCount(candidates));
+        }
+        Knn::check_finite(&vector)?;
+        let vector = if options.preprocessed {
+            vector
+        } else {
+            index.preprocess(&vector)?
+        };
         if vector.len() != index.dimension() as usize {
-            return future::err(Error::DimensionError(
+            return Err(Error::DimensionError(
                 vector.len(),
                 index.dimension() as usize,
             ));
         }
-        let res = index.get_nns_by_vector(vector.as_slice(), n, Some(k));
-        future::ok(res)
+        index.distance().check_vector(&vector)?;
+        Ok(vector)
+    }
+
+    pub fn search(
+        index: Arc<idmapping::MappingIndex<i64>>,
+        vector: Vec<f32>,
+        k: i32,
+        n: i32,
+        options: SearchOptions,
+    ) -> impl Future<Item = SearchResult, Error = Error> {
+        debug!("New request: @{}, for {} item", k, n);
+        let vector = match Knn::query_vector(&index, vector, k, n, &options) {
+            Ok(vector) => vector,
+            Err(e) => return future::err(e),
+        };
+        let search_k = if k > 0 { Some(k) } else { None };
+        let dot_product = *index.distance() == Distance::DotProduct;
+        let algorithm = options.algorithm.unwrap_or_else(|| index.algorithm());
+        let wanted = options.candidates(n);
+        // at most all excluded ids are part of the results
+        let fetched = wanted.saturating_add(options.exclude.len() as i32);
+        let found = match (options.max_distance, &options.filter) {
+            (Some(max_distance), filter) => {
+                // distances of dot product indexes are opposite of inner products
+                let threshold = if dot_product {
+                    -max_distance
+                } else {
+                    max_distance
+                };
+                let max_results = if n > 0 {
+                    n.min(SearchOptions::MAX_RADIUS_RESULTS)
+                } else {
+                    SearchOptions::MAX_RADIUS_RESULTS
+                }
+                .saturating_add(options.exclude.len() as i32);
+                // the filter is applied to up to max_candidates items within the distance,
+                // before keeping max_results of them
+                let fetched = match filter {
+                    Some(_) => options.max_candidates.max(max_results),
+                    None => max_results,
+                };
+                index
+                    .get_nns_within_using(
+                        algorithm,
+                        vector.as_slice(),
+                        threshold,
+                        fetched,
+                        search_k,
+                    )
+                    .map(|(ids, distances, truncated)| match filter {
+                        Some(filter) => {
+                            let (mut ids, mut distances): (Vec<i64>, Vec<f32>) = ids
+                                .into_iter()
+                                .zip(distances)
+                                .filter(|(id, _)| index.matches(*id, filter))
+                                .unzip();
+                            let truncated = truncated || ids.len() > max_results as usize;
+                            ids.truncate(max_results as usize);
+                            distances.truncate(max_results as usize);
+                            (ids, distances, truncated)
+                        }
+                        None => (ids, distances, truncated),
+                    })
+            }
+            (None, Some(filter)) => index
+                .get_nns_by_vector_filtered_using(
+                    algorithm,
+                    vector.as_slice(),
+                    fetched,
+                    search_k,
+                    filter,
+                    options.max_candidates.max(fetched),
+                )
+                .map(|(ids, distances)| (ids, distances, false)),
+            (None, None) => index
+                .get_nns_by_vector_using(algorithm, vector.as_slice(), fetched, search_k)
+                .map(|(ids, distances)| (ids, distances, false)),
+        };
+        let (mut ids, mut distances, truncated) = match found {
+            Ok(found) => found,
+            Err(e) => return future::err(Error::from(e)),
+        };
+        if !options.exclude.is_empty() {
+            let (kept_ids, kept_distances) = ids
+                .into_iter()
+                .zip(distances)
+                .filter(|(id, _)| !options.exclude.contains(id))
+                .take(if wanted > 0 {
+                    wanted as usize
+                } else {
+                    usize::MAX
+                })
+                .unzip();
+            ids = kept_ids;
+            distances = kept_distances;
+        }
+        if dot_product {
+            // annoy returns inner products, responses are sorted by increasing distance
+            for d in distances.iter_mut() {
+                *d = -*d;
+            }
+        }
+        if options.reranked() {
+            let (reranked_ids, reranked_distances) = Knn::rerank(
+                &index,
+                &vector,
+                ids,
+                options.rerank_distance,
+                options.diversity_candidates(n),
+            );
+            ids = reranked_ids;
+            distances = reranked_distances;
+        }
+        if let Some(lambda) = options.diversity {
+            let (diverse_ids, diverse_distances) =
+                diversity::mmr(&index, ids, distances, n, lambda);
+            ids = diverse_ids;
+            distances = diverse_distances;
+        }
+        future::ok(SearchResult {
+            ids,
+            distances,
+            truncated,
+        })
+    }
+
+    /// Read the index directory [path] like [read_index], also mapping the f32 vectors of its
+    /// annoy index when its searches read quantized vectors, for the tools encoding the items
+    /// or measuring the recall against them
+    fn read_f32_index(name: &str, path: &Path) -> Result<idmapping::MappingIndex<i64>, Error> {
+        let index = Knn::read_index(name, path)?;
+        if index.has_quantized() {
+            index.load_index(path.join(Knn::INDEX_FILE_NAME))?;
+        }
+        Ok(index)
+    }
+
+    /// Precompute the [k] closest items of every item of the index directory [path]
+    /// on [threads] threads, searched by id when enough neighbours are requested
+    pub fn save_neighbours<P: AsRef<Path>>(path: P, k: i32, threads: usize) -> Result<(), Error> {
+        let path = path.as_ref();
+        let index = Arc::new(Knn::read_index("neighbours", path)?);
+        info!("Computing {} neighbours of {} items", k, index.len());
+        idmapping::MappingIndex::save_neighbours(
+            &index,
+            path.join(Knn::NEIGHBOURS_FILE_NAME),
+            k,
+            None,
+            threads,
+        )?;
+        Ok(())
+    }
+
+    /// Build the HNSW graph of the index directory [path] with [m] neighbours per item and layer
+    /// and [ef_construction] candidates per insertion, storing vectors with [quantization],
+    /// searched when requested or set in metadata
+    pub fn save_hnsw<P: AsRef<Path>>(
+        path: P,
+        m: usize,
+        ef_construction: usize,
+        quantization: Quantization,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut index = Knn::read_f32_index("hnsw", path)?;
+        info!(
+            "Building the {} hnsw graph of {} items",
+            quantization,
+            index.len()
+        );
+        index.build_hnsw(m, ef_construction, quantization);
+        index.save_hnsw(path.join(Knn::HNSW_FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Build the IVF-PQ codes of the index directory [path] with [lists] coarse centroids and
+    /// [subquantizers] bytes per item, trained on [sample_size] items. The original vectors
+    /// are encoded when the directory has a vectors file, and are then used for exact re-ranking.
+    pub fn save_ivfpq<P: AsRef<Path>>(
+        path: P,
+        lists: usize,
+        subquantizers: usize,
+        sample_size: usize,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut index = Knn::read_f32_index("ivfpq", path)?;
+        info!(
+            "Encoding {} items in {} lists of {} bytes codes",
+            index.len(),
+            lists,
+            subquantizers
+        );
+        let builder = IvfPqBuilder::new(index.dimension(), *index.distance())
+            .with_lists(lists)
+            .with_subquantizers(subquantizers);
+        index.build_ivfpq(builder, sample_size)?;
+        index.save_ivfpq(path.join(Knn::IVFPQ_FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Encode the f32 vectors of the annoy index of the directory [path] with [quantization],
+    /// annoy searches then reading them in place of the item nodes of the index file
+    pub fn save_quantized<P: AsRef<Path>>(
+        path: P,
+        quantization: Quantization,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let index = Knn::read_f32_index("quantized", path)?;
+        info!("Encoding {} items as {}", index.len(), quantization);
+        index.save_quantized(path.join(Knn::QUANTIZED_FILE_NAME), quantization)?;
+        Ok(())
+    }
+
+    /// Measure the recall@[n] of [algorithm] with [search_k] on the index directory [path]
+    /// against an exact search over its f32 vectors, with [queries] of its items
+    pub fn recall<P: AsRef<Path>>(
+        path: P,
+        algorithm: Algorithm,
+        n: usize,
+        queries: usize,
+        search_k: Option<i32>,
+    ) -> Result<f32, Error> {
+        let index = Knn::read_f32_index("recall", path.as_ref())?;
+        info!(
+            "Measuring the {} recall@{} on {} items",
+            algorithm,
+            n,
+            index.len()
+        );
+        Ok(index.recall(algorithm, n, queries, search_k)?)
+    }
+
+    /// Answer a search by [id] from its precomputed neighbours, when there are enough of them
+    /// and no option requires a live search. The neighbours are computed with the default algorithm.
+    fn search_neighbours(
+        index: &idmapping::MappingIndex<i64>,
+        id: i64,
+        n: i32,
+        options: &SearchOptions,
+    ) -> Option<SearchResult> {
+        // invalid counts are rejected by the live search
+        if n <= 0
+            || options.filter.is_some()
+            || (options.algorithm.is_some() && options.algorithm != Some(index.algorithm()))
+            || options.max_distance.is_some()
+            || options.diversity.is_some()
+            || options.reranked()
+        {
+            return None;
+        }
+        let fetched = n.saturating_add(options.exclude.len() as i32);
+        let (ids, distances) = index.get_neighbours(id, fetched)?;
+        let dot_product = *index.distance() == Distance::DotProduct;
+        let (ids, distances) = ids
+            .into_iter()
+            .zip(distances)
+            .filter(|(id, _)| !options.exclude.contains(id))
+            .take(n as usize)
+            .map(|(id, d)| (id, if dot_product { -d } else { d }))
+            .unzip();
+        Some(SearchResult {
+            ids,
+            distances,
+            truncated: false,
+        })
+    }
+
+    /// Search through [cache] when enabled, [name] and [query] identifying the index and query
+    pub fn cached_search(
+        cache: Option<Arc<QueryCache>>,
+        name: &str,
+        query: QueryKey,
+        index: Arc<idmapping::MappingIndex<i64>>,
+        vector: Vec<f32>,
+        k: i32,
+        n: i32,
+        options: SearchOptions,
+    ) -> Box<dyn Future<Item = SearchResult, Error = Error> + Send> {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return Box::new(Knn::search(index, vector, k, n, options)),
+        };
+        let mut lookup = cache.get(name, query, n, k, &options);
+        if let Some(result) = lookup.result.take() {
+            return Box::new(future::ok(result));
+        }
+        let res = Knn::search(index, vector, k, n, options).map(move |result| {
+            cache.insert(lookup, result.clone());
+            result
+        });
+        Box::new(res)
+    }
+
+    /// Score the candidates [ids] exactly against [vector], with [distance] on their original
+    /// vectors or the index distance on the indexed vectors, and keep the [n] closest ones
+    fn rerank(
+        index: &idmapping::MappingIndex<i64>,
+        vector: &[f32],
+        ids: Vec<i64>,
+        distance: Option<Distance>,
+        n: i32,
+    ) -> (Vec<i64>, Vec<f32>) {
+        let mut scored: Vec<(i64, f32)> = ids
+            .into_iter()
+            .filter_map(|id| {
+                let (item, distance) = match distance {
+                    Some(distance) => (index.get_original_vector(id)?, distance),
+                    None => (index.get_item_vector(id)?, *index.distance()),
+                };
+                let d = distance.between(vector, &item);
+                // lower is closer in responses
+                Some((
+                    id,
+                    if distance == Distance::DotProduct {
+                        -d
+                    } else {
+                        d
+                    },
+                ))
+            })
+            .collect();
+        scored.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        if n > 0 {
+            scored.truncate(n as usize);
+        }
+        scored.into_iter().unzip()
     }
 
     pub fn get_index<'a>(&self, name: &'a str) -> Result<Arc<idmapping::MappingIndex<i64>>, Error> {
@@ -106,10 +1040,12 @@ impl Knn {
 
     pub fn create_response_from_vectors(
         index: &Arc<idmapping::MappingIndex<i64>>,
-        response_builder: knn_response::Builder,
+        mut response_builder: knn_response::Builder,
         ids: &[i64],
         distances: &[f32],
+        truncated: bool,
     ) -> Result<(), Error> {
+        response_builder.set_truncated(truncated);
         let mut list: capnp::struct_list::Builder<knn_response::item::Owned> =
             response_builder.init_items(ids.len() as u32);
         for (i, elements) in ids.iter().enumerate() {
@@ -131,31 +1067,49 @@ impl Knn {
     pub fn search2(
         index: Arc<idmapping::MappingIndex<i64>>,
         request: knn_request::Reader,
+        cache: Option<Arc<QueryCache>>,
     ) -> Box<dyn Future<Item = Builder<HeapAllocator>, Error = Error> + Send> {
-        let v: Vec<f32> = request.get_vector().unwrap().iter().collect();
+        let v: Vec<f32> = match request.get_vector() {
+            Ok(vector) => vector.iter().collect(),
+            Err(e) => return Box::new(future::err(Error::from(e))),
+        };
         let k = request.get_search_k();
         let n = request.get_result_count();
+        let name = match request.get_index_name() {
+            Ok(name) => name,
+            Err(e) => return Box::new(future::err(Error::from(e))),
+        };
+        let options = match SearchOptions::from_request(request) {
+            Ok(options) => options,
+            Err(e) => return Box::new(future::err(e)),
+        };
         let index_copy = index.clone();
-        let res = Knn::search(index, v, k, n).and_then(move |(r, d)| {
-            let mut message = ::capnp::message::Builder::new_default();
-            {
-                let response: knn_response::Builder =
-                    message.init_root::<knn_serving_api::service_capnp::knn_response::Builder>();
-                Knn::create_response_from_vectors(
-                    &index_copy,
-                    response,
-                    r.as_slice(),
-                    d.as_slice(),
-                )?;
-            }
-            Ok(message)
-        });
+        let query = QueryKey::vector(&v);
+        let res = Knn::cached_search(cache, name, query, index, v, k, n, options).and_then(
+            move |result| {
+                let mut message = ::capnp::message::Builder::new_default();
+                {
+                    let response: knn_response::Builder =
+                        message
+                            .init_root::<knn_serving_api::service_capnp::knn_response::Builder>();
+                    Knn::create_response_from_vectors(
+                        &index_copy,
+                        response,
+                        result.ids.as_slice(),
+                        result.distances.as_slice(),
+                        result.truncated,
+                    )?;
+                }
+                Ok(message)
+            },
+        );
         Box::new(res)
     }
 
     pub fn search_id(
         index: Arc<idmapping::MappingIndex<i64>>,
         request: knn_request_by_id::Reader,
+        cache: Option<Arc<QueryCache>>,
     ) -> Box<dyn Future<Item = Builder<HeapAllocator>, Error = Error> + Send> {
         let pid = request.get_product_id();
         let v = Knn::get_vector(&index, pid);
@@ -165,8 +1119,21 @@ impl Knn {
         let v = v.unwrap();
         let k = request.get_search_k();
         let n = request.get_result_count();
+        let name = match request.get_index_name() {
+            Ok(name) => name,
+            Err(e) => return Box::new(future::err(Error::from(e))),
+        };
+        let options = match SearchOptions::from_request_by_id(request) {
+            Ok(options) => options,
+            Err(e) => return Box::new(future::err(e)),
+        };
         let index_copy = index.clone();
-        let res = Knn::search(index, v, k, n).and_then(move |(r, d)| {
+        let search: Box<dyn Future<Item = SearchResult, Error = Error> + Send> =
+            match Knn::search_neighbours(&index, pid, n, &options) {
+                Some(result) => Box::new(future::ok(result)),
+                None => Knn::cached_search(cache, name, QueryKey::Id(pid), index, v, k, n, options),
+            };
+        let res = search.and_then(move |result| {
             let mut message = ::capnp::message::Builder::new_default();
             {
                 let response: knn_response::Builder =
@@ -174,8 +1141,9 @@ impl Knn {
                 Knn::create_response_from_vectors(
                     &index_copy,
                     response,
-                    r.as_slice(),
-                    d.as_slice(),
+                    result.ids.as_slice(),
+                    result.distances.as_slice(),
+                    result.truncated,
                 )?;
             }
             Ok(message)
@@ -183,3 +1151,51 @@ impl Knn {
         Box::new(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_test() {
+        let mut builder =
+            idmapping::MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        let path = std::env::temp_dir().join("knn_save_test");
+        Knn::save(&index, &path).unwrap();
+        assert!(path.join(Knn::INDEX_FILE_NAME).exists());
+
+        let loaded = Knn::read_index("test", &path).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(loaded.has_annoy());
+        assert_eq!(loaded.algorithm(), Algorithm::Annoy);
+        let (ids, _) = loaded.get_nns_by_vector(&[50.2, 0.0], 2, None);
+        assert_eq!(ids, vec![500, 510]);
+    }
+
+    #[test]
+    fn quantized_test() {
+        let mut builder =
+            idmapping::MappingIndexBuilder::<i64>::new("test", 2, Distance::Euclidean);
+        for i in 0..100 {
+            builder.put(i * 10, &[i as f32, 0.0]).unwrap();
+        }
+        let index = builder.build(Some(10));
+        let path = std::env::temp_dir().join("knn_quantized_test");
+        Knn::save(&index, &path).unwrap();
+        Knn::save_quantized(&path, Quantization::F16).unwrap();
+        // encoded again from the f32 vectors
+        Knn::save_quantized(&path, Quantization::Int8).unwrap();
+
+        let loaded = Knn::read_index("test", &path).unwrap();
+        assert!(loaded.has_quantized());
+        let (ids, _) = loaded.get_nns_by_vector(&[50.2, 0.0], 2, None);
+        assert_eq!(ids, vec![500, 510]);
+        let recall = Knn::recall(&path, Algorithm::Annoy, 5, 50, None).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(recall > 0.7);
+    }
+}
@@ -0,0 +1,243 @@
+use err::Error;
+use futures::future;
+use futures::sync::oneshot;
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
+
+/// Bounds on the searches of a server
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Requests searched at once, the most the adaptive limit reaches
+    pub in_flight: usize,
+    /// Requests of an index searched or queued at once, 0 for no limit
+    pub per_index: usize,
+    /// Requests waiting for one of the in-flight slots, more are shed
+    pub queue: usize,
+    /// Lower the in-flight limit when the latency grows, raise it back when it does not
+    pub adaptive: bool,
+}
+
+impl Limits {
+    pub const DEFAULT_QUEUE: usize = 1000;
+
+    pub fn new(in_flight: usize) -> Limits {
+        Limits {
+            in_flight: in_flight.max(1),
+            per_index: 0,
+            queue: Limits::DEFAULT_QUEUE,
+            adaptive: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LoadStats {
+    /// Requests searched at once, lowered by the adaptive limit
+    pub limit: usize,
+    pub in_flight: usize,
+    pub queued: usize,
+    /// Requests rejected since the server started
+    pub shed: usize,
+}
+
+/// Request waiting for an in-flight slot
+struct Waiter {
+    index: Option<String>,
+    sender: oneshot::Sender<Permit>,
+}
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    queue: VecDeque<Waiter>,
+    /// Requests searched or queued by index
+    indexes: HashMap<String, usize>,
+    shed: usize,
+    shed_by_index: HashMap<String, usize>,
+    /// Typical latency of the searches in ms, the adaptive limit is lowered above it
+    baseline_ms: f64,
+}
+
+/// Admits the searches within `Limits`, queueing or shedding the others
+pub struct Limiter {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+/// In-flight slot of a search, released when dropped
+pub struct Permit {
+    /// None once the slot was given back
+    limiter: Option<Arc<Limiter>>,
+    index: Option<String>,
+    start: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            Limiter::release(&limiter, self.index.take(), self.start.elapsed());
+        }
+    }
+}
+
+impl Limiter {
+    /// Latency above this multiple of the baseline lowers the adaptive limit
+    const LATENCY_TOLERANCE: f64 = 2.0;
+    /// Weight of each search in the baseline latency when it is above it
+    const BASELINE_DRIFT: f64 = 0.01;
+
+    pub fn new(limits: Limits) -> Limiter {
+        Limiter {
+            limits,
+            state: Mutex::new(State {
+                limit: limits.in_flight,
+                in_flight: 0,
+                queue: VecDeque::new(),
+                indexes: HashMap::new(),
+                shed: 0,
+                shed_by_index: HashMap::new(),
+                baseline_ms: 0.0,
+            }),
+        }
+    }
+
+    /// Wait for an in-flight slot for a search of [index] until [deadline], failing with
+    /// `Error::Overloaded` right away when the index is at its limit or the queue is full
+    pub fn acquire(
+        limiter: &Arc<Limiter>,
+        index: Option<String>,
+        deadline: Instant,
+    ) -> Box<dyn Future<Item = Permit, Error = Error> + Send> {
+        let mut state = limiter.state.lock().unwrap();
+        if let Some(ref index) = index {
+            let count = state.indexes.get(index).cloned().unwrap_or(0);
+            if limiter.limits.per_index > 0 && count >= limiter.limits.per_index {
+                state.shed(Some(index));
+                return Box::new(future::err(Error::Overloaded));
+            }
+        }
+        if state.in_flight < state.limit {
+            state.in_flight += 1;
+            state.enter(&index);
+            let permit = Permit {
+                limiter: Some(limiter.clone()),
+                index,
+                start: Instant::now(),
+            };
+            return Box::new(future::ok(permit));
+        }
+        // requests that gave up waiting no longer hold a place
+        let (cancelled, queue): (Vec<Waiter>, VecDeque<Waiter>) =
+            state.queue.drain(..).partition(|w| w.sender.is_canceled());
+        state.queue = queue;
+        for waiter in cancelled {
+            state.leave(&waiter.index);
+        }
+        if state.queue.len() >= limiter.limits.queue {
+            state.shed(index.as_ref());
+            return Box::new(future::err(Error::Overloaded));
+        }
+        state.enter(&index);
+        let (sender, receiver) = oneshot::channel();
+        state.queue.push_back(Waiter { index, sender });
+        let permit = Timeout::new_at(receiver, deadline).map_err(|e| {
+            if e.is_inner() {
+                Error::CancelledFuture
+            } else {
+                Error::Timeout
+            }
+        });
+        Box::new(permit)
+    }
+
+    /// Give the slot of a search of [index] that took [latency] to the next waiting request
+    fn release(limiter: &Arc<Limiter>, index: Option<String>, latency: Duration) {
+        let mut state = limiter.state.lock().unwrap();
+        state.leave(&index);
+        if limiter.limits.adaptive {
+            state.adapt(latency, limiter.limits.in_flight);
+        }
+        while state.in_flight <= state.limit {
+            let waiter = match state.queue.pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            let permit = Permit {
+                limiter: Some(limiter.clone()),
+                index: waiter.index,
+                start: Instant::now(),
+            };
+            match waiter.sender.send(permit) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    // the request gave up waiting
+                    permit.limiter = None;
+                    state.leave(&permit.index);
+                }
+            }
+        }
+        state.in_flight -= 1;
+    }
+
+    pub fn stats(&self) -> LoadStats {
+        let state = self.state.lock().unwrap();
+        LoadStats {
+            limit: state.limit,
+            in_flight: state.in_flight,
+            queued: state.queue.len(),
+            shed: state.shed,
+        }
+    }
+
+    /// Number of requests of [index] rejected
+    pub fn shed(&self, index: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.shed_by_index.get(index).cloned().unwrap_or(0)
+    }
+}
+
+impl State {
+    fn enter(&mut self, index: &Option<String>) {
+        if let Some(ref index) = index {
+            *self.indexes.entry(index.clone()).or_insert(0) += 1;
+        }
+    }
+
+    fn leave(&mut self, index: &Option<String>) {
+        if let Some(ref index) = index {
+            let count = self.indexes.get(index).cloned().unwrap_or(1);
+            if count <= 1 {
+                self.indexes.remove(index);
+            } else {
+                self.indexes.insert(index.clone(), count - 1);
+            }
+        }
+    }
+
+    fn shed(&mut self, index: Option<&String>) {
+        self.shed += 1;
+        if let Some(index) = index {
+            *self.shed_by_index.entry(index.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Lower the limit by a tenth when [latency] is well above the baseline, raise it by one
+    /// up to [max] when the slots are all used and the latency is fine
+    fn adapt(&mut self, latency: Duration, max: usize) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        if self.baseline_ms == 0.0 || ms < self.baseline_ms {
+            self.baseline_ms = ms;
+        } else {
+            // follows the latency slowly, e.g. when the indexes grow
+            self.baseline_ms += (ms - self.baseline_ms) * Limiter::BASELINE_DRIFT;
+        }
+        if ms > Limiter::LATENCY_TOLERANCE * self.baseline_ms {
+            self.limit = self.limit.saturating_sub((self.limit / 10).max(1)).max(1);
+        } else if self.in_flight >= self.limit {
+            self.limit = (self.limit + 1).min(max);
+        }
+    }
+}
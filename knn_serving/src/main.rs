@@ -8,6 +8,7 @@ extern crate futures;
 extern crate bytes;
 extern crate hyper;
 extern crate knn_serving_api;
+extern crate num_cpus;
 extern crate rand;
 #[macro_use]
 extern crate serde_derive;
@@ -15,8 +16,16 @@ extern crate evmap;
 extern crate serde_json;
 extern crate tokio;
 
+mod build;
+mod cache;
+mod diversity;
 mod err;
+mod fanout;
 mod knn;
+mod limiter;
+mod multi;
+mod pool;
+mod router;
 mod server;
 mod service;
 mod util;
@@ -0,0 +1,225 @@
+use annoy_rs::idmapping;
+use capnp::message::{Builder, HeapAllocator};
+use err::Error;
+use futures::{future, Future};
+use knn::{Knn, SearchOptions, SearchResult};
+pub use knn_serving_api::service_capnp::knn_request_multi::Aggregation;
+use knn_serving_api::service_capnp::{knn_request_multi, knn_response};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Constant of reciprocal rank fusion, limiting the weight of the first ranks
+const RECIPROCAL_RANK_K: f32 = 60.0;
+
+/// One query of a multi query search
+pub struct Query {
+    pub vector: Vec<f32>,
+    pub weight: f32,
+    pub negative: bool,
+}
+
+/// Read the queries and options of [request], resolving product ids with [index]
+fn read_request(
+    index: &Arc<idmapping::MappingIndex<i64>>,
+    request: knn_request_multi::Reader,
+) -> Result<(Vec<Query>, Aggregation, SearchOptions), Error> {
+    let mut options = SearchOptions::new(
+        request.get_filter()?,
+        request.get_max_candidates(),
+        f32::INFINITY,
+    )?;
+    options.exclude = request.get_exclude_ids()?.iter().collect();
+    // product vectors are already indexed, query vectors are preprocessed when read
+    options.preprocessed = true;
+
+    let mut queries = Vec::new();
+    for query in request.get_queries()?.iter() {
+        // a zero or negative weight would divide or flip the distances
+        let weight = query.get_weight();
+        if !weight.is_finite() || weight <= 0.0 {
+            return Err(Error::InvalidWeight(weight));
+        }
+        let vector = match query.which().map_err(capnp::Error::from)? {
+            knn_request_multi::query::Which::Vector(vector) => {
+                let vector: Vec<f32> = vector?.iter().collect();
+                Knn::check_finite(&vector)?;
+                index.preprocess(&vector)?
+            }
+            knn_request_multi::query::Which::ProductId(id) => {
+                if !request.get_include_products() {
+                    options.exclude.insert(id);
+                }
+                Knn::get_vector(index, id).ok_or(Error::NoProductVectorFound(id))?
+            }
+        };
+        if vector.len() != index.dimension() as usize {
+            return Err(Error::DimensionError(
+                vector.len(),
+                index.dimension() as usize,
+            ));
+        }
+        queries.push(Query {
+            vector,
+            weight,
+            negative: query.get_negative(),
+        });
+    }
+    if !queries.iter().any(|q| !q.negative) {
+        return Err(Error::NoQuery);
+    }
+    let aggregation = request.get_aggregation().map_err(capnp::Error::from)?;
+    Ok((queries, aggregation, options))
+}
+
+/// Weighted mean of the queries minus the weighted mean of the negative examples
+fn centroid(queries: &[Query]) -> Vec<f32> {
+    let weight_sum = |negative: bool| -> f32 {
+        queries
+            .iter()
+            .filter(|q| q.negative == negative)
+            .map(|q| q.weight)
+            .sum()
+    };
+    let (positive, negative) = (weight_sum(false), weight_sum(true));
+    let mut centroid = vec![0.0; queries[0].vector.len()];
+    for query in queries {
+        let weight = if query.negative {
+            if negative > 0.0 {
+                -query.weight / negative
+            } else {
+                0.0
+            }
+        } else {
+            query.weight / positive
+        };
+        for (c, x) in centroid.iter_mut().zip(&query.vector) {
+            *c += weight * x;
+        }
+    }
+    centroid
+}
+
+/// Make [distance] smaller for heavier queries, distances of dot product indexes can be negative
+fn weighted(distance: f32, weight: f32) -> f32 {
+    if distance >= 0.0 {
+        distance / weight
+    } else {
+        distance * weight
+    }
+}
+
+/// Items sorted by increasing distance, at most [n] of them when [n] is positive
+fn sorted_result(distances: HashMap<i64, f32>, n: i32) -> SearchResult {
+    let mut items: Vec<(i64, f32)> = distances.into_iter().collect();
+    items.sort_by(|a, b| {
+        a.1.partial_cmp(&b.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.0.cmp(&b.0))
+    });
+    if n > 0 {
+        items.truncate(n as usize);
+    }
+    let (ids, distances) = items.into_iter().unzip();
+    SearchResult {
+        ids,
+        distances,
+        truncated: false,
+    }
+}
+
+fn merge_min_distance(queries: &[(f32, bool)], results: &[SearchResult], n: i32) -> SearchResult {
+    let mut distances: HashMap<i64, f32> = HashMap::new();
+    for (&(weight, _), result) in queries.iter().zip(results).filter(|(q, _)| !q.1) {
+        for (id, d) in result.ids.iter().zip(&result.distances) {
+            let d = weighted(*d, weight);
+            let distance = distances.entry(*id).or_insert(d);
+            if d < *distance {
+                *distance = d;
+            }
+        }
+    }
+    for (&(weight, _), result) in queries.iter().zip(results).filter(|(q, _)| q.1) {
+        for (id, d) in result.ids.iter().zip(&result.distances) {
+            let closer = match distances.get(id) {
+                Some(distance) => weighted(*d, weight) < *distance,
+                None => false,
+            };
+            if closer {
+                distances.remove(id);
+            }
+        }
+    }
+    sorted_result(distances, n)
+}
+
+fn fuse_ranks(queries: &[(f32, bool)], results: &[SearchResult], n: i32) -> SearchResult {
+    let mut scores: HashMap<i64, f32> = HashMap::new();
+    for (&(weight, _), result) in queries.iter().zip(results).filter(|(q, _)| !q.1) {
+        for (rank, id) in result.ids.iter().enumerate() {
+            *scores.entry(*id).or_insert(0.0) += weight / (RECIPROCAL_RANK_K + rank as f32 + 1.0);
+        }
+    }
+    // negative examples only lower the score of items found by the queries
+    for (&(weight, _), result) in queries.iter().zip(results).filter(|(q, _)| q.1) {
+        for (rank, id) in result.ids.iter().enumerate() {
+            if let Some(score) = scores.get_mut(id) {
+                *score -= weight / (RECIPROCAL_RANK_K + rank as f32 + 1.0);
+            }
+        }
+    }
+    let distances = scores.into_iter().map(|(id, score)| (id, -score)).collect();
+    sorted_result(distances, n)
+}
+
+/// Search the [n] items closest to all [queries], aggregated according to [aggregation]
+pub fn search(
+    index: Arc<idmapping::MappingIndex<i64>>,
+    queries: Vec<Query>,
+    aggregation: Aggregation,
+    k: i32,
+    n: i32,
+    options: SearchOptions,
+) -> Box<dyn Future<Item = SearchResult, Error = Error> + Send> {
+    if let Aggregation::Centroid = aggregation {
+        return Box::new(Knn::search(index, centroid(&queries), k, n, options));
+    }
+    let weights: Vec<(f32, bool)> = queries.iter().map(|q| (q.weight, q.negative)).collect();
+    let searches: Vec<_> = queries
+        .into_iter()
+        .map(|q| Knn::search(index.clone(), q.vector, k, n, options.clone()))
+        .collect();
+    let res = future::join_all(searches).map(move |results| match aggregation {
+        Aggregation::ReciprocalRank => fuse_ranks(&weights, &results, n),
+        _ => merge_min_distance(&weights, &results, n),
+    });
+    Box::new(res)
+}
+
+pub fn search_multi(
+    index: Arc<idmapping::MappingIndex<i64>>,
+    request: knn_request_multi::Reader,
+) -> Box<dyn Future<Item = Builder<HeapAllocator>, Error = Error> + Send> {
+    let k = request.get_search_k();
+    let n = request.get_result_count();
+    let (queries, aggregation, options) = match read_request(&index, request) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let index_copy = index.clone();
+    let res = search(index, queries, aggregation, k, n, options).and_then(move |result| {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let response: knn_response::Builder =
+                message.init_root::<knn_serving_api::service_capnp::knn_response::Builder>();
+            Knn::create_response_from_vectors(
+                &index_copy,
+                response,
+                result.ids.as_slice(),
+                result.distances.as_slice(),
+                result.truncated,
+            )?;
+        }
+        Ok(message)
+    });
+    Box::new(res)
+}
@@ -0,0 +1,133 @@
+use err::Error;
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use hyper::HeaderMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed number of threads running the searches, so that slow queries neither block the
+/// connections nor start once their deadline passed
+pub struct WorkerPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    threads: usize,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> WorkerPool {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("knn-worker-{}", i))
+                .spawn(move || loop {
+                    // the workers stop when the pool is dropped
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("A search panicked");
+                    }
+                })
+                .expect("Unable to start a worker thread");
+        }
+        WorkerPool {
+            sender: Mutex::new(sender),
+            threads,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Run [f] on a worker, failing with `Error::Timeout` once [deadline] passed.
+    /// [f] is not started when its deadline passed or its result is no longer awaited,
+    /// e.g. when the client disconnected.
+    pub fn run<F, T>(&self, deadline: Instant, f: F) -> impl Future<Item = T, Error = Error>
+    where
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel::<Result<T, Error>>();
+        let job: Job = Box::new(move || {
+            if tx.is_canceled() {
+                return;
+            }
+            if Instant::now() >= deadline {
+                let _ = tx.send(Err(Error::Timeout));
+                return;
+            }
+            let _ = tx.send(f());
+        });
+        if self.sender.lock().unwrap().send(job).is_err() {
+            return Either::B(future::err(Error::CancelledFuture));
+        }
+        let result = rx
+            .map_err(|_| Error::CancelledFuture)
+            .and_then(|result| result);
+        Either::A(Timeout::new_at(result, deadline).map_err(|e| {
+            if e.is_inner() {
+                e.into_inner().unwrap()
+            } else {
+                Error::Timeout
+            }
+        }))
+    }
+}
+
+/// Time requests are given to complete, read from the `x-timeout-ms` header
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Timeout of the requests without header
+    pub default: Duration,
+    /// Longest timeout a request can ask for
+    pub max: Duration,
+}
+
+impl Timeouts {
+    pub const HEADER: &'static str = "x-timeout-ms";
+    pub const DEFAULT_MS: u64 = 1000;
+    pub const MAX_MS: u64 = 10_000;
+
+    pub fn new(default: Duration, max: Duration) -> Timeouts {
+        Timeouts {
+            default: default.min(max),
+            max,
+        }
+    }
+
+    /// Deadline of a request received now with [headers]
+    pub fn deadline(&self, headers: &HeaderMap) -> Result<Instant, Error> {
+        let timeout = match headers.get(Timeouts::HEADER) {
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| Error::ParsingError(Timeouts::HEADER.to_owned()))?;
+                let ms = value
+                    .parse::<u64>()
+                    .map_err(|_| Error::ParsingError(format!("{} {}", Timeouts::HEADER, value)))?;
+                Duration::from_millis(ms).min(self.max)
+            }
+            None => self.default,
+        };
+        Ok(Instant::now() + timeout)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts::new(
+            Duration::from_millis(Timeouts::DEFAULT_MS),
+            Duration::from_millis(Timeouts::MAX_MS),
+        )
+    }
+}
@@ -0,0 +1,462 @@
+use bytes::Bytes;
+use capnp::serialize_packed;
+use err::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use knn_serving_api::service_capnp::{knn_request, knn_response};
+use pool::Timeouts;
+use serde_json;
+use service::error_response;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
+
+/// Shard map of the router, read from a json file
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ShardMap {
+    /// Shards of each index, as lists of replica base urls, e.g. `http://10.0.0.1:8080`
+    pub indexes: HashMap<String, Vec<Vec<String>>>,
+    /// Timeout of the requests without `x-timeout-ms` header, defaults to 1000ms.
+    /// The time left is split between the attempts of each shard.
+    pub timeout_ms: Option<u64>,
+    /// Number of other replicas tried when a request fails or times out, defaults to 1
+    pub retries: Option<usize>,
+}
+
+impl ShardMap {
+    const DEFAULT_TIMEOUT_MS: u64 = 1000;
+    const DEFAULT_RETRIES: usize = 1;
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<ShardMap, Error> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+/// Forwards search requests to one replica of each shard and merges their results
+#[derive(Clone)]
+pub struct Router {
+    shards: Arc<ShardMap>,
+    client: Client<HttpConnector>,
+    /// Spreads requests on the replicas
+    counter: Arc<AtomicUsize>,
+    timeouts: Timeouts,
+}
+
+impl Router {
+    pub fn new(shards: ShardMap) -> Router {
+        let timeout_ms = shards.timeout_ms.unwrap_or(ShardMap::DEFAULT_TIMEOUT_MS);
+        let timeouts = Timeouts::new(
+            Duration::from_millis(timeout_ms),
+            Duration::from_millis(timeout_ms.max(Timeouts::MAX_MS)),
+        );
+        Router {
+            shards: Arc::new(shards),
+            client: Client::new(),
+            counter: Arc::new(AtomicUsize::new(0)),
+            timeouts,
+        }
+    }
+
+    /// Send [body] to [replicas] starting at [start], trying the next one on failure.
+    /// Each attempt is given an equal share of the time left until [deadline].
+    fn query_shard(
+        &self,
+        replicas: Arc<Vec<String>>,
+        start: usize,
+        attempt: usize,
+        body: Bytes,
+        deadline: Instant,
+    ) -> Box<dyn Future<Item = hyper::Chunk, Error = Error> + Send> {
+        let max_attempts =
+            (self.shards.retries.unwrap_or(ShardMap::DEFAULT_RETRIES) + 1).min(replicas.len());
+        let now = Instant::now();
+        if now >= deadline {
+            return Box::new(future::err(Error::Timeout));
+        }
+        let timeout = attempt_timeout(deadline - now, max_attempts - attempt);
+        let url = &replicas[(start + attempt) % replicas.len()];
+        // the replica stops searching once the router gave up on it
+        let timeout_ms = (timeout.as_millis() as u64).max(1);
+        let request = match Request::post(format!("{}/search", url))
+            .header(Timeouts::HEADER, timeout_ms.to_string())
+            .body(Body::from(body.clone()))
+        {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(Error::from(e))),
+        };
+        let response = self
+            .client
+            .request(request)
+            .map_err(Error::from)
+            .and_then(|response| {
+                if response.status().is_success() {
+                    Either::A(response.into_body().concat2().map_err(Error::from))
+                } else {
+                    Either::B(future::err(Error::ShardError(
+                        response.status().to_string(),
+                    )))
+                }
+            });
+        let router = self.clone();
+        let url = url.clone();
+        let res = Timeout::new_at(response, now + timeout)
+            .map_err(|e| {
+                if e.is_inner() {
+                    e.into_inner().unwrap()
+                } else {
+                    Error::Timeout
+                }
+            })
+            .or_else(move |e| {
+                warn!("Request to {} failed: {}", url, e);
+                if attempt + 1 < max_attempts {
+                    router.query_shard(replicas, start, attempt + 1, body, deadline)
+                } else {
+                    Box::new(future::err(e))
+                }
+            });
+        Box::new(res)
+    }
+
+    /// Search all shards of the requested index before [deadline], merging the closest items.
+    /// The response is flagged partial when some shards did not answer, and the search fails
+    /// with `Error::Timeout` when none answered in time.
+    pub fn search(
+        &self,
+        body: Bytes,
+        deadline: Instant,
+    ) -> Box<dyn Future<Item = Bytes, Error = Error> + Send> {
+        let (name, n) = match read_request(&body) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let shards = match self.shards.indexes.get(&name) {
+            Some(shards) if !shards.is_empty() => shards,
+            _ => return Box::new(future::err(Error::NoIndexLoaded(name))),
+        };
+        let shard_count = shards.len();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        let searches: Vec<_> = shards
+            .iter()
+            .map(|replicas| {
+                self.query_shard(Arc::new(replicas.clone()), start, 0, body.clone(), deadline)
+                    .then(Ok::<_, Error>)
+            })
+            .collect();
+        let res = future::join_all(searches).and_then(move |responses| {
+            let mut answered = Vec::new();
+            let mut timed_out = true;
+            for response in responses {
+                match response {
+                    Ok(chunk) => answered.push(chunk),
+                    Err(Error::Timeout) => {}
+                    Err(_) => timed_out = false,
+                }
+            }
+            if answered.is_empty() {
+                return Err(if timed_out {
+                    Error::Timeout
+                } else {
+                    Error::ShardError(format!("no shard of {} answered", name))
+                });
+            }
+            merge_responses(&answered, n, answered.len() < shard_count)
+        });
+        Box::new(res)
+    }
+}
+
+/// Time given to one of the [attempts] left of a shard, when [remaining] is left
+fn attempt_timeout(remaining: Duration, attempts: usize) -> Duration {
+    remaining / attempts.max(1) as u32
+}
+
+/// Index name and result count of a packed `KnnRequest`
+fn read_request(body: &[u8]) -> Result<(String, i32), Error> {
+    let mut body = body;
+    let message =
+        serialize_packed::read_message(&mut body, ::capnp::message::ReaderOptions::default())?;
+    let request = message.get_root::<knn_request::Reader>()?;
+    Ok((
+        request.get_index_name()?.to_owned(),
+        request.get_result_count(),
+    ))
+}
+
+/// Merge the packed `KnnResponse` of each shard into the [n] closest items
+fn merge_responses(responses: &[hyper::Chunk], n: i32, partial: bool) -> Result<Bytes, Error> {
+    let mut items: Vec<(i64, f32)> = Vec::new();
+    let mut truncated = false;
+    for response in responses {
+        let mut body: &[u8] = response.as_ref();
+        let message =
+            serialize_packed::read_message(&mut body, ::capnp::message::ReaderOptions::default())?;
+        let response = message.get_root::<knn_response::Reader>()?;
+        truncated |= response.get_truncated();
+        for item in response.get_items()?.iter() {
+            items.push((item.get_id(), item.get_distance()));
+        }
+    }
+    items.sort_by(|a, b| {
+        a.1.partial_cmp(&b.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.0.cmp(&b.0))
+    });
+    if n > 0 {
+        items.truncate(n as usize);
+    }
+
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let mut response = message.init_root::<knn_response::Builder>();
+        response.set_truncated(truncated);
+        response.set_partial(partial);
+        let mut list = response.init_items(items.len() as u32);
+        for (i, (id, distance)) in items.into_iter().enumerate() {
+            let mut item = list.reborrow().get(i as u32);
+            item.set_id(id);
+            item.set_distance(distance);
+        }
+    }
+    let mut buffer = Vec::with_capacity(256);
+    serialize_packed::write_message(&mut buffer, &message)?;
+    Ok(Bytes::from(buffer))
+}
+
+pub struct RouterService {
+    pub router: Router,
+}
+
+impl Service for RouterService {
+    type ReqBody = hyper::Body;
+    type ResBody = hyper::Body;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Error> + Send>;
+
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        debug!("Receiving request");
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/search") => {
+                let router = self.router.clone();
+                let deadline = match router.timeouts.deadline(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                let res = req
+                    .into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |buf| router.search(buf.into_bytes(), deadline))
+                    .map(|buffer| Response::new(Body::from(buffer)))
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    })
+                    .or_else(error_response);
+                Box::new(res)
+            }
+            (&Method::GET, "/health") => Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Body::from("OK"))
+                    .unwrap(),
+            )),
+            _ => Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap(),
+            )),
+        }
+    }
+}
+
+impl futures::future::IntoFuture for RouterService {
+    type Future = future::FutureResult<Self::Item, Self::Error>;
+    type Item = Self;
+    type Error = Error;
+
+    fn into_future(self) -> Self::Future {
+        future::ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::service_fn_ok;
+    use hyper::Server;
+    use std::net::SocketAddr;
+    use tokio::runtime::Runtime;
+
+    /// Packed `KnnResponse` of [items]
+    fn response(items: &[(i64, f32)], truncated: bool) -> Vec<u8> {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut response = message.init_root::<knn_response::Builder>();
+            response.set_truncated(truncated);
+            let mut list = response.init_items(items.len() as u32);
+            for (i, (id, distance)) in items.iter().enumerate() {
+                let mut item = list.reborrow().get(i as u32);
+                item.set_id(*id);
+                item.set_distance(*distance);
+            }
+        }
+        let mut buffer = Vec::new();
+        serialize_packed::write_message(&mut buffer, &message).unwrap();
+        buffer
+    }
+
+    /// Items, truncated and partial flags of a packed `KnnResponse`
+    fn read_response(body: &[u8]) -> (Vec<(i64, f32)>, bool, bool) {
+        let mut body = body;
+        let message =
+            serialize_packed::read_message(&mut body, ::capnp::message::ReaderOptions::default())
+                .unwrap();
+        let response = message.get_root::<knn_response::Reader>().unwrap();
+        let items = response
+            .get_items()
+            .unwrap()
+            .iter()
+            .map(|item| (item.get_id(), item.get_distance()))
+            .collect();
+        (items, response.get_truncated(), response.get_partial())
+    }
+
+    /// Answer every request with [status] and [body] on a local port, counted in [hits]
+    fn serve(
+        runtime: &mut Runtime,
+        status: StatusCode,
+        body: Vec<u8>,
+        hits: Arc<AtomicUsize>,
+    ) -> String {
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = Server::bind(&addr).serve(move || {
+            let body = body.clone();
+            let hits = hits.clone();
+            service_fn_ok(move |_: Request<Body>| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                Response::builder()
+                    .status(status)
+                    .body(Body::from(body.clone()))
+                    .unwrap()
+            })
+        });
+        let url = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|_| ()));
+        url
+    }
+
+    #[test]
+    fn merge_test() {
+        let responses = vec![
+            hyper::Chunk::from(response(&[(1, 0.5), (3, 2.0)], false)),
+            hyper::Chunk::from(response(&[(2, 0.5), (4, 1.0)], true)),
+        ];
+        let merged = merge_responses(&responses, 3, true).unwrap();
+        assert_eq!(
+            read_response(&merged),
+            (vec![(1, 0.5), (2, 0.5), (4, 1.0)], true, true)
+        );
+        let merged = merge_responses(&responses[..1], 0, false).unwrap();
+        assert_eq!(
+            read_response(&merged),
+            (vec![(1, 0.5), (3, 2.0)], false, false)
+        );
+    }
+
+    #[test]
+    fn attempt_timeout_test() {
+        let remaining = Duration::from_millis(900);
+        assert_eq!(attempt_timeout(remaining, 3), Duration::from_millis(300));
+        assert_eq!(attempt_timeout(remaining, 1), remaining);
+        assert_eq!(attempt_timeout(remaining, 0), remaining);
+    }
+
+    #[test]
+    fn error_status_test() {
+        let mut runtime = Runtime::new().unwrap();
+        let failing = serve(
+            &mut runtime,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Vec::new(),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let mut indexes = HashMap::new();
+        indexes.insert("test".to_owned(), vec![vec![failing]]);
+        let router = Router::new(ShardMap {
+            indexes,
+            ..ShardMap::default()
+        });
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut request = message.init_root::<knn_request::Builder>();
+            request.set_index_name("test");
+            request.set_result_count(1);
+        }
+        let mut body = Vec::new();
+        serialize_packed::write_message(&mut body, &message).unwrap();
+        let body = Bytes::from(body);
+        let status =
+            |result: Result<Bytes, Error>| error_response(result.unwrap_err()).unwrap().status();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result = runtime.block_on(router.search(body.clone(), deadline));
+        assert_eq!(status(result), StatusCode::SERVICE_UNAVAILABLE);
+        let result = runtime.block_on(router.search(body, Instant::now()));
+        assert_eq!(status(result), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn retry_test() {
+        let mut runtime = Runtime::new().unwrap();
+        let failures = Arc::new(AtomicUsize::new(0));
+        let answers = Arc::new(AtomicUsize::new(0));
+        let failing = serve(
+            &mut runtime,
+            StatusCode::SERVICE_UNAVAILABLE,
+            Vec::new(),
+            failures.clone(),
+        );
+        let answering = serve(
+            &mut runtime,
+            StatusCode::OK,
+            response(&[(1, 0.5)], false),
+            answers.clone(),
+        );
+        let replicas = Arc::new(vec![failing, answering]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let router = Router::new(ShardMap::default());
+        let body = runtime
+            .block_on(router.query_shard(replicas.clone(), 0, 0, Bytes::new(), deadline))
+            .unwrap();
+        assert_eq!(read_response(&body).0, vec![(1, 0.5)]);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        assert_eq!(answers.load(Ordering::SeqCst), 1);
+
+        let router = Router::new(ShardMap {
+            retries: Some(0),
+            ..ShardMap::default()
+        });
+        let result =
+            runtime.block_on(router.query_shard(replicas.clone(), 0, 0, Bytes::new(), deadline));
+        assert!(result.is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+
+        // nothing is sent once the deadline passed
+        let result =
+            runtime.block_on(router.query_shard(replicas, 1, 0, Bytes::new(), Instant::now()));
+        assert!(result.is_err());
+        assert_eq!(answers.load(Ordering::SeqCst), 1);
+    }
+}
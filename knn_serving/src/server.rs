@@ -1,10 +1,23 @@
+use annoy_rs::hnsw::HnswBuilder;
+use annoy_rs::idmapping::Algorithm;
+use annoy_rs::quantization::Quantization;
 use futures::Future;
 use futures::Stream;
 use hyper::Server;
 use knn::Knn;
+use limiter::Limits;
+use pool::Timeouts;
+use router::{Router, RouterService, ShardMap};
 use service::KnnService;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of items the ivfpq quantizers are trained on by default
+const DEFAULT_IVFPQ_SAMPLE_SIZE: usize = 100_000;
+/// Number of neighbours and of query items of the recall measures by default
+const DEFAULT_RECALL_N: usize = 10;
+const DEFAULT_RECALL_QUERIES: usize = 1000;
 
 pub fn start_http(knn: Knn, http_addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
     let server = Server::bind(&http_addr)
@@ -18,16 +31,189 @@ pub fn start_http(knn: Knn, http_addr: SocketAddr) -> impl Future<Item = (), Err
     server
 }
 
+pub fn start_router(router: Router, http_addr: SocketAddr) -> impl Future<Item = (), Error = ()> {
+    let server = Server::bind(&http_addr)
+        .serve(move || RouterService {
+            router: router.clone(),
+        })
+        .map_err(|e| info!("server error: {}", e));
+
+    info!("Routing on {}", http_addr);
+    server
+}
+
 pub fn main() {
     let args: Vec<_> = std::env::args().collect();
-    if args.len() != 3 {
+    if args.len() >= 4 && args[1] == "neighbours" {
+        let k = args[3].parse::<i32>().expect("Unable to parse K");
+        let threads = match args.get(4) {
+            Some(threads) => threads.parse::<usize>().expect("Unable to parse THREADS"),
+            None => num_cpus::get(),
+        };
+        Knn::save_neighbours(&args[2], k, threads).expect("Unable to compute neighbours");
+        return;
+    }
+    if args.len() >= 3 && args[1] == "hnsw" {
+        let m = match args.get(3) {
+            Some(m) => m.parse::<usize>().expect("Unable to parse M"),
+            None => HnswBuilder::DEFAULT_M,
+        };
+        let ef_construction = match args.get(4) {
+            Some(ef) => ef
+                .parse::<usize>()
+                .expect("Unable to parse EF_CONSTRUCTION"),
+            None => HnswBuilder::DEFAULT_EF_CONSTRUCTION,
+        };
+        let quantization = match args.get(5) {
+            Some(quantization) => quantization
+                .parse::<Quantization>()
+                .expect("Unable to parse QUANTIZATION"),
+            None => Quantization::F32,
+        };
+        Knn::save_hnsw(&args[2], m, ef_construction, quantization)
+            .expect("Unable to build the hnsw graph");
+        return;
+    }
+    if args.len() >= 5 && args[1] == "ivfpq" {
+        let lists = args[3].parse::<usize>().expect("Unable to parse LISTS");
+        let subquantizers = args[4]
+            .parse::<usize>()
+            .expect("Unable to parse SUBQUANTIZERS");
+        let sample_size = match args.get(5) {
+            Some(size) => size.parse::<usize>().expect("Unable to parse SAMPLE_SIZE"),
+            None => DEFAULT_IVFPQ_SAMPLE_SIZE,
+        };
+        Knn::save_ivfpq(&args[2], lists, subquantizers, sample_size)
+            .expect("Unable to build the ivfpq codes");
+        return;
+    }
+    if args.len() >= 4 && args[1] == "quantize" {
+        let quantization = args[3]
+            .parse::<Quantization>()
+            .expect("Unable to parse QUANTIZATION");
+        Knn::save_quantized(&args[2], quantization).expect("Unable to quantize the vectors");
+        return;
+    }
+    if args.len() >= 4 && args[1] == "recall" {
+        let algorithm = args[3]
+            .parse::<Algorithm>()
+            .expect("Unable to parse ALGORITHM");
+        let n = match args.get(4) {
+            Some(n) => n.parse::<usize>().expect("Unable to parse N"),
+            None => DEFAULT_RECALL_N,
+        };
+        let queries = match args.get(5) {
+            Some(queries) => queries.parse::<usize>().expect("Unable to parse QUERIES"),
+            None => DEFAULT_RECALL_QUERIES,
+        };
+        let search_k = args
+            .get(6)
+            .map(|k| k.parse::<i32>().expect("Unable to parse SEARCH_K"));
+        let recall = Knn::recall(&args[2], algorithm, n, queries, search_k)
+            .expect("Unable to measure the recall");
+        println!("{} recall@{}: {:.4}", algorithm, n, recall);
+        return;
+    }
+    if args.len() < 3 || (args[1] == "router") != (args.len() == 4) {
         println!("usage: {} server HTTP_PORT", args[0]);
+        println!("       {} router HTTP_PORT SHARD_MAP", args[0]);
+        println!("       {} neighbours INDEX_PATH K [THREADS]", args[0]);
+        println!(
+            "       {} hnsw INDEX_PATH [M] [EF_CONSTRUCTION] [QUANTIZATION]",
+            args[0]
+        );
+        println!(
+            "       {} ivfpq INDEX_PATH LISTS SUBQUANTIZERS [SAMPLE_SIZE]",
+            args[0]
+        );
+        println!("       {} quantize INDEX_PATH QUANTIZATION", args[0]);
+        println!(
+            "       {} recall INDEX_PATH ALGORITHM [N] [QUERIES] [SEARCH_K]",
+            args[0]
+        );
         return;
     }
 
     let http_port = args[2].parse::<u16>().expect("Unable to parse HTTP_PORT");
     let http_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), http_port);
 
-    let service = Knn::new();
-    tokio::run(start_http(service, http_addr));
+    if args[1] == "router" {
+        let shards = ShardMap::read(&args[3]).expect("Unable to read SHARD_MAP");
+        tokio::run(start_router(Router::new(shards), http_addr));
+    } else {
+        // number of cached search results, disabled by default
+        let cache_size = std::env::var("KNN_CACHE_SIZE")
+            .map(|size| {
+                size.parse::<usize>()
+                    .expect("Unable to parse KNN_CACHE_SIZE")
+            })
+            .unwrap_or(0);
+        // threads running the searches, one per cpu by default
+        let workers = std::env::var("KNN_WORKERS")
+            .map(|workers| {
+                workers
+                    .parse::<usize>()
+                    .expect("Unable to parse KNN_WORKERS")
+            })
+            .unwrap_or_else(|_| num_cpus::get());
+        // timeout of the requests without x-timeout-ms header, and longest one allowed
+        let timeout_ms = std::env::var("KNN_TIMEOUT_MS")
+            .map(|ms| ms.parse::<u64>().expect("Unable to parse KNN_TIMEOUT_MS"))
+            .unwrap_or(Timeouts::DEFAULT_MS);
+        let max_timeout_ms = std::env::var("KNN_MAX_TIMEOUT_MS")
+            .map(|ms| {
+                ms.parse::<u64>()
+                    .expect("Unable to parse KNN_MAX_TIMEOUT_MS")
+            })
+            .unwrap_or(Timeouts::MAX_MS);
+        // searches run at once, one per worker by default, and per index, unlimited by default
+        let mut limits = Limits::new(
+            std::env::var("KNN_MAX_IN_FLIGHT")
+                .map(|n| {
+                    n.parse::<usize>()
+                        .expect("Unable to parse KNN_MAX_IN_FLIGHT")
+                })
+                .unwrap_or(workers),
+        );
+        limits.per_index = std::env::var("KNN_MAX_IN_FLIGHT_PER_INDEX")
+            .map(|n| {
+                n.parse::<usize>()
+                    .expect("Unable to parse KNN_MAX_IN_FLIGHT_PER_INDEX")
+            })
+            .unwrap_or(0);
+        // searches waiting for a slot, the others are answered with a 503
+        limits.queue = std::env::var("KNN_QUEUE_SIZE")
+            .map(|n| n.parse::<usize>().expect("Unable to parse KNN_QUEUE_SIZE"))
+            .unwrap_or(Limits::DEFAULT_QUEUE);
+        limits.adaptive = std::env::var("KNN_ADAPTIVE_CONCURRENCY")
+            .map(|a| {
+                a.parse::<bool>()
+                    .expect("Unable to parse KNN_ADAPTIVE_CONCURRENCY")
+            })
+            .unwrap_or(false);
+        let mut service = Knn::new()
+            .with_cache(cache_size)
+            .with_workers(workers)
+            .with_timeouts(
+                Duration::from_millis(timeout_ms),
+                Duration::from_millis(max_timeout_ms),
+            )
+            .with_limits(limits);
+        info!(
+            "Searching on {} threads with a {}ms timeout",
+            service.workers.threads(),
+            service.timeouts.default.as_millis()
+        );
+        info!(
+            "Searching {} requests at once{}, queueing up to {}",
+            limits.in_flight,
+            if limits.adaptive { " at most" } else { "" },
+            limits.queue
+        );
+        // directory of the indexes built with /build, disabled when not set
+        if let Ok(root) = std::env::var("KNN_INDEX_ROOT") {
+            service = service.with_builds(root);
+        }
+        tokio::run(start_http(service, http_addr));
+    }
 }
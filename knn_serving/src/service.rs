@@ -1,12 +1,17 @@
 use annoy_rs::annoy::Distance;
+use annoy_rs::filter;
 use annoy_rs::idmapping;
+use build;
+use cache::{CacheStats, QueryCache};
 use capnp::capability::Promise;
+use capnp::message::{Builder, HeapAllocator};
+use capnp::serialize;
 use capnp::serialize_packed;
 use capnp::text;
 use err;
 use err::Error;
+use fanout;
 use futures::future;
-use futures::future::Either;
 use futures::prelude::*;
 use futures::sync::oneshot;
 use futures::Async;
@@ -15,94 +20,197 @@ use futures::Stream;
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use knn::{Knn, KnnMapRead};
-use knn_serving_api::service_capnp::{knn_request, knn_request_by_id, knn_response, knn_service};
+use knn_serving_api::service_capnp::{
+    knn_request, knn_request_by_id, knn_request_fan_out, knn_request_multi, knn_response,
+    knn_service,
+};
+use limiter::{Limiter, LoadStats, Permit};
+use multi;
+use pool::WorkerPool;
 use serde_json;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 use util;
 
-macro_rules! fry {
-    ($expr: expr) => {
-        match $expr {
-            Ok(value) => value,
-            Err(error) => return Either::B(future::err(Error::from(error))),
-        }
-    };
-}
+type Message = ::capnp::message::Reader<serialize::OwnedSegments>;
 
-fn search(
+/// Read the packed capnp request of [req] and wait for [limiter] to admit it under the index
+/// returned by [name] before [deadline]
+fn read_request<N>(
     req: Request<Body>,
-    hashmap: KnnMapRead,
-) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+    limiter: Arc<Limiter>,
+    deadline: Instant,
+    name: N,
+) -> impl Future<Item = (Message, Permit), Error = Error> + Send
+where
+    N: FnOnce(&Message) -> Result<Option<String>, Error> + Send + 'static,
+{
     let body = req.into_body();
-    let s = body
-        .concat2()
-        .into_future()
+    body.concat2()
         .map_err(Error::from)
-        .and_then(|buf| {
+        .and_then(move |buf| {
             debug!("Deserializing message");
-            serialize_packed::read_message(
+            let message_reader = serialize_packed::read_message(
                 &mut buf.as_ref(),
                 ::capnp::message::ReaderOptions::default(),
-            )
-            .map_err(Error::from)
+            )?;
+            let name = name(&message_reader)?;
+            Ok((message_reader, name))
         })
-        .and_then(move |message_reader| {
+        .and_then(move |(message_reader, name)| {
+            Limiter::acquire(&limiter, name, deadline).map(|permit| (message_reader, permit))
+        })
+}
+
+fn write_response(builder: &Builder<HeapAllocator>) -> Result<Response<Body>, Error> {
+    debug!("Builing Response");
+    let mut buffer = Vec::with_capacity(256);
+    serialize_packed::write_message(&mut buffer, builder)?;
+    Ok(Response::new(Body::from(buffer)))
+}
+
+/// Read the request of [req] with [read_request], then answer it with [handle] on [workers]
+/// before [deadline]
+fn run_search<N, F>(
+    req: Request<Body>,
+    limiter: Arc<Limiter>,
+    workers: Arc<WorkerPool>,
+    deadline: Instant,
+    name: N,
+    handle: F,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send>
+where
+    N: FnOnce(&Message) -> Result<Option<String>, Error> + Send + 'static,
+    F: FnOnce(&Message) -> Result<Builder<HeapAllocator>, Error> + Send + 'static,
+{
+    let s = read_request(req, limiter, deadline, name).and_then(move |(message_reader, permit)| {
+        workers.run(deadline, move || {
+            // the slot is held until the search completes, even past the deadline
+            let _permit = permit;
             debug!("Sending to Knn service");
-            let request = fry!(message_reader.get_root::<knn_request::Reader>());
-            let name = fry!(request.get_index_name());
-            let index = fry!(Knn::get_index2(hashmap.clone(), name));
-            debug!("Searching in index: {}", name);
-            Either::A(Knn::search2(index, request))
+            let builder = handle(&message_reader)?;
+            write_response(&builder)
         })
-        .and_then(move |builder| {
-            debug!("Builing Response");
-            let mut buffer = Vec::with_capacity(256);
-            serialize_packed::write_message(&mut buffer, &builder)?;
-            Ok(Response::new(Body::from(buffer)))
-        });
+    });
 
     Box::new(s)
 }
 
+fn search(
+    req: Request<Body>,
+    hashmap: KnnMapRead,
+    cache: Option<Arc<QueryCache>>,
+    limiter: Arc<Limiter>,
+    workers: Arc<WorkerPool>,
+    deadline: Instant,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    let name = |message_reader: &Message| -> Result<Option<String>, Error> {
+        let request = message_reader.get_root::<knn_request::Reader>()?;
+        Ok(Some(request.get_index_name()?.to_owned()))
+    };
+    let handle = move |message_reader: &Message| {
+        let request = message_reader.get_root::<knn_request::Reader>()?;
+        let name = request.get_index_name()?;
+        let index = Knn::get_index2(hashmap, name)?;
+        debug!("Searching in index: {}", name);
+        Knn::search2(index, request, cache).wait()
+    };
+    run_search(req, limiter, workers, deadline, name, handle)
+}
+
 fn search2(
     req: Request<Body>,
     hashmap: KnnMapRead,
-) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
-    let body = req.into_body();
-    let s = body
-        .concat2()
-        .into_future()
-        .map_err(Error::from)
-        .and_then(|buf| {
-            debug!("Deserializing message");
-            serialize_packed::read_message(
-                &mut buf.as_ref(),
-                ::capnp::message::ReaderOptions::default(),
-            )
-            .map_err(Error::from)
-        })
-        .and_then(move |message_reader| {
-            debug!("Sending to Knn service");
-            let request = fry!(message_reader.get_root::<knn_request_by_id::Reader>());
-            let name = fry!(request.get_index_name());
-            let index = fry!(Knn::get_index2(hashmap.clone(), name));
-            debug!("Searching in index: {}", name);
-            Either::A(Knn::search_id(index, request))
-        })
-        .and_then(move |builder| {
-            debug!("Builing Response");
-            let mut buffer = Vec::with_capacity(256);
-            serialize_packed::write_message(&mut buffer, &builder)?;
-            Ok(Response::new(Body::from(buffer)))
-        });
+    cache: Option<Arc<QueryCache>>,
+    limiter: Arc<Limiter>,
+    workers: Arc<WorkerPool>,
+    deadline: Instant,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    let name = |message_reader: &Message| -> Result<Option<String>, Error> {
+        let request = message_reader.get_root::<knn_request_by_id::Reader>()?;
+        Ok(Some(request.get_index_name()?.to_owned()))
+    };
+    let handle = move |message_reader: &Message| {
+        let request = message_reader.get_root::<knn_request_by_id::Reader>()?;
+        let name = request.get_index_name()?;
+        let index = Knn::get_index2(hashmap, name)?;
+        debug!("Searching in index: {}", name);
+        Knn::search_id(index, request, cache).wait()
+    };
+    run_search(req, limiter, workers, deadline, name, handle)
+}
 
+fn search_multi(
+    req: Request<Body>,
+    hashmap: KnnMapRead,
+    limiter: Arc<Limiter>,
+    workers: Arc<WorkerPool>,
+    deadline: Instant,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    let name = |message_reader: &Message| -> Result<Option<String>, Error> {
+        let request = message_reader.get_root::<knn_request_multi::Reader>()?;
+        Ok(Some(request.get_index_name()?.to_owned()))
+    };
+    let handle = move |message_reader: &Message| {
+        let request = message_reader.get_root::<knn_request_multi::Reader>()?;
+        let name = request.get_index_name()?;
+        let index = Knn::get_index2(hashmap, name)?;
+        debug!("Searching in index: {}", name);
+        multi::search_multi(index, request).wait()
+    };
+    run_search(req, limiter, workers, deadline, name, handle)
+}
+
+/// Fan-out searches only count against the global limit, their indexes being searched in
+/// parallel on [workers]
+fn search_fan_out(
+    req: Request<Body>,
+    hashmap: KnnMapRead,
+    limiter: Arc<Limiter>,
+    workers: Arc<WorkerPool>,
+    deadline: Instant,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    let name = |_: &Message| -> Result<Option<String>, Error> { Ok(None) };
+    let s = read_request(req, limiter, deadline, name).and_then(move |(message_reader, permit)| {
+        let searched: Box<dyn Future<Item = Builder<HeapAllocator>, Error = Error> + Send> =
+            match message_reader.get_root::<knn_request_fan_out::Reader>() {
+                Ok(request) => fanout::search_fan_out(hashmap, request, &workers, deadline),
+                Err(e) => Box::new(future::err(Error::from(e))),
+            };
+        searched.and_then(move |builder| {
+            // the slot is held until every index was searched
+            drop(permit);
+            write_response(&builder)
+        })
+    });
     Box::new(s)
 }
 
+/// Answer [error] with a status when the client can retry, e.g. after a timeout, or fix its
+/// request. Other errors close the connection.
+pub fn error_response(error: Error) -> Result<Response<Body>, Error> {
+    let status = match error {
+        Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        Error::Overloaded | Error::ShardError(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::DimensionError(_, _)
+        | Error::NonFiniteComponent(_)
+        | Error::ZeroVector
+        | Error::InvalidResultCount(_)
+        | Error::InvalidSearchK(_)
+        | Error::InvalidCandidateCount(_)
+        | Error::InvalidWeight(_) => StatusCode::BAD_REQUEST,
+        _ => return Err(error),
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::from(error.to_string()))
+        .map_err(Error::from)
+}
+
 pub struct KnnService {
     pub state: Knn,
 }
@@ -113,6 +221,67 @@ impl KnnService {
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Metrics {
+    pub cache: Option<CacheStats>,
+    pub load: LoadStats,
+    pub indexes: HashMap<String, IndexStats>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct IndexStats {
+    pub items: usize,
+    pub deleted: usize,
+    /// Items searched exactly until the index is compacted
+    pub upserted: usize,
+    /// Fraction of the items deleted, the index should be rebuilt when it grows
+    pub tombstone_ratio: f32,
+    /// Searches rejected because the server or the index was overloaded
+    pub shed: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeleteRequest {
+    pub index_name: String,
+    pub ids: Vec<i64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UpsertRequest {
+    pub index_name: String,
+    pub items: Vec<UpsertItem>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct UpsertItem {
+    pub id: i64,
+    pub vector: Vec<f32>,
+    /// Tab separated `key=value` pairs, as in the attributes file
+    pub attributes: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CompactRequest {
+    pub index_name: String,
+    /// Directory the compacted index is written to
+    pub path: String,
+    /// Number of trees, annoy's default when not set
+    pub n_tree: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CancelRequest {
+    pub index_name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeleteResponse {
+    /// Number of ids newly deleted, ids unknown or already deleted are ignored
+    pub deleted: usize,
+    pub tombstone_ratio: f32,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct LoadRequest {
     pub index_name: String,
@@ -127,10 +296,26 @@ impl Service for KnnService {
 
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
         debug!("Receiving request");
+        Box::new(self.route(req).or_else(error_response))
+    }
+}
+
+impl KnnService {
+    fn route(
+        &mut self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+        let deadline = match self.state.timeouts.deadline(req.headers()) {
+            Ok(deadline) => deadline,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let workers = self.state.workers.clone();
+        let limiter = self.state.limiter.clone();
         match (req.method(), req.uri().path()) {
             (&Method::POST, "/search") => {
                 let hashmap = self.state.index_read.clone();
-                let res = search(req, hashmap).map_err(|r| {
+                let cache = self.state.cache.clone();
+                let res = search(req, hashmap, cache, limiter, workers, deadline).map_err(|r| {
                     warn!("{:?}", r);
                     r
                 });
@@ -138,7 +323,24 @@ impl Service for KnnService {
             }
             (&Method::POST, "/search2") => {
                 let hashmap = self.state.index_read.clone();
-                let res = search2(req, hashmap).map_err(|r| {
+                let cache = self.state.cache.clone();
+                let res = search2(req, hashmap, cache, limiter, workers, deadline).map_err(|r| {
+                    warn!("{:?}", r);
+                    r
+                });
+                Box::new(res)
+            }
+            (&Method::POST, "/search_multi") => {
+                let hashmap = self.state.index_read.clone();
+                let res = search_multi(req, hashmap, limiter, workers, deadline).map_err(|r| {
+                    warn!("{:?}", r);
+                    r
+                });
+                Box::new(res)
+            }
+            (&Method::POST, "/search_fan_out") => {
+                let hashmap = self.state.index_read.clone();
+                let res = search_fan_out(req, hashmap, limiter, workers, deadline).map_err(|r| {
                     warn!("{:?}", r);
                     r
                 });
@@ -146,6 +348,7 @@ impl Service for KnnService {
             }
             (&Method::POST, "/load") => {
                 let write_handle = self.state.index_write.clone();
+                let cache = self.state.cache.clone();
                 let f = req
                     .into_body()
                     .concat2()
@@ -160,10 +363,15 @@ impl Service for KnnService {
                             Ok(loadr) => {
                                 match Knn::load(write_handle, &loadr.index_name, loadr.path) {
                                     Err(e) => Err(e),
-                                    Ok(_) => Response::builder()
-                                        .status(StatusCode::OK)
-                                        .body(Body::empty())
-                                        .map_err(Error::from),
+                                    Ok(_) => {
+                                        if let Some(cache) = cache {
+                                            cache.invalidate(&loadr.index_name);
+                                        }
+                                        Response::builder()
+                                            .status(StatusCode::OK)
+                                            .body(Body::empty())
+                                            .map_err(Error::from)
+                                    }
                                 }
                             }
                         },
@@ -174,6 +382,168 @@ impl Service for KnnService {
                     });
                 Box::new(f)
             }
+            (&Method::POST, "/delete") => {
+                let knn = self.state.clone();
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |buf| {
+                        let request = serde_json::from_slice::<DeleteRequest>(&buf)?;
+                        let deleted = knn.delete(&request.index_name, &request.ids)?;
+                        let index = knn.get_index(&request.index_name)?;
+                        let response = DeleteResponse {
+                            deleted,
+                            tombstone_ratio: index.tombstone_ratio(),
+                        };
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::to_vec(&response)?))
+                            .map_err(Error::from)
+                    })
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    });
+                Box::new(f)
+            }
+            (&Method::POST, "/upsert") => {
+                let knn = self.state.clone();
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |buf| {
+                        let request = serde_json::from_slice::<UpsertRequest>(&buf)?;
+                        let mut items = Vec::with_capacity(request.items.len());
+                        for item in request.items {
+                            let attributes = filter::parse_attributes(&item.attributes)?;
+                            items.push((item.id, item.vector, attributes));
+                        }
+                        knn.upsert(&request.index_name, items)?;
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .body(Body::empty())
+                            .map_err(Error::from)
+                    })
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    });
+                Box::new(f)
+            }
+            (&Method::POST, "/compact") => {
+                let knn = self.state.clone();
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |buf| {
+                        let request = serde_json::from_slice::<CompactRequest>(&buf)?;
+                        knn.compact(&request.index_name, &request.path, request.n_tree)?;
+                        Response::builder()
+                            .status(StatusCode::ACCEPTED)
+                            .body(Body::empty())
+                            .map_err(Error::from)
+                    })
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    });
+                Box::new(f)
+            }
+            (&Method::POST, "/build") => {
+                let builds = match self.state.builds.clone() {
+                    Some(builds) => builds,
+                    None => return Box::new(future::err(Error::BuildsDisabled)),
+                };
+                let f = build::build(req.into_body(), self.state.clone(), builds)
+                    .and_then(|status| {
+                        Response::builder()
+                            .status(StatusCode::ACCEPTED)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::to_vec(&status)?))
+                            .map_err(Error::from)
+                    })
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    });
+                Box::new(f)
+            }
+            (&Method::GET, "/jobs") => {
+                let statuses = match self.state.builds {
+                    Some(ref builds) => builds.statuses(),
+                    None => HashMap::new(),
+                };
+                let res = serde_json::to_vec(&statuses)
+                    .map_err(Error::from)
+                    .and_then(|body| {
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body))
+                            .map_err(Error::from)
+                    });
+                Box::new(future::result(res))
+            }
+            (&Method::POST, "/jobs/cancel") => {
+                let builds = match self.state.builds.clone() {
+                    Some(builds) => builds,
+                    None => return Box::new(future::err(Error::BuildsDisabled)),
+                };
+                let f = req
+                    .into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |buf| {
+                        let request = serde_json::from_slice::<CancelRequest>(&buf)?;
+                        let status = builds.cancel(&request.index_name)?;
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::to_vec(&status)?))
+                            .map_err(Error::from)
+                    })
+                    .map_err(|r| {
+                        warn!("{:?}", r);
+                        r
+                    });
+                Box::new(f)
+            }
+            (&Method::GET, "/metrics") => {
+                let mut indexes = HashMap::new();
+                let limiter = &self.state.limiter;
+                self.state.index_read.for_each(|name, values| {
+                    let index = &values[0];
+                    indexes.insert(
+                        name.clone(),
+                        IndexStats {
+                            items: index.len(),
+                            deleted: index.deleted_count(),
+                            upserted: index.upserted_count(),
+                            tombstone_ratio: index.tombstone_ratio(),
+                            shed: limiter.shed(name),
+                        },
+                    );
+                });
+                let metrics = Metrics {
+                    cache: self.state.cache.as_ref().map(|cache| cache.stats()),
+                    load: limiter.stats(),
+                    indexes,
+                };
+                let res = serde_json::to_vec(&metrics)
+                    .map_err(Error::from)
+                    .and_then(|body| {
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body))
+                            .map_err(Error::from)
+                    });
+                Box::new(future::result(res))
+            }
             (&Method::GET, "/health") => Box::new(future::ok(
                 Response::builder()
                     .status(StatusCode::OK)